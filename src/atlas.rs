@@ -1,15 +1,49 @@
+use crate::cache::{self, CacheKey, Reader, Writer};
+use crate::font::Font;
 use crate::point::Point;
 use crate::size::Size;
 use crate::rectangle::Rectangle;
+use crate::texture::{self, Texture, TextureError, TextureFilter};
+
+use image::RgbaImage;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::Path;
 use crate::tileset::Tileset;
 
-use bitmaps::Bitmap;
-// use std::ops::{Shl, Shr};
+use std::borrow::Cow;
+use std::cmp::Reverse;
+
+
+// Cache files start with these, so a stale format is never misread
+const CACHE_MAGIC: &[u8; 8] = b"NOCATLAS";
+const CACHE_VERSION: u32 = 5;
 
+// Sources by their index in cache files
+const SOURCES: [TileSource; 5] = [
+    TileSource::Sheet,
+    TileSource::Tileset,
+    TileSource::Font,
+    TileSource::Added,
+    TileSource::Generated,
+];
 
-const BITMAP_SIZE: usize = 16;
+// Pages start this big and double in either direction until the tiles fit
+const MIN_PAGE_SIZE: u32 = 256;
 
+// Nearest sampling exactly on a texel edge may round to either side, so
+// coordinates stay this many texels inside the tile
+const NEAREST_INSET: f32 = 0.01;
 
+// The tile sheet is read as a 16x16 grid of tiles indexed by the low byte of
+// a code
+const SHEET_COLUMNS: u32 = 16;
+const SHEET_ROWS: u32 = 16;
+
+
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct TexCoords {
     tu1: f32,
     tv1: f32,
@@ -17,7 +51,92 @@ pub struct TexCoords {
     tv2: f32,
 }
 
+impl TexCoords {
+    pub const FULL: TexCoords = TexCoords { tu1: 0.0, tv1: 0.0, tu2: 1.0, tv2: 1.0 };
+
+    /// Coordinates of a `width` by `height` pixel rectangle at `(x, y)` in a
+    /// texture of `size`.
+    pub fn from_rect(x: u32, y: u32, width: u32, height: u32, size: Size<u32>) -> Self {
+        let (w, h) = (size.width as f32, size.height as f32);
+        Self {
+            tu1: x as f32 / w,
+            tv1: y as f32 / h,
+            tu2: (x + width) as f32 / w,
+            tv2: (y + height) as f32 / h,
+        }
+    }
+
+    /// Coordinates of tile `index` in a texture laid out as a uniform grid,
+    /// read left to right, top to bottom.
+    pub fn from_grid(index: u32, columns: u32, rows: u32) -> Self {
+        let index = index % (columns * rows);
+        let (column, row) = (index % columns, index / columns);
+        let (width, height) = (1.0 / columns as f32, 1.0 / rows as f32);
+
+        Self {
+            tu1: column as f32 * width,
+            tv1: row as f32 * height,
+            tu2: (column + 1) as f32 * width,
+            tv2: (row + 1) as f32 * height,
+        }
+    }
+
+    /// Map coordinates relative to `region` onto the texture `region` is in.
+    pub fn within(self, region: TexCoords) -> Self {
+        let (w, h) = (region.tu2 - region.tu1, region.tv2 - region.tv1);
+        Self {
+            tu1: region.tu1 + self.tu1 * w,
+            tv1: region.tv1 + self.tv1 * h,
+            tu2: region.tu1 + self.tu2 * w,
+            tv2: region.tv1 + self.tv2 * h,
+        }
+    }
+
+    /// Move every edge `u` and `v` toward the middle, in texture units.
+    pub fn inset(self, u: f32, v: f32) -> Self {
+        Self {
+            tu1: self.tu1 + u,
+            tv1: self.tv1 + v,
+            tu2: self.tu2 - u,
+            tv2: self.tv2 - v,
+        }
+    }
+
+    pub fn to_array(self) -> [f32; 4] {
+        [self.tu1, self.tv1, self.tu2, self.tv2]
+    }
+}
+
+
+/// Where a tile's pixels came from, as listed by [`Atlas::dump`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum TileSource {
+    /// Cut from the tile sheet.
+    Sheet,
+    /// Inserted from a tileset.
+    Tileset,
+    /// A font glyph rasterized on demand.
+    Font,
+    /// Added by the application.
+    Added,
+    /// A glyph drawn for the cell size.
+    Generated,
+}
+
+impl TileSource {
+    fn name(self) -> &'static str {
+        match self {
+            TileSource::Sheet => "sheet",
+            TileSource::Tileset => "tileset",
+            TileSource::Font => "font",
+            TileSource::Added => "added",
+            TileSource::Generated => "generated",
+        }
+    }
+}
 
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum TileAlignment {
     Unknown,
     Center,
@@ -28,90 +147,1934 @@ pub enum TileAlignment {
     BottomRight,
 }
 
+impl TileAlignment {
+    /// The point a tile is scaled and rotated about, relative to its size.
+    pub fn anchor(self) -> [f32; 2] {
+        match self {
+            TileAlignment::Unknown
+            | TileAlignment::Center
+            | TileAlignment::DeadCenter => [0.5, 0.5],
+            TileAlignment::TopLeft => [0.0, 0.0],
+            TileAlignment::TopRight => [1.0, 0.0],
+            TileAlignment::BottomLeft => [0.0, 1.0],
+            TileAlignment::BottomRight => [1.0, 1.0],
+        }
+    }
+
+    /// Where a `tile` sized quad goes relative to the top-left corner of a
+    /// `cell` sized cell. `Center` rounds down to whole pixels so pixel art
+    /// stays sharp; `DeadCenter` does not round.
+    pub fn position(self, tile: Size<f32>, cell: Size<f32>) -> [f32; 2] {
+        let slack = [cell.width - tile.width, cell.height - tile.height];
+        match self {
+            TileAlignment::Unknown | TileAlignment::TopLeft => [0.0, 0.0],
+            TileAlignment::Center => [(slack[0] / 2.0).floor(), (slack[1] / 2.0).floor()],
+            TileAlignment::DeadCenter => [slack[0] / 2.0, slack[1] / 2.0],
+            TileAlignment::TopRight => [slack[0], 0.0],
+            TileAlignment::BottomLeft => [0.0, slack[1]],
+            TileAlignment::BottomRight => slack,
+        }
+    }
+}
+
 
-pub struct TileInfo<'a> {
-    tileset: Tileset,
-    texture: AtlasTexture,
-    bitmap: &'a mut Bitmap<BITMAP_SIZE>,
+/// Where a tile is in the atlas and how big it is.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TileLocation {
+    pub page: usize,
+    pub tex_coords: TexCoords,
+    /// The tile's own size in pixels.
+    pub size: Size<i32>,
+    /// Pixels the tile is shifted by wherever it is drawn.
+    pub offset: Point<i32>,
+}
+
+
+/// A tile image and the space it takes on an atlas page.
+#[derive(Debug, Clone)]
+pub struct TileInfo {
+    pixels: RgbaImage,
+    // Where the pixels are on the page
     useful_space: Rectangle<i32>,
+    // The space reserved for the tile: its pixels, the extruded border
+    // around them and the spacing after that
     total_space: Rectangle<i32>,
     texture_coords: TexCoords,
     offset: Point<i32>,
     spacing: Size<i32>,
-    alignment: TileAlignment,
-    is_animated: bool,
+    extrusion: i32,
+    page: usize,
+    placed: bool,
+    // Whether clean_up may evict the tile once nothing references it
+    evictable: bool,
+    source: TileSource,
 }
 
+impl TileInfo {
+    /// A tile that keeps `padding` empty pixels from its neighbours, with
+    /// its outermost pixels repeated `extrusion` times around it.
+    pub fn new(pixels: RgbaImage, padding: u32, extrusion: u32) -> Self {
+        let (width, height) = pixels.dimensions();
+        let spacing = Size::new(padding as i32, padding as i32);
+        let extrusion = extrusion as i32;
+
+        Self {
+            pixels,
+            useful_space: Rectangle::new(0, 0, width as i32, height as i32),
+            total_space: Rectangle::new(
+                0, 0,
+                width as i32 + extrusion * 2 + spacing.width,
+                height as i32 + extrusion * 2 + spacing.height,
+            ),
+            texture_coords: TexCoords::FULL,
+            offset: Point::new(0, 0),
+            spacing,
+            extrusion,
+            page: 0,
+            placed: false,
+            evictable: false,
+            source: TileSource::Added,
+        }
+    }
+
+    pub fn pixels(&self) -> &RgbaImage {
+        &self.pixels
+    }
+
+    pub fn useful_space(&self) -> Rectangle<i32> {
+        self.useful_space
+    }
+
+    pub fn total_space(&self) -> Rectangle<i32> {
+        self.total_space
+    }
+
+    /// Where the tile is on its page. Only meaningful once it is placed.
+    pub fn tex_coords(&self) -> TexCoords {
+        self.texture_coords
+    }
+
+    /// The atlas page the tile is on.
+    pub fn page(&self) -> usize {
+        self.page
+    }
+
+    pub fn offset(&self) -> Point<i32> {
+        self.offset
+    }
+
+    fn location(&self) -> TileLocation {
+        TileLocation {
+            page: self.page,
+            tex_coords: self.texture_coords,
+            size: self.useful_space.size(),
+            offset: self.offset,
+        }
+    }
+
+    pub fn is_placed(&self) -> bool {
+        self.placed
+    }
+
+    // The space the tile takes on its page, padding and extrusion included
+    fn space(&self) -> Rectangle<u32> {
+        let space = self.total_space;
+        Rectangle::new(space.x as u32, space.y as u32, space.width as u32, space.height as u32)
+    }
+
+    // Record that the tile was packed into `space` on `page`
+    fn place_at(&mut self, page: usize, space: Rectangle<u32>) {
+        let (x, y) = (space.x as i32, space.y as i32);
+        self.total_space.x = x;
+        self.total_space.y = y;
+        self.useful_space.x = x + self.extrusion;
+        self.useful_space.y = y + self.extrusion;
+        self.page = page;
+        self.placed = true;
+    }
+
+    // What goes on the page: the pixels with their edges extruded
+    fn page_pixels(&self) -> Cow<'_, RgbaImage> {
+        match self.extrusion {
+            0 => Cow::Borrowed(&self.pixels),
+            extrusion => Cow::Owned(extrude(&self.pixels, extrusion as u32)),
+        }
+    }
+}
+
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct Segment {
+    x: u32,
+    y: u32,
+    width: u32,
+}
+
+
+/// Free space on a page. The skyline is the top of the highest tile over
+/// each run of columns, and new tiles go wherever they leave it lowest.
+/// Space given back by removed tiles is kept apart and reused first.
+#[derive(Debug, Clone)]
+struct Skyline {
+    size: Size<u32>,
+    segments: Vec<Segment>,
+    freed: Vec<Rectangle<u32>>,
+    used: u64,
+}
+
+impl Skyline {
+    fn new(width: u32, height: u32) -> Self {
+        Self {
+            size: Size::new(width, height),
+            segments: vec![Segment { x: 0, y: 0, width }],
+            freed: Vec::new(),
+            used: 0,
+        }
+    }
+
+    /// Reserve a `width` by `height` rectangle, if there is room for one.
+    fn allocate(&mut self, width: u32, height: u32) -> Option<Rectangle<u32>> {
+        if width == 0 || height == 0 {
+            return Some(Rectangle::new(0, 0, width, height));
+        }
+
+        let space = self
+            .allocate_freed(width, height)
+            .or_else(|| self.allocate_skyline(width, height))?;
+        self.used += width as u64 * height as u64;
+        Some(space)
+    }
+
+    /// Give back a rectangle returned by [`Skyline::allocate`].
+    fn deallocate(&mut self, space: Rectangle<u32>) {
+        if space.width == 0 || space.height == 0 {
+            return;
+        }
+        self.used -= space.width as u64 * space.height as u64;
+
+        // Join neighbours that share a whole edge, so a tile as big as
+        // several removed ones fits again
+        let mut space = space;
+        while let Some((index, joined)) = self
+            .freed
+            .iter()
+            .enumerate()
+            .find_map(|(index, other)| Self::join(space, *other).map(|joined| (index, joined)))
+        {
+            self.freed.swap_remove(index);
+            space = joined;
+        }
+        self.freed.push(space);
+    }
+
+    fn clear(&mut self) {
+        *self = Self::new(self.size.width, self.size.height);
+    }
+
+    /// Extend the packing area to `width` by `height`. What was allocated
+    /// stays where it is.
+    fn grow(&mut self, width: u32, height: u32) {
+        if width > self.size.width {
+            self.segments.push(Segment {
+                x: self.size.width,
+                y: 0,
+                width: width - self.size.width,
+            });
+        }
+        self.size = Size::new(width.max(self.size.width), height.max(self.size.height));
+    }
+
+    /// The fraction of the page taken by allocated rectangles.
+    fn occupancy(&self) -> f32 {
+        let area = self.size.width as u64 * self.size.height as u64;
+        if area == 0 {
+            return 0.0;
+        }
+        self.used as f32 / area as f32
+    }
+
+    // The smallest freed rectangle the tile fits in. What is left is split
+    // into the strip to the right of the tile and the strip below it.
+    fn allocate_freed(&mut self, width: u32, height: u32) -> Option<Rectangle<u32>> {
+        let (index, _) = self
+            .freed
+            .iter()
+            .enumerate()
+            .filter(|(_, space)| space.width >= width && space.height >= height)
+            .min_by_key(|(_, space)| space.width as u64 * space.height as u64)?;
+        let space = self.freed.swap_remove(index);
+
+        let right = Rectangle::new(space.x + width, space.y, space.width - width, height);
+        let below = Rectangle::new(space.x, space.y + height, space.width, space.height - height);
+        for part in [right, below] {
+            if part.width > 0 && part.height > 0 {
+                self.freed.push(part);
+            }
+        }
+
+        Some(Rectangle::new(space.x, space.y, width, height))
+    }
+
+    fn allocate_skyline(&mut self, width: u32, height: u32) -> Option<Rectangle<u32>> {
+        let (index, y) = (0..self.segments.len())
+            .filter_map(|index| self.fit(index, width, height).map(|y| (index, y)))
+            .min_by_key(|(_, y)| *y)?;
+
+        let x = self.segments[index].x;
+        self.raise(index, Segment { x, y: y + height, width });
+        Some(Rectangle::new(x, y, width, height))
+    }
+
+    // The lowest a tile fits with its left edge on segment `index`
+    fn fit(&self, index: usize, width: u32, height: u32) -> Option<u32> {
+        let x = self.segments[index].x;
+        if x + width > self.size.width {
+            return None;
+        }
+
+        let mut y = 0;
+        let mut covered = 0;
+        for segment in &self.segments[index..] {
+            if covered >= width {
+                break;
+            }
+            y = y.max(segment.y);
+            covered += segment.width;
+        }
+
+        (y + height <= self.size.height).then_some(y)
+    }
+
+    // Put `top` into the skyline at `index`, cutting back what it covers
+    fn raise(&mut self, index: usize, top: Segment) {
+        self.segments.insert(index, top);
 
+        let right = top.x + top.width;
+        let next = index + 1;
+        while next < self.segments.len() {
+            let segment = &mut self.segments[next];
+            let end = segment.x + segment.width;
+            if segment.x >= right {
+                break;
+            }
+            if end <= right {
+                self.segments.remove(next);
+            } else {
+                segment.width = end - right;
+                segment.x = right;
+                break;
+            }
+        }
+
+        self.segments.dedup_by(|next, previous| {
+            let level = next.y == previous.y;
+            if level {
+                previous.width += next.width;
+            }
+            level
+        });
+    }
+
+    fn join(a: Rectangle<u32>, b: Rectangle<u32>) -> Option<Rectangle<u32>> {
+        let (left, right) = if a.x <= b.x { (a, b) } else { (b, a) };
+        if left.y == right.y && left.height == right.height && left.x + left.width == right.x {
+            return Some(Rectangle::new(left.x, left.y, left.width + right.width, left.height));
+        }
+
+        let (top, bottom) = if a.y <= b.y { (a, b) } else { (b, a) };
+        if top.x == bottom.x && top.width == bottom.width && top.y + top.height == bottom.y {
+            return Some(Rectangle::new(top.x, top.y, top.width, top.height + bottom.height));
+        }
+
+        None
+    }
+}
+
+
+/// The atlas pages: the layers of one texture array, all the same size,
+/// each with the tiles packed into it.
 pub struct AtlasTexture {
-    initial_size: Size<i32>,
+    texture: Texture,
+    // One per layer
+    packers: Vec<Skyline>,
 }
 
 impl AtlasTexture {
-    pub fn new(initial_size: Size<i32>) -> Self {
-        Self { initial_size }
+    pub fn new(texture: Texture) -> Self {
+        let size = texture.size();
+        let packers = texture.layers()
+            .iter()
+            .map(|_| Skyline::new(size.width, size.height))
+            .collect();
+        Self { texture, packers }
+    }
+
+    pub fn texture(&self) -> &Texture {
+        &self.texture
     }
 
-    pub fn add(tile: &TileInfo) {
-        todo!()
+    pub fn page_count(&self) -> usize {
+        self.packers.len()
     }
 
-    pub fn remove(tile: &TileInfo) {
-        todo!()
+    /// Find room for `tile` on `page`, upload its pixels there and fill in
+    /// where it went. Returns `false`, leaving the tile unplaced, if the
+    /// page is full.
+    pub fn add(&mut self, queue: &wgpu::Queue, page: usize, tile: &mut TileInfo) -> bool {
+        let Rectangle { width, height, .. } = tile.space();
+        let space = match self.packers[page].allocate(width, height) {
+            Some(space) => space,
+            None => return false,
+        };
+
+        tile.place_at(page, space);
+        tile.texture_coords = self.calculate_tex_coords(tile);
+        self.texture.write(queue, page, space.x, space.y, &tile.page_pixels());
+        true
+    }
+
+    /// Free the space `tile` took. Its pixels are cleared, so nothing stale
+    /// bleeds into tiles placed there later.
+    pub fn remove(&mut self, queue: &wgpu::Queue, tile: &mut TileInfo) {
+        if !tile.placed {
+            return;
+        }
+
+        let space = tile.space();
+        self.packers[tile.page].deallocate(space);
+        let blank = RgbaImage::new(space.width, space.height);
+        self.texture.write(queue, tile.page, space.x, space.y, &blank);
+        tile.placed = false;
     }
 
-    pub fn defragment() {
-        todo!()
+    /// The fraction of `page` taken by tiles.
+    pub fn occupancy(&self, page: usize) -> f32 {
+        self.packers[page].occupancy()
     }
 
-    pub fn clean_up() {
-        todo!()
+    /// Bring the mip chains up to date after tiles were added or removed.
+    pub fn invalidate_mipmaps(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        self.texture.invalidate_mipmaps(device, queue);
     }
 
-    pub fn clear() {
-        todo!()
+    pub fn apply_texture_filter(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        filter: TextureFilter,
+    ) {
+        self.texture.apply_filter(device, queue, filter);
     }
 
-    pub fn apply_texture_filter() {
-        todo!()
+    /// Rebuild the pages on a new device from their retained pixels.
+    pub fn recreate(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<(), TextureError> {
+        self.texture.recreate(device, queue)
     }
 
-    fn try_grow() {
-        todo!()
+    fn size(&self) -> Size<u32> {
+        self.texture.size()
     }
 
-    fn calculate_tex_coords() -> TexCoords {
-        todo!()
+    /// Double the shorter side of every page, or the other one if that
+    /// would pass the device's limit, copying the tiles over on the GPU.
+    /// Returns `false` once neither side can grow. Tile coordinates have to
+    /// be recalculated afterwards.
+    fn try_grow(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) -> bool {
+        let max = device.limits().max_texture_dimension_2d;
+        let Size { width, height } = self.size();
+
+        let wider = Size::new(width * 2, height);
+        let taller = Size::new(width, height * 2);
+        let candidates = if width <= height { [wider, taller] } else { [taller, wider] };
+
+        match candidates.into_iter().find(|size| size.width <= max && size.height <= max) {
+            Some(size) => {
+                self.texture.grow(device, queue, size.width, size.height);
+                for packer in &mut self.packers {
+                    packer.grow(size.width, size.height);
+                }
+                true
+            }
+            None => false,
+        }
     }
+
+    /// Open an empty page the size of the others. Fails if the device
+    /// allows no more layers.
+    fn add_page(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<(), TextureError> {
+        self.texture.add_layer(device, queue)?;
+        let size = self.size();
+        self.packers.push(Skyline::new(size.width, size.height));
+        Ok(())
+    }
+
+    /// Coordinates of `tile`'s pixels, pulled in from the edges as far as
+    /// the current filter needs so the sampler never reads a neighbour.
+    fn calculate_tex_coords(&self, tile: &TileInfo) -> TexCoords {
+        tex_coords(tile, self.size(), self.texture.filter())
+    }
+}
+
+
+/// How big an atlas page is and how much of it holds tiles.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PageStats {
+    pub size: Size<u32>,
+    /// Fraction of the page's area taken by tiles, padding included.
+    pub occupancy: f32,
 }
 
 
 pub struct Atlas {
-    textures: Vec<AtlasTexture>,
+    // Every page, as the layers of one texture. `None` until the first
+    // rebuild
+    texture: Option<AtlasTexture>,
+    filter: TextureFilter,
+    padding: u32,
+    extrusion: u32,
+    // The loaded tile sheet cut into its grid of tiles, indexed by the low
+    // byte of a code
+    sheet: Vec<TileInfo>,
+    // Tiles added one at a time, packed around the sheet
+    tiles: BTreeMap<char, TileInfo>,
+    // Glyphs drawn for the cell size, shown for codes without a tile of
+    // their own
+    generated: BTreeMap<char, TileInfo>,
+    // How many cells, sprites and animation frames use each code
+    references: HashMap<char, usize>,
+    // Every sheet, tileset, font and drawn tile given so far, in order
+    sources: CacheKey,
 }
 
 impl Atlas {
-    pub fn add(tile: &TileInfo) {
-        todo!()
+    /// An atlas whose tiles keep `padding` pixels apart, each with its
+    /// edges extruded by `extrusion` pixels.
+    pub fn new(filter: TextureFilter, padding: u32, extrusion: u32) -> Self {
+        let mut atlas = Self {
+            texture: None,
+            filter,
+            padding,
+            extrusion,
+            sheet: Vec::new(),
+            tiles: BTreeMap::new(),
+            generated: BTreeMap::new(),
+            references: HashMap::new(),
+            sources: CacheKey::new(),
+        };
+
+        // Tiles sample a white placeholder until a sheet is loaded, so
+        // cells still show their colors
+        let placeholder = RgbaImage::from_pixel(1, 1, image::Rgba([255; 4]));
+        atlas.sheet = atlas.slice_sheet(&placeholder);
+        atlas
+    }
+
+    pub fn filter(&self) -> TextureFilter {
+        self.filter
+    }
+
+    /// Load an image file as the tile sheet.
+    pub fn load_path(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        path: &Path,
+    ) -> Result<(), TextureError> {
+        let sheet = texture::load_image(path)?;
+        self.set_sheet(device, queue, sheet)?;
+        Ok(())
+    }
+
+    /// Decode an in-memory image as the tile sheet.
+    pub fn load_bytes(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        bytes: &[u8],
+        label: &str,
+    ) -> Result<(), TextureError> {
+        let sheet = texture::decode_image(bytes, label)?;
+        self.set_sheet(device, queue, sheet)?;
+        Ok(())
+    }
+
+    /// Replace the tile sheet. Its tiles are packed separately, taking
+    /// the old sheet's space where they fit. Returns whether pages were
+    /// grown, added or replaced, in which case the caller has to recreate
+    /// the bind groups that sample them. Fails if a tile does not fit on
+    /// any page the device allows; it is left unplaced and the rest are
+    /// still packed, so pages may have changed all the same.
+    pub fn set_sheet(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        sheet: RgbaImage,
+    ) -> Result<bool, TextureError> {
+        self.hash_source(TileSource::Sheet, None, &sheet);
+        let sheet = self.slice_sheet(&sheet);
+        let old = std::mem::replace(&mut self.sheet, sheet);
+        let before = self.page_sizes();
+        let Some(pages) = &mut self.texture else {
+            self.rebuild(device, queue)?;
+            return Ok(true);
+        };
+
+        for mut tile in old {
+            pages.remove(queue, &mut tile);
+        }
+        let placed = place_all(pages, device, queue, &mut self.sheet);
+        let changed = self.finish(device, queue, before);
+        placed.map(|()| changed)
+    }
+
+    /// Add tiles drawn on the CPU, replacing any with the same code. They
+    /// take precedence over the sheet. Only the new tiles are uploaded.
+    /// Returns whether pages were grown or added, and fails on a tile too
+    /// big for any page, like [`Atlas::set_sheet`].
+    pub fn insert_tiles(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        tiles: impl IntoIterator<Item = (char, RgbaImage)>,
+    ) -> Result<bool, TextureError> {
+        if self.texture.is_none() {
+            self.extend_tiles(tiles);
+            self.rebuild(device, queue)?;
+            return Ok(true);
+        }
+
+        let before = self.page_sizes();
+        let mut placed = Ok(());
+        for (code, pixels) in tiles {
+            self.hash_source(TileSource::Tileset, Some(code), &pixels);
+            let tile = self.new_tile(pixels, TileSource::Tileset);
+            let result = self.insert_tile(device, queue, code, tile);
+            placed = placed.and(result);
+        }
+        let changed = self.finish(device, queue, before);
+        placed.map(|()| changed)
+    }
+
+    /// Add one tile that [`Atlas::clean_up`] evicts once nothing references
+    /// it, such as a glyph rendered on demand. `source` is what the dump
+    /// lists it as. Otherwise like [`Atlas::insert_tiles`].
+    pub fn add(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        code: char,
+        pixels: RgbaImage,
+        source: TileSource,
+    ) -> Result<bool, TextureError> {
+        if self.texture.is_none() {
+            self.extend_added([(code, pixels)], source);
+            self.rebuild(device, queue)?;
+            return Ok(true);
+        }
+
+        let before = self.page_sizes();
+        let tile = self.added_tile(code, pixels, source);
+        let placed = self.insert_tile(device, queue, code, tile);
+        let changed = self.finish(device, queue, before);
+        placed.map(|()| changed)
+    }
+
+    /// Take tile `code` out of the atlas, whether or not it is still used.
+    /// Cells showing it fall back to the sheet. Returns whether there was
+    /// such a tile.
+    pub fn remove(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, code: char) -> bool {
+        let Some(mut tile) = self.tiles.remove(&code) else {
+            return false;
+        };
+
+        if let Some(pages) = &mut self.texture {
+            pages.remove(queue, &mut tile);
+            pages.invalidate_mipmaps(device, queue);
+        }
+        true
+    }
+
+    /// Evict every tile added with [`Atlas::add`] that nothing references.
+    /// Returns the evicted codes.
+    pub fn clean_up(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) -> Vec<char> {
+        let mut evicted = self.evict_unused();
+        if let Some(pages) = &mut self.texture {
+            for (_, tile) in &mut evicted {
+                pages.remove(queue, tile);
+            }
+            if !evicted.is_empty() {
+                pages.invalidate_mipmaps(device, queue);
+            }
+        }
+        evicted.into_iter().map(|(code, _)| code).collect()
+    }
+
+    /// Repack the surviving tiles into fresh pages with no dead space. Every
+    /// tile may move, so the caller has to recreate the bind groups and
+    /// rebuild whatever holds texture coordinates. Fails like
+    /// [`Atlas::rebuild`].
+    pub fn defragment(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<(), TextureError> {
+        self.rebuild(device, queue)
+    }
+
+    /// Replace the generated glyphs with `tiles`. They only show for codes
+    /// no inserted tile covers, so tilesets keep their own box drawing
+    /// whenever the glyphs are redrawn. Returns whether pages were grown or
+    /// added, and fails on a glyph too big for any page, like
+    /// [`Atlas::set_sheet`].
+    pub fn set_generated(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        tiles: impl IntoIterator<Item = (char, RgbaImage)>,
+    ) -> Result<bool, TextureError> {
+        let tiles: Vec<(char, TileInfo)> = tiles
+            .into_iter()
+            .map(|(code, pixels)| {
+                self.hash_source(TileSource::Generated, Some(code), &pixels);
+                (code, self.new_tile(pixels, TileSource::Generated))
+            })
+            .collect();
+        let old = std::mem::replace(&mut self.generated, tiles.into_iter().collect());
+        let before = self.page_sizes();
+        let Some(pages) = &mut self.texture else {
+            self.rebuild(device, queue)?;
+            return Ok(true);
+        };
+
+        for mut tile in old.into_values() {
+            pages.remove(queue, &mut tile);
+        }
+        let placed = place_all(pages, device, queue, self.generated.values_mut());
+        let changed = self.finish(device, queue, before);
+        placed.map(|()| changed)
+    }
+
+    /// Like [`Atlas::set_generated`], but the glyphs only reach the GPU with
+    /// the next rebuild. Used before there is a device.
+    pub fn extend_generated(&mut self, tiles: impl IntoIterator<Item = (char, RgbaImage)>) {
+        for (code, pixels) in tiles {
+            self.hash_source(TileSource::Generated, Some(code), &pixels);
+            let tile = self.new_tile(pixels, TileSource::Generated);
+            self.generated.insert(code, tile);
+        }
+    }
+
+    /// Remove every inserted tile, leaving the sheet and generated glyphs.
+    /// Fails like [`Atlas::rebuild`].
+    pub fn clear(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<(), TextureError> {
+        self.tiles.clear();
+        self.rebuild(device, queue)
+    }
+
+    /// Count one more cell, sprite or frame showing `code`.
+    pub fn retain(&mut self, code: char) {
+        *self.references.entry(code).or_insert(0) += 1;
+    }
+
+    /// Count one less user of `code`.
+    pub fn release(&mut self, code: char) {
+        if let Some(count) = self.references.get_mut(&code) {
+            *count -= 1;
+            if *count == 0 {
+                self.references.remove(&code);
+            }
+        }
+    }
+
+    pub fn references(&self, code: char) -> usize {
+        self.references.get(&code).copied().unwrap_or(0)
+    }
+
+    fn insert_tile(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        code: char,
+        mut tile: TileInfo,
+    ) -> Result<(), TextureError> {
+        // Only called once there are pages
+        let pages = self.texture.as_mut().unwrap();
+        if let Some(mut old) = self.tiles.remove(&code) {
+            pages.remove(queue, &mut old);
+        }
+
+        // Kept even if unplaced, so a later rebuild can try again
+        let placed = place(pages, device, queue, &mut tile);
+        self.tiles.insert(code, tile);
+        placed
+    }
+
+    // Take the tiles `clean_up` evicts out of the table, still holding the
+    // space they take on their pages
+    fn evict_unused(&mut self) -> Vec<(char, TileInfo)> {
+        self.unused_tiles()
+            .into_iter()
+            .map(|code| (code, self.tiles.remove(&code).unwrap()))
+            .collect()
+    }
+
+    fn unused_tiles(&self) -> Vec<char> {
+        self.tiles
+            .iter()
+            .filter(|(code, tile)| tile.evictable && !self.references.contains_key(code))
+            .map(|(code, _)| *code)
+            .collect()
+    }
+
+    /// Like [`Atlas::insert_tiles`], but the tiles only reach the GPU with
+    /// the next rebuild. Used before there is a device.
+    pub fn extend_tiles(&mut self, tiles: impl IntoIterator<Item = (char, RgbaImage)>) {
+        for (code, pixels) in tiles {
+            self.hash_source(TileSource::Tileset, Some(code), &pixels);
+            let tile = self.new_tile(pixels, TileSource::Tileset);
+            self.tiles.insert(code, tile);
+        }
+    }
+
+    /// Like [`Atlas::add`] for each tile, but the tiles only reach the GPU
+    /// with the next rebuild.
+    pub fn extend_added(
+        &mut self,
+        tiles: impl IntoIterator<Item = (char, RgbaImage)>,
+        source: TileSource,
+    ) {
+        for (code, pixels) in tiles {
+            let tile = self.added_tile(code, pixels, source);
+            self.tiles.insert(code, tile);
+        }
+    }
+
+    /// Count `font` among the sources of the atlas, since the glyphs drawn
+    /// from it on demand are not.
+    pub fn add_font_source(&mut self, font: &Font) {
+        TileSource::Font.hash(&mut self.sources);
+        font.hash(&mut self.sources);
+    }
+
+    /// A hash of every sheet, tileset, font and tile given to the atlas so
+    /// far, in order, for keying its cache. Restoring the cache leaves it
+    /// as it is.
+    pub fn source_key(&self) -> u64 {
+        self.sources.finish()
+    }
+
+    /// The codes of the inserted tiles that came from `source`.
+    pub fn codes(&self, source: TileSource) -> impl Iterator<Item = char> + '_ {
+        self.tiles
+            .iter()
+            .filter(move |(_, tile)| tile.source == source)
+            .map(|(code, _)| *code)
     }
 
-    pub fn remove(tile: &TileInfo) {
-        todo!()
+    /// Whether `code` has an inserted tile of its own rather than falling
+    /// back to a generated glyph or the sheet.
+    pub fn contains(&self, code: char) -> bool {
+        self.tiles.contains_key(&code)
     }
 
-    pub fn defragment() {
-        todo!()
+    /// Where tile `code` is: an inserted tile if there is one, then a
+    /// generated glyph, otherwise the sheet tile indexed by the low byte of
+    /// the code.
+    pub fn locate(&self, code: char) -> TileLocation {
+        let tile = self.tiles.get(&code)
+            .filter(|tile| tile.is_placed())
+            .or_else(|| self.generated.get(&code).filter(|tile| tile.is_placed()));
+        match tile {
+            Some(tile) => tile.location(),
+            None => self.sheet[(code as u32 & 0xFF) as usize].location(),
+        }
     }
 
-    pub fn clean_up() {
-        todo!()
+    /// Shift inserted tile `code` by `offset` pixels wherever it is drawn,
+    /// on top of any offset its tileset gives it. Returns whether there is
+    /// such a tile.
+    pub fn set_tile_offset(&mut self, code: char, offset: Point<i32>) -> bool {
+        match self.tiles.get_mut(&code) {
+            Some(tile) => {
+                tile.offset = offset;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Pack the sheet, every tile and the generated glyphs onto new pages.
+    /// The first page is made just big enough for all of them, within the
+    /// device's limit; what does not fit goes on further pages. Fails if a
+    /// tile is bigger than any page the device allows, leaving it unplaced.
+    pub fn rebuild(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<(), TextureError> {
+        let mut tiles: Vec<&mut TileInfo> = self.sheet
+            .iter_mut()
+            .chain(self.tiles.values_mut())
+            .chain(self.generated.values_mut())
+            .collect();
+        let max = device.limits().max_texture_dimension_2d;
+        let packers = lay_out(&mut tiles, max);
+
+        // Drawn on the CPU and uploaded in one go
+        let size = packers[0].size;
+        let mut layers = vec![RgbaImage::new(size.width, size.height); packers.len()];
+        for tile in tiles.iter().filter(|tile| tile.placed) {
+            let (x, y) = (tile.total_space.x as i64, tile.total_space.y as i64);
+            image::imageops::replace(&mut layers[tile.page], &*tile.page_pixels(), x, y);
+        }
+        let unplaced = tiles
+            .iter()
+            .find(|tile| !tile.placed)
+            .map(|tile| TextureError::TooLarge("atlas tile".into(), tile.space().size(), max));
+
+        self.texture = None;
+        let mut pages = match new_texture(device, queue, layers, self.filter, self.extrusion) {
+            Ok(pages) => pages,
+            Err(error) => {
+                for tile in &mut tiles {
+                    tile.placed = false;
+                }
+                return Err(error);
+            }
+        };
+        pages.packers = packers;
+        self.texture = Some(pages);
+        self.refresh_tex_coords();
+        unplaced.map_or(Ok(()), Err)
+    }
+
+    /// Rebuild every page on a new device after the old one was lost, or
+    /// build the first page if there is none yet. Fails if the pages are
+    /// bigger or more than the new device allows.
+    pub fn recreate(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<(), TextureError> {
+        match &mut self.texture {
+            Some(pages) => pages.recreate(device, queue),
+            None => self.rebuild(device, queue),
+        }
+    }
+
+    /// The texture holding every page as a layer, once there are pages.
+    pub fn texture(&self) -> Option<&Texture> {
+        self.texture.as_ref().map(AtlasTexture::texture)
+    }
+
+    /// Write the packed pages and tile tables to `path`, for
+    /// [`Atlas::load_cache`] to restore while the sources still hash to
+    /// `key`.
+    pub fn save_cache(&self, path: impl AsRef<Path>, key: u64) -> io::Result<()> {
+        let pages: Vec<(&RgbaImage, &Skyline)> = self.texture
+            .iter()
+            .flat_map(|pages| pages.texture.layers().iter().zip(&pages.packers))
+            .collect();
+        let bytes = write_cache(
+            key,
+            self.padding,
+            self.extrusion,
+            pages.into_iter(),
+            &self.sheet,
+            [&self.tiles, &self.generated],
+        );
+
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        // Written aside and moved over, so a crash never leaves half a cache
+        let partial = path.with_extension("partial");
+        std::fs::write(&partial, bytes)?;
+        std::fs::rename(partial, path)
     }
 
-    pub fn clear() {
-        todo!()
+    /// Replace every page and tile with the cache at `path`, if it was saved
+    /// under `key` with the same padding and extrusion. Returns `false`,
+    /// changing nothing, if there is no such cache, for the caller to build
+    /// the atlas as usual. Tiles may have moved, as after a rebuild.
+    pub fn load_cache(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        path: impl AsRef<Path>,
+        key: u64,
+    ) -> io::Result<bool> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(error) => return Err(error),
+        };
+        let cached = match read_cache(&bytes, key, self.padding, self.extrusion)? {
+            Some(cached) => cached,
+            None => return Ok(false),
+        };
+
+        let (layers, packers): (Vec<RgbaImage>, Vec<Skyline>) = cached.pages.into_iter().unzip();
+        let pages = new_texture(device, queue, layers, self.filter, self.extrusion);
+        // Pages packed for a device that allows bigger textures or more layers
+        let Ok(mut pages) = pages else {
+            return Ok(false);
+        };
+        pages.packers = packers;
+        pages.invalidate_mipmaps(device, queue);
+        self.texture = Some(pages);
+        self.sheet = cached.sheet;
+        self.tiles = cached.tiles;
+        self.generated = cached.generated;
+
+        self.refresh_tex_coords();
+        Ok(true)
+    }
+
+    /// Size and fill of every page, in page order.
+    pub fn page_stats(&self) -> Vec<PageStats> {
+        let Some(pages) = &self.texture else {
+            return Vec::new();
+        };
+        (0..pages.page_count())
+            .map(|page| PageStats {
+                size: pages.size(),
+                occupancy: pages.occupancy(page),
+            })
+            .collect()
+    }
+
+    /// Write every page to `dir` as `page-<n>.png`, along with a
+    /// `manifest.json` listing where each tile is, to find out why a tile
+    /// draws as garbage. `dir` is created if missing.
+    pub fn dump(&self, dir: impl AsRef<Path>) -> io::Result<()> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+
+        let layers = self.texture().map(Texture::layers).unwrap_or_default();
+        for (index, layer) in layers.iter().enumerate() {
+            layer
+                .save(dir.join(page_file(index)))
+                .map_err(io::Error::other)?;
+        }
+        std::fs::write(dir.join("manifest.json"), self.manifest())
+    }
+
+    /// The JSON manifest written by [`Atlas::dump`]. Sheet tiles are listed
+    /// by their index in the sheet, other tiles by their code, each with
+    /// its [`TileSource`].
+    fn manifest(&self) -> String {
+        let mut json = String::from("{\n  \"pages\": [");
+        for (index, stats) in self.page_stats().iter().enumerate() {
+            let separator = if index == 0 { "" } else { "," };
+            let _ = write!(
+                json,
+                "{separator}\n    {{\"file\": \"{}\", \"width\": {}, \"height\": {}, \"occupancy\": {}}}",
+                page_file(index),
+                stats.size.width,
+                stats.size.height,
+                stats.occupancy,
+            );
+        }
+        json.push_str("\n  ],\n  \"tiles\": [");
+
+        let mut first = true;
+        let sheet = self.sheet
+            .iter()
+            .enumerate()
+            .map(|(index, tile)| (format!("\"index\": {index}"), tile));
+        let coded = self.tiles.iter().chain(&self.generated).map(|(code, tile)| {
+            (format!("\"code\": {}, \"char\": \"{}\"", *code as u32, json_char(*code)), tile)
+        });
+        for (key, tile) in sheet.chain(coded).filter(|(_, tile)| tile.placed) {
+            let rect = tile.useful_space;
+            let uv = tile.texture_coords;
+            let separator = if first { "" } else { "," };
+            first = false;
+            let _ = write!(
+                json,
+                "{separator}\n    {{{key}, \"source\": \"{}\", \"page\": {}, \
+                 \"rect\": [{}, {}, {}, {}], \"uv\": [{}, {}, {}, {}]}}",
+                tile.source.name(),
+                tile.page,
+                rect.x, rect.y, rect.width, rect.height,
+                uv.tu1, uv.tv1, uv.tu2, uv.tv2,
+            );
+        }
+        json.push_str("\n  ]\n}\n");
+        json
+    }
+
+    fn page_sizes(&self) -> Vec<Size<u32>> {
+        self.page_stats().iter().map(|stats| stats.size).collect()
+    }
+
+    // Bring the mip chains up to date after tiles were uploaded, and the
+    // tile coordinates if pages changed size since `before`
+    fn finish(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, before: Vec<Size<u32>>) -> bool {
+        if let Some(pages) = &mut self.texture {
+            pages.invalidate_mipmaps(device, queue);
+        }
+
+        let changed = self.page_sizes() != before;
+        if changed {
+            self.refresh_tex_coords();
+        }
+        changed
+    }
+
+    fn refresh_tex_coords(&mut self) {
+        let Some(pages) = &self.texture else {
+            return;
+        };
+        let tiles = self.sheet
+            .iter_mut()
+            .chain(self.tiles.values_mut())
+            .chain(self.generated.values_mut());
+        for tile in tiles {
+            if tile.placed {
+                tile.texture_coords = pages.calculate_tex_coords(tile);
+            }
+        }
+    }
+
+    fn hash_source(&mut self, source: TileSource, code: Option<char>, pixels: &RgbaImage) {
+        source.hash(&mut self.sources);
+        code.hash(&mut self.sources);
+        pixels.dimensions().hash(&mut self.sources);
+        pixels.as_raw().hash(&mut self.sources);
+    }
+
+    // An evictable tile for `add`
+    fn added_tile(&mut self, code: char, pixels: RgbaImage, source: TileSource) -> TileInfo {
+        // Font glyphs follow from the font, which is a source of its own
+        if source != TileSource::Font {
+            self.hash_source(source, Some(code), &pixels);
+        }
+        let mut tile = self.new_tile(pixels, source);
+        tile.evictable = true;
+        tile
+    }
+
+    fn new_tile(&self, pixels: RgbaImage, source: TileSource) -> TileInfo {
+        let mut tile = TileInfo::new(pixels, self.padding, self.extrusion);
+        tile.source = source;
+        tile
+    }
+
+    /// Cut `sheet` into its grid of tiles, left to right and top to bottom.
+    /// A sheet smaller than the grid is used whole for every tile.
+    fn slice_sheet(&self, sheet: &RgbaImage) -> Vec<TileInfo> {
+        let count = (SHEET_COLUMNS * SHEET_ROWS) as usize;
+        let width = sheet.width() / SHEET_COLUMNS;
+        let height = sheet.height() / SHEET_ROWS;
+        if width == 0 || height == 0 {
+            return vec![self.new_tile(sheet.clone(), TileSource::Sheet); count];
+        }
+
+        (0..count as u32)
+            .map(|index| {
+                let (column, row) = (index % SHEET_COLUMNS, index / SHEET_COLUMNS);
+                let pixels = image::imageops::crop_imm(
+                    sheet,
+                    column * width,
+                    row * height,
+                    width,
+                    height,
+                );
+                self.new_tile(pixels.to_image(), TileSource::Sheet)
+            })
+            .collect()
+    }
+
+    /// Switch every page to `filter`. Tile coordinates are inset to suit
+    /// it, so the caller has to rebuild the bind groups that sample the
+    /// atlas and whatever holds texture coordinates.
+    pub fn apply_texture_filter(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        filter: TextureFilter,
+    ) {
+        self.filter = filter;
+        if let Some(pages) = &mut self.texture {
+            pages.apply_texture_filter(device, queue, filter);
+        }
+        self.refresh_tex_coords();
+    }
+}
+
+
+/// How many texels inside its edges a tile is sampled so `filter` never
+/// reaches past its `extrusion`. Linear filtering reads half a texel beyond
+/// the coordinates, which only extruded pixels make safe. Mipmapped
+/// filtering reads further on coarser levels; [`mip_limit`] keeps it to the
+/// levels the extrusion covers.
+fn edge_inset(filter: TextureFilter, extrusion: u32) -> f32 {
+    match filter {
+        TextureFilter::Nearest => NEAREST_INSET,
+        TextureFilter::Linear | TextureFilter::LinearMipmapped if extrusion == 0 => 0.5,
+        TextureFilter::Linear | TextureFilter::LinearMipmapped => 0.0,
+    }
+}
+
+/// The coarsest mip level whose samples at a tile's edge stay within
+/// `extrusion` pixels of it. A texel of level `n` spans `2^n` pixels, and
+/// filtering at the edge reads up to one and a half of them past it, so
+/// level `n` needs an extrusion of `3 * 2^(n - 1)`. Coarser levels would
+/// blend in neighbouring tiles, so `LinearMipmapped` stops there: an
+/// extrusion of 0 to 2 allows only the full-size level, 3 to 5 allow
+/// halving once, 6 to 11 twice, and so on.
+fn mip_limit(extrusion: u32) -> u32 {
+    let mut level: u32 = 0;
+    while 3 << level <= extrusion * 2 {
+        level += 1;
+    }
+    level.saturating_sub(1)
+}
+
+/// An atlas as read back from a cache file, before it is uploaded.
+struct CachedAtlas {
+    pages: Vec<(RgbaImage, Skyline)>,
+    sheet: Vec<TileInfo>,
+    tiles: BTreeMap<char, TileInfo>,
+    generated: BTreeMap<char, TileInfo>,
+}
+
+/// Encode an atlas. `maps` are the inserted tiles, then the generated
+/// glyphs.
+fn write_cache<'a>(
+    key: u64,
+    padding: u32,
+    extrusion: u32,
+    pages: impl ExactSizeIterator<Item = (&'a RgbaImage, &'a Skyline)>,
+    sheet: &[TileInfo],
+    maps: [&BTreeMap<char, TileInfo>; 2],
+) -> Vec<u8> {
+    let mut writer = Writer::new();
+    writer.bytes(CACHE_MAGIC);
+    writer.u32(CACHE_VERSION);
+    writer.u64(key);
+    writer.u32(padding);
+    writer.u32(extrusion);
+
+    writer.u32(pages.len() as u32);
+    for (pixels, packer) in pages {
+        writer.u32(packer.size.width);
+        writer.u32(packer.size.height);
+        writer.u64(packer.used);
+        writer.u32(packer.segments.len() as u32);
+        for segment in &packer.segments {
+            writer.u32(segment.x);
+            writer.u32(segment.y);
+            writer.u32(segment.width);
+        }
+        writer.u32(packer.freed.len() as u32);
+        for rect in &packer.freed {
+            for value in [rect.x, rect.y, rect.width, rect.height] {
+                writer.u32(value);
+            }
+        }
+        writer.bytes(pixels.as_raw());
+    }
+
+    writer.u32(sheet.len() as u32);
+    for tile in sheet {
+        write_tile(&mut writer, tile);
+    }
+    for tiles in maps {
+        writer.u32(tiles.len() as u32);
+        for (code, tile) in tiles {
+            writer.u32(*code as u32);
+            write_tile(&mut writer, tile);
+        }
+    }
+
+    writer.finish()
+}
+
+fn write_tile(writer: &mut Writer, tile: &TileInfo) {
+    writer.bool(tile.placed);
+    writer.u32(tile.page as u32);
+    for rect in [tile.total_space, tile.useful_space] {
+        for value in [rect.x, rect.y, rect.width, rect.height] {
+            writer.i32(value);
+        }
+    }
+    writer.i32(tile.offset.x);
+    writer.i32(tile.offset.y);
+    writer.i32(tile.spacing.width);
+    writer.i32(tile.spacing.height);
+    writer.i32(tile.extrusion);
+    writer.bool(tile.evictable);
+    writer.u8(SOURCES.iter().position(|source| *source == tile.source).unwrap() as u8);
+
+    // Placed tiles are cut back out of their page
+    if !tile.placed {
+        writer.u32(tile.pixels.width());
+        writer.u32(tile.pixels.height());
+        writer.bytes(tile.pixels.as_raw());
+    }
+}
+
+/// Decode a cache written by [`write_cache`]. `None` if it was written for
+/// another key, padding or extrusion, or by another version.
+fn read_cache(bytes: &[u8], key: u64, padding: u32, extrusion: u32) -> io::Result<Option<CachedAtlas>> {
+    let mut reader = Reader::new(bytes);
+    if reader.bytes(CACHE_MAGIC.len())? != CACHE_MAGIC || reader.u32()? != CACHE_VERSION {
+        return Ok(None);
+    }
+    if reader.u64()? != key || reader.u32()? != padding || reader.u32()? != extrusion {
+        return Ok(None);
+    }
+
+    let page_count = reader.u32()?;
+    let mut pages = Vec::new();
+    for _ in 0..page_count {
+        let size = Size::new(reader.u32()?, reader.u32()?);
+        let used = reader.u64()?;
+        let segments = (0..reader.u32()?)
+            .map(|_| Ok(Segment { x: reader.u32()?, y: reader.u32()?, width: reader.u32()? }))
+            .collect::<io::Result<Vec<_>>>()?;
+        let freed = (0..reader.u32()?)
+            .map(|_| Ok(Rectangle::new(reader.u32()?, reader.u32()?, reader.u32()?, reader.u32()?)))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let length = size.width as usize * size.height as usize * 4;
+        let pixels = RgbaImage::from_raw(size.width, size.height, reader.bytes(length)?.to_vec())
+            .ok_or_else(|| cache::invalid("atlas page has the wrong size"))?;
+        pages.push((pixels, Skyline { size, segments, freed, used }));
+    }
+    // Pages are the layers of one texture
+    let first = pages.first().map(|(_, packer)| packer.size);
+    if first.is_none() || pages.iter().any(|(_, packer)| Some(packer.size) != first) {
+        return Err(cache::invalid("atlas pages are missing or differ in size"));
+    }
+
+    let sheet = (0..reader.u32()?)
+        .map(|_| read_tile(&mut reader, &pages))
+        .collect::<io::Result<Vec<_>>>()?;
+    if sheet.len() != (SHEET_COLUMNS * SHEET_ROWS) as usize {
+        return Err(cache::invalid("tile sheet has the wrong number of tiles"));
+    }
+
+    let mut maps = [BTreeMap::new(), BTreeMap::new()];
+    for tiles in &mut maps {
+        for _ in 0..reader.u32()? {
+            let code = char::from_u32(reader.u32()?)
+                .ok_or_else(|| cache::invalid("tile code is not a character"))?;
+            tiles.insert(code, read_tile(&mut reader, &pages)?);
+        }
+    }
+
+    let [tiles, generated] = maps;
+    Ok(Some(CachedAtlas { pages, sheet, tiles, generated }))
+}
+
+fn read_tile(reader: &mut Reader, pages: &[(RgbaImage, Skyline)]) -> io::Result<TileInfo> {
+    let placed = reader.bool()?;
+    let page = reader.u32()? as usize;
+    let mut rect = || -> io::Result<Rectangle<i32>> {
+        Ok(Rectangle::new(reader.i32()?, reader.i32()?, reader.i32()?, reader.i32()?))
+    };
+    let total_space = rect()?;
+    let useful_space = rect()?;
+    let offset = Point::new(reader.i32()?, reader.i32()?);
+    let spacing = Size::new(reader.i32()?, reader.i32()?);
+    let extrusion = reader.i32()?;
+    let evictable = reader.bool()?;
+    let source = *SOURCES
+        .get(reader.u8()? as usize)
+        .ok_or_else(|| cache::invalid("unknown tile source"))?;
+
+    let pixels = if placed {
+        let (pixels, _) = pages
+            .get(page)
+            .ok_or_else(|| cache::invalid("tile is on a page that does not exist"))?;
+        let Rectangle { x, y, width, height } = useful_space;
+        let right = x.checked_add(width);
+        let bottom = y.checked_add(height);
+        let inside = x >= 0 && y >= 0 && width >= 0 && height >= 0
+            && right.is_some_and(|right| right as u32 <= pixels.width())
+            && bottom.is_some_and(|bottom| bottom as u32 <= pixels.height());
+        if !inside {
+            return Err(cache::invalid("tile is outside its page"));
+        }
+        image::imageops::crop_imm(pixels, x as u32, y as u32, width as u32, height as u32).to_image()
+    } else {
+        let (width, height) = (reader.u32()?, reader.u32()?);
+        let length = width as usize * height as usize * 4;
+        RgbaImage::from_raw(width, height, reader.bytes(length)?.to_vec())
+            .ok_or_else(|| cache::invalid("tile has the wrong size"))?
+    };
+
+    Ok(TileInfo {
+        pixels,
+        useful_space,
+        total_space,
+        texture_coords: TexCoords::FULL,
+        offset,
+        spacing,
+        extrusion,
+        // Unplaced tiles are on no page, whatever the file says
+        page: if placed { page } else { 0 },
+        placed,
+        evictable,
+        source,
+    })
+}
+
+fn page_file(index: usize) -> String {
+    format!("page-{index}.png")
+}
+
+/// `code` as the inside of a JSON string, escaping anything that is not
+/// printable as is.
+fn json_char(code: char) -> String {
+    match code {
+        '"' => "\\\"".into(),
+        '\\' => "\\\\".into(),
+        code if code.is_control() => {
+            let mut units = [0; 2];
+            code.encode_utf16(&mut units)
+                .iter()
+                .map(|unit| format!("\\u{unit:04x}"))
+                .collect()
+        }
+        code => code.to_string(),
+    }
+}
+
+/// `image` with its outermost pixels repeated `amount` times around it.
+fn extrude(image: &RgbaImage, amount: u32) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    RgbaImage::from_fn(width + amount * 2, height + amount * 2, |x, y| {
+        let x = x.saturating_sub(amount).min(width - 1);
+        let y = y.saturating_sub(amount).min(height - 1);
+        *image.get_pixel(x, y)
+    })
+}
+
+/// Where `tile`'s pixels are on a page of `size`, pulled in from the edges
+/// as far as `filter` needs so the sampler never reads a neighbour.
+fn tex_coords(tile: &TileInfo, size: Size<u32>, filter: TextureFilter) -> TexCoords {
+    let space = tile.useful_space;
+    let inset = edge_inset(filter, tile.extrusion as u32);
+
+    TexCoords::from_rect(
+        space.x as u32,
+        space.y as u32,
+        space.width as u32,
+        space.height as u32,
+        size,
+    )
+    .inset(inset / size.width as f32, inset / size.height as f32)
+}
+
+/// Pack `tiles` tallest first, which leaves the flattest skyline, onto
+/// pages of one size: the smallest within `max` that holds them all, with
+/// more pages for what does not fit. Tiles bigger than a page are left
+/// unplaced.
+fn lay_out(tiles: &mut [&mut TileInfo], max: u32) -> Vec<Skyline> {
+    tiles.sort_by_key(|tile| Reverse(tile.total_space.height));
+    let sizes: Vec<Size<u32>> = tiles.iter().map(|tile| tile.space().size()).collect();
+    let size = page_size(&sizes, max);
+
+    let mut packers = vec![Skyline::new(size.width, size.height)];
+    for (tile, space) in tiles.iter_mut().zip(sizes) {
+        tile.placed = false;
+        if space.width > size.width || space.height > size.height {
+            continue;
+        }
+
+        let found = packers
+            .iter_mut()
+            .enumerate()
+            .find_map(|(page, packer)| Some((page, packer.allocate(space.width, space.height)?)));
+        let (page, rect) = match found {
+            Some(found) => found,
+            None => {
+                let mut packer = Skyline::new(size.width, size.height);
+                let rect = packer.allocate(space.width, space.height).unwrap();
+                packers.push(packer);
+                (packers.len() - 1, rect)
+            }
+        };
+        tile.place_at(page, rect);
+    }
+    packers
+}
+
+/// Pages holding `layers`, mipmapped no further than `extrusion` keeps
+/// tiles from bleeding into each other.
+fn new_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layers: Vec<RgbaImage>,
+    filter: TextureFilter,
+    extrusion: u32,
+) -> Result<AtlasTexture, TextureError> {
+    let mut texture = Texture::from_layers(device, queue, layers, Some("Atlas Pages"), filter)?;
+    texture.set_mip_limit(device, mip_limit(extrusion));
+    Ok(AtlasTexture::new(texture))
+}
+
+/// Put `tile` on the first page with room for it. Pages are grown before a
+/// new one is opened. Fails, leaving the tile unplaced, if it is bigger
+/// than any page the device allows, or if the device allows no more pages.
+fn place(
+    pages: &mut AtlasTexture,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    tile: &mut TileInfo,
+) -> Result<(), TextureError> {
+    let fits = |pages: &mut AtlasTexture, tile: &mut TileInfo| {
+        (0..pages.page_count()).any(|page| pages.add(queue, page, tile))
+    };
+    if fits(pages, tile) {
+        return Ok(());
+    }
+    while pages.try_grow(device, queue) {
+        if fits(pages, tile) {
+            return Ok(());
+        }
+    }
+
+    let space = tile.total_space;
+    let space = Size::new(space.width as u32, space.height as u32);
+    let max = device.limits().max_texture_dimension_2d;
+    let size = pages.size();
+    if space.width > size.width || space.height > size.height {
+        return Err(TextureError::TooLarge("atlas tile".into(), space, max));
+    }
+
+    pages.add_page(device, queue)?;
+    let page = pages.page_count() - 1;
+    pages.add(queue, page, tile);
+    Ok(())
+}
+
+/// [`place`] every tile, carrying on past failures. Returns the first.
+fn place_all<'a>(
+    pages: &mut AtlasTexture,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    tiles: impl IntoIterator<Item = &'a mut TileInfo>,
+) -> Result<(), TextureError> {
+    let mut placed = Ok(());
+    for tile in tiles {
+        let result = place(pages, device, queue, tile);
+        placed = placed.and(result);
+    }
+    placed
+}
+
+/// The smallest page, in powers of two no bigger than `max`, that packs
+/// rectangles of `sizes` in the order given. If even a `max` by `max` page
+/// is too small, that is what is returned.
+fn page_size(sizes: &[Size<u32>], max: u32) -> Size<u32> {
+    let widest = sizes.iter().map(|size| size.width).max().unwrap_or(0);
+    let tallest = sizes.iter().map(|size| size.height).max().unwrap_or(0);
+    let side = |length: u32| length.next_power_of_two().max(MIN_PAGE_SIZE).min(max);
+    let mut page = Size::new(side(widest), side(tallest));
+
+    loop {
+        let mut packer = Skyline::new(page.width, page.height);
+        if sizes.iter().all(|size| packer.allocate(size.width, size.height).is_some()) {
+            return page;
+        }
+
+        let can_widen = page.width * 2 <= max;
+        let can_heighten = page.height * 2 <= max;
+        if can_widen && (page.width <= page.height || !can_heighten) {
+            page.width *= 2;
+        } else if can_heighten {
+            page.height *= 2;
+        } else {
+            return page;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use image::{Rgba, RgbaImage};
+
+    use crate::atlas::{
+        edge_inset,
+        extrude,
+        json_char,
+        lay_out,
+        mip_limit,
+        page_size,
+        read_cache,
+        tex_coords,
+        write_cache,
+        Atlas,
+        Skyline,
+        TexCoords,
+        TileAlignment,
+        TileInfo,
+        TileSource,
+    };
+    use crate::texture::TextureFilter;
+    use crate::rectangle::Rectangle;
+    use crate::size::Size;
+
+    fn overlaps(a: Rectangle<u32>, b: Rectangle<u32>) -> bool {
+        a.x < b.x + b.width && b.x < a.x + a.width
+            && a.y < b.y + b.height && b.y < a.y + a.height
+    }
+
+    #[test]
+    fn test_skyline_packs_without_overlap() {
+        let mut packer = Skyline::new(64, 64);
+        let mut placed = Vec::new();
+        for i in 0..40 {
+            let (width, height) = (3 + i * 7 % 11, 2 + i * 5 % 9);
+            if let Some(space) = packer.allocate(width, height) {
+                placed.push(space);
+            }
+        }
+
+        assert!(placed.len() > 30);
+        for (i, a) in placed.iter().enumerate() {
+            assert!(a.x + a.width <= 64 && a.y + a.height <= 64);
+            for b in &placed[i + 1..] {
+                assert!(!overlaps(*a, *b), "{:?} overlaps {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_skyline_full() {
+        let mut packer = Skyline::new(16, 16);
+        for _ in 0..4 {
+            assert!(packer.allocate(8, 8).is_some());
+        }
+        assert_eq!(packer.allocate(1, 1), None);
+        assert_eq!(packer.occupancy(), 1.0);
+    }
+
+    #[test]
+    fn test_skyline_reuses_freed_space() {
+        let mut packer = Skyline::new(16, 16);
+        let spaces: Vec<_> = (0..4).map(|_| packer.allocate(8, 8).unwrap()).collect();
+
+        // Two neighbours freed separately take a tile as big as both
+        packer.deallocate(spaces[0]);
+        packer.deallocate(spaces[1]);
+        let space = packer.allocate(16, 8).unwrap();
+        assert_eq!(space, Rectangle::new(0, 0, 16, 8));
+        assert_eq!(packer.allocate(1, 1), None);
+    }
+
+    #[test]
+    fn test_skyline_grow_keeps_allocations() {
+        let mut packer = Skyline::new(16, 16);
+        let first = packer.allocate(16, 16).unwrap();
+        assert_eq!(packer.allocate(8, 8), None);
+
+        packer.grow(32, 16);
+        let second = packer.allocate(8, 8).unwrap();
+        assert_eq!(second.x, 16);
+        assert!(!overlaps(first, second));
+
+        packer.grow(32, 32);
+        assert!(packer.allocate(32, 16).is_some());
+    }
+
+    #[test]
+    fn test_page_size_grows_to_fit() {
+        let sizes = vec![Size::new(300, 40); 10];
+
+        assert_eq!(page_size(&sizes, 8192), Size::new(512, 512));
+        assert_eq!(page_size(&sizes, 256), Size::new(256, 256));
+    }
+
+    #[test]
+    fn test_rebuild_packs_tallest_first_onto_equal_pages() {
+        let mut tiles: Vec<TileInfo> = [(40, 10), (30, 120), (60, 50)]
+            .into_iter()
+            .map(|(width, height)| TileInfo::new(RgbaImage::new(width, height), 0, 0))
+            .collect();
+        let mut refs: Vec<&mut TileInfo> = tiles.iter_mut().collect();
+        let packers = lay_out(&mut refs, 8192);
+
+        assert_eq!(packers.len(), 1);
+        assert_eq!(packers[0].size, page_size(&[Size::new(30, 120)], 8192));
+        assert_eq!(tiles[1].space(), Rectangle::new(0, 0, 30, 120));
+        assert_eq!(tiles[2].space(), Rectangle::new(30, 0, 60, 50));
+        assert_eq!(tiles[0].space(), Rectangle::new(90, 0, 40, 10));
+
+        // What does not fit goes on more pages of the same size
+        let mut tiles = vec![TileInfo::new(RgbaImage::new(200, 200), 0, 0); 3];
+        let mut refs: Vec<&mut TileInfo> = tiles.iter_mut().collect();
+        let packers = lay_out(&mut refs, 256);
+        assert_eq!(packers.len(), 3);
+        assert!(packers.iter().all(|packer| packer.size == Size::new(256, 256)));
+        assert_eq!(tiles.iter().map(|tile| tile.page).collect::<Vec<_>>(), [0, 1, 2]);
+
+        // Tiles bigger than any page are left out
+        let mut tiles = [TileInfo::new(RgbaImage::new(300, 10), 0, 0)];
+        let mut refs: Vec<&mut TileInfo> = tiles.iter_mut().collect();
+        lay_out(&mut refs, 256);
+        assert!(!tiles[0].is_placed());
+    }
+
+    #[test]
+    fn test_eviction_frees_packer_space() {
+        let mut atlas = Atlas::new(TextureFilter::Nearest, 0, 0);
+        let tiles = ['a', 'b', 'c', 'd'].map(|code| (code, RgbaImage::new(128, 128)));
+        atlas.extend_added(tiles, TileSource::Added);
+        let mut refs: Vec<&mut TileInfo> = atlas.tiles.values_mut().collect();
+        let mut packers = lay_out(&mut refs, 256);
+        assert_eq!(packers[0].allocate(128, 128), None);
+
+        for code in ['a', 'b', 'd'] {
+            atlas.retain(code);
+        }
+        let evicted = atlas.evict_unused();
+        assert_eq!(evicted.len(), 1);
+        let (code, tile) = &evicted[0];
+        assert_eq!(*code, 'c');
+
+        // As the page does when the tile leaves it
+        packers[tile.page].deallocate(tile.space());
+        assert_eq!(packers[0].allocate(128, 128), Some(tile.space()));
+    }
+
+    #[test]
+    fn test_only_unreferenced_added_tiles_are_evicted() {
+        let mut atlas = Atlas::new(TextureFilter::Nearest, 1, 0);
+        atlas.extend_tiles([('a', RgbaImage::new(2, 2))]);
+        let added = ['b', 'c'].map(|code| (code, RgbaImage::new(2, 2)));
+        atlas.extend_added(added, TileSource::Added);
+
+        atlas.retain('b');
+        atlas.retain('b');
+        atlas.release('b');
+        assert_eq!(atlas.references('b'), 1);
+        assert_eq!(atlas.unused_tiles(), vec!['c']);
+
+        let evicted: Vec<char> = atlas.evict_unused().into_iter().map(|(code, _)| code).collect();
+        assert_eq!(evicted, ['c']);
+        assert!(atlas.contains('a'));
+        assert!(atlas.contains('b'));
+        assert!(!atlas.contains('c'));
+
+        // Once released, it goes on the next clean up
+        atlas.release('b');
+        assert_eq!(atlas.unused_tiles(), vec!['b']);
+        assert_eq!(atlas.evict_unused().len(), 1);
+        assert!(!atlas.contains('b'));
+    }
+
+    #[test]
+    fn test_defragment_remaps_coordinates() {
+        let mut tiles: Vec<TileInfo> = [(200, 200), (200, 200), (50, 50)]
+            .into_iter()
+            .map(|(width, height)| TileInfo::new(RgbaImage::new(width, height), 0, 0))
+            .collect();
+        let mut refs: Vec<&mut TileInfo> = tiles.iter_mut().collect();
+        let packers = lay_out(&mut refs, 512);
+        let before = tex_coords(&tiles[2], packers[0].size, TextureFilter::Linear);
+        assert_eq!(packers[0].size, Size::new(512, 256));
+        assert_eq!(tiles[2].space(), Rectangle::new(400, 0, 50, 50));
+
+        // The middle tile is evicted and the survivors repacked
+        tiles.remove(1);
+        let mut refs: Vec<&mut TileInfo> = tiles.iter_mut().collect();
+        let packers = lay_out(&mut refs, 512);
+        let after = tex_coords(&tiles[1], packers[0].size, TextureFilter::Linear);
+        assert_eq!(packers[0].size, Size::new(256, 256));
+        assert_eq!(tiles[1].space(), Rectangle::new(200, 0, 50, 50));
+
+        assert_ne!(before, after);
+        let expected = TexCoords::from_rect(200, 0, 50, 50, Size::new(256, 256));
+        assert_eq!(after, expected.inset(0.5 / 256.0, 0.5 / 256.0));
+    }
+
+    #[test]
+    fn test_alignment_positions_tile_in_cell() {
+        let tile = Size::new(16.0, 16.0);
+        let cell = Size::new(9.0, 16.0);
+
+        assert_eq!(TileAlignment::TopLeft.position(tile, cell), [0.0, 0.0]);
+        assert_eq!(TileAlignment::TopRight.position(tile, cell), [-7.0, 0.0]);
+        assert_eq!(TileAlignment::BottomRight.position(tile, cell), [-7.0, 0.0]);
+        assert_eq!(TileAlignment::Center.position(tile, cell), [-4.0, 0.0]);
+        assert_eq!(TileAlignment::DeadCenter.position(tile, cell), [-3.5, 0.0]);
+    }
+
+    #[test]
+    fn test_extrude_repeats_edges() {
+        let mut image = RgbaImage::new(2, 1);
+        image.put_pixel(0, 0, Rgba([255, 0, 0, 255]));
+        image.put_pixel(1, 0, Rgba([0, 0, 255, 255]));
+
+        let extruded = extrude(&image, 2);
+        assert_eq!(extruded.dimensions(), (6, 5));
+        assert_eq!(extruded.get_pixel(0, 0), image.get_pixel(0, 0));
+        assert_eq!(extruded.get_pixel(2, 2), image.get_pixel(0, 0));
+        assert_eq!(extruded.get_pixel(5, 4), image.get_pixel(1, 0));
+    }
+
+    #[test]
+    fn test_tiles_reserve_padding_and_extrusion() {
+        let tile = TileInfo::new(RgbaImage::new(8, 16), 1, 2);
+        assert_eq!(tile.total_space().size(), Size::new(13, 21));
+
+        // Linear filtering needs half a texel unless edges are extruded
+        assert_eq!(edge_inset(TextureFilter::Linear, 0), 0.5);
+        assert_eq!(edge_inset(TextureFilter::Linear, 1), 0.0);
+        assert!(edge_inset(TextureFilter::Nearest, 1) > 0.0);
+    }
+
+    #[test]
+    fn test_mip_limit_stays_within_extrusion() {
+        let limits: Vec<u32> = [0, 1, 2, 3, 5, 6, 11, 12].into_iter().map(mip_limit).collect();
+        assert_eq!(limits, vec![0, 0, 0, 1, 1, 2, 2, 3]);
+
+        // Level n reads 1.5 texels of 2^n pixels past the edge
+        for extrusion in 1..64 {
+            let level = mip_limit(extrusion);
+            assert!(3 << level <= extrusion * 2 || level == 0);
+            assert!(3 << (level + 1) > extrusion * 2);
+        }
+    }
+
+    #[test]
+    fn test_sheet_is_cut_into_tiles() {
+        let atlas = Atlas::new(TextureFilter::Nearest, 1, 0);
+        let sheet = atlas.slice_sheet(&RgbaImage::new(128, 256));
+
+        assert_eq!(sheet.len(), 256);
+        assert_eq!(sheet[17].pixels().dimensions(), (8, 16));
+    }
+
+    #[test]
+    fn test_manifest_lists_placed_tiles() {
+        let mut atlas = Atlas::new(TextureFilter::Nearest, 1, 0);
+        let mut tile = atlas.new_tile(RgbaImage::new(8, 8), TileSource::Tileset);
+        tile.placed = true;
+        tile.useful_space = Rectangle::new(16, 0, 8, 8);
+        atlas.tiles.insert('"', tile);
+        atlas.tiles.insert('x', TileInfo::new(RgbaImage::new(8, 8), 1, 0));
+        let mut glyph = atlas.new_tile(RgbaImage::new(8, 8), TileSource::Sheet);
+        glyph.placed = true;
+        atlas.sheet = vec![glyph.clone(); 200];
+
+        let manifest = atlas.manifest();
+        assert!(manifest.contains(r#""code": 34, "char": "\"", "source": "tileset""#));
+        assert!(manifest.contains(r#"{"index": 199, "source": "sheet""#));
+        assert!(!manifest.contains(r#""char": "\u00c7""#));
+        assert!(manifest.contains(r#""rect": [16, 0, 8, 8]"#));
+        assert!(!manifest.contains(r#""char": "x""#));
+    }
+
+    #[test]
+    fn test_cache_round_trip() {
+        let atlas = Atlas::new(TextureFilter::Nearest, 1, 0);
+        let mut sheet = atlas.slice_sheet(&RgbaImage::new(16, 16));
+        let mut packer = Skyline::new(4, 4);
+        let mut pixels = RgbaImage::new(4, 4);
+        pixels.put_pixel(2, 1, Rgba([9, 8, 7, 6]));
+
+        packer.allocate(2, 2).unwrap();
+        sheet[65].placed = true;
+        sheet[65].useful_space = Rectangle::new(2, 0, 1, 2);
+        let mut tiles = BTreeMap::new();
+        tiles.insert('@', TileInfo::new(RgbaImage::new(3, 3), 1, 0));
+        let mut generated = BTreeMap::new();
+        generated.insert('│', TileInfo::new(RgbaImage::new(2, 2), 1, 0));
+
+        let maps = [&tiles, &generated];
+        let bytes = write_cache(42, 1, 0, [(&pixels, &packer)].into_iter(), &sheet, maps);
+        let cached = read_cache(&bytes, 42, 1, 0).unwrap().unwrap();
+
+        assert_eq!(cached.pages.len(), 1);
+        assert_eq!(cached.pages[0].0, pixels);
+        assert_eq!(cached.pages[0].1.used, packer.used);
+        assert_eq!(*cached.sheet[65].pixels().get_pixel(0, 1), Rgba([9, 8, 7, 6]));
+        assert_eq!(cached.tiles[&'@'].pixels().dimensions(), (3, 3));
+        assert!(!cached.tiles[&'@'].is_placed());
+        assert_eq!(cached.generated[&'│'].pixels().dimensions(), (2, 2));
+    }
+
+    #[test]
+    fn test_cache_misses_on_other_key_and_rejects_truncation() {
+        let sheet = vec![TileInfo::new(RgbaImage::new(1, 1), 1, 0); 256];
+        let empty = BTreeMap::new();
+        let bytes = write_cache(42, 1, 0, std::iter::empty(), &sheet, [&empty, &empty]);
+
+        assert!(read_cache(&bytes, 43, 1, 0).unwrap().is_none());
+        assert!(read_cache(&bytes, 42, 2, 0).unwrap().is_none());
+        assert!(read_cache(&bytes[..bytes.len() - 1], 42, 1, 0).is_err());
+    }
+
+    #[test]
+    fn test_cache_rejects_overflowing_tiles_and_resets_unplaced_pages() {
+        let packer = Skyline::new(4, 4);
+        let pixels = RgbaImage::new(4, 4);
+        let mut sheet = vec![TileInfo::new(RgbaImage::new(1, 1), 1, 0); 256];
+        sheet[1].page = 7;
+        let empty = BTreeMap::new();
+        let maps = [&empty, &empty];
+
+        let bytes = write_cache(42, 1, 0, [(&pixels, &packer)].into_iter(), &sheet, maps);
+        let cached = read_cache(&bytes, 42, 1, 0).unwrap().unwrap();
+        assert_eq!(cached.sheet[1].page, 0);
+
+        sheet[1].placed = true;
+        sheet[1].page = 0;
+        sheet[1].useful_space = Rectangle::new(2, 0, i32::MAX, 1);
+        let bytes = write_cache(42, 1, 0, [(&pixels, &packer)].into_iter(), &sheet, maps);
+        assert!(read_cache(&bytes, 42, 1, 0).is_err());
+    }
+
+    #[test]
+    fn test_source_key_follows_every_source() {
+        let key = |tile: u8, glyph: u8| {
+            let mut atlas = Atlas::new(TextureFilter::Nearest, 1, 0);
+            atlas.extend_tiles([('@', RgbaImage::from_pixel(1, 1, Rgba([tile; 4])))]);
+            atlas.extend_generated([('│', RgbaImage::from_pixel(1, 1, Rgba([glyph; 4])))]);
+            atlas.source_key()
+        };
+
+        assert_eq!(key(1, 2), key(1, 2));
+        assert_ne!(key(1, 2), key(3, 2));
+        assert_ne!(key(1, 2), key(1, 3));
+    }
+
+    #[test]
+    fn test_generated_glyphs_fall_back_behind_tileset_tiles() {
+        // Stand in for placement, which needs a device
+        fn place_all(atlas: &mut Atlas) {
+            for tile in atlas.tiles.values_mut() {
+                tile.placed = true;
+                tile.page = 1;
+            }
+            for tile in atlas.generated.values_mut() {
+                tile.placed = true;
+                tile.page = 2;
+            }
+        }
+
+        let glyphs = |size| [('│', RgbaImage::new(size, size)), ('─', RgbaImage::new(size, size))];
+        let mut atlas = Atlas::new(TextureFilter::Nearest, 1, 0);
+        atlas.extend_generated(glyphs(8));
+        atlas.extend_tiles([('│', RgbaImage::new(8, 8))]);
+        place_all(&mut atlas);
+        assert_eq!(atlas.locate('│').page, 1);
+        assert_eq!(atlas.locate('─').page, 2);
+
+        // A new cell size redraws the glyphs but keeps the tileset's own
+        atlas.extend_generated(glyphs(12));
+        place_all(&mut atlas);
+        assert!(atlas.contains('│'));
+        assert_eq!(atlas.locate('│').page, 1);
+        assert_eq!(atlas.locate('│').size, Size::new(8, 8));
+        assert_eq!(atlas.locate('─').size, Size::new(12, 12));
     }
 
-    pub fn apply_texture_filter() {
-        todo!()
+    #[test]
+    fn test_json_char_escapes() {
+        assert_eq!(json_char('a'), "a");
+        assert_eq!(json_char('\\'), r"\\");
+        assert_eq!(json_char('\0'), r"\u0000");
+        assert_eq!(json_char('☺'), "☺");
     }
 }
@@ -0,0 +1,165 @@
+use std::hash::Hasher;
+use std::io;
+
+
+const FNV_OFFSET: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01B3;
+
+
+/// Hashes the sources an atlas is built from into the key its cache is
+/// stored under. FNV-1a rather than the standard hasher, which may change
+/// between Rust releases and throw every cache away.
+#[derive(Debug, Copy, Clone)]
+pub struct CacheKey(u64);
+
+impl CacheKey {
+    pub fn new() -> Self {
+        Self(FNV_OFFSET)
+    }
+}
+
+impl Default for CacheKey {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hasher for CacheKey {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+    }
+}
+
+
+/// Little-endian encoder for cache files.
+#[derive(Debug, Default)]
+pub(crate) struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, value: i32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+
+/// Decoder for what [`Writer`] wrote. Running out of bytes is an
+/// `InvalidData` error, as for any other corrupt cache.
+#[derive(Debug)]
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    pub fn bytes(&mut self, count: usize) -> io::Result<&'a [u8]> {
+        if count > self.bytes.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "cache file is truncated"));
+        }
+        let (head, tail) = self.bytes.split_at(count);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> io::Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+}
+
+
+/// An `InvalidData` error for a cache that decodes but makes no sense.
+pub(crate) fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::hash::{Hash, Hasher};
+
+    use crate::cache::{CacheKey, Reader, Writer};
+
+    #[test]
+    fn test_key_is_stable() {
+        // FNV-1a of "a", from the reference implementation
+        let mut key = CacheKey::new();
+        key.write(b"a");
+        assert_eq!(key.finish(), 0xAF63_DC4C_8601_EC8C);
+
+        let mut other = CacheKey::new();
+        "a".hash(&mut other);
+        assert_ne!(other.finish(), CacheKey::new().finish());
+    }
+
+    #[test]
+    fn test_round_trip_and_truncation() {
+        let mut writer = Writer::new();
+        writer.u32(7);
+        writer.i32(-3);
+        writer.bool(true);
+        writer.u64(u64::MAX);
+        let bytes = writer.finish();
+
+        let mut reader = Reader::new(&bytes);
+        assert_eq!(reader.u32().unwrap(), 7);
+        assert_eq!(reader.i32().unwrap(), -3);
+        assert!(reader.bool().unwrap());
+        assert_eq!(reader.u64().unwrap(), u64::MAX);
+        assert!(reader.u8().is_err());
+    }
+}
@@ -0,0 +1,91 @@
+use std::time::Instant;
+
+
+/// The time, in seconds, fed to shaders through the globals uniform. It can be
+/// paused and set explicitly so animated output is reproducible.
+#[derive(Debug, Clone)]
+pub struct Clock {
+    start: Instant,
+    offset: f32,
+    paused_at: Option<f32>,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            offset: 0.0,
+            paused_at: None,
+        }
+    }
+
+    pub fn time(&self) -> f32 {
+        self.paused_at
+            .unwrap_or_else(|| self.offset + self.start.elapsed().as_secs_f32())
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused_at.is_some()
+    }
+
+    pub fn pause(&mut self) {
+        if self.paused_at.is_none() {
+            self.paused_at = Some(self.time());
+        }
+    }
+
+    pub fn resume(&mut self) {
+        if let Some(time) = self.paused_at.take() {
+            self.restart_at(time);
+        }
+    }
+
+    /// Jump to `time` seconds, staying paused if the clock was paused.
+    pub fn set_time(&mut self, time: f32) {
+        match self.paused_at {
+            Some(_) => self.paused_at = Some(time),
+            None => self.restart_at(time),
+        }
+    }
+
+    fn restart_at(&mut self, time: f32) {
+        self.start = Instant::now();
+        self.offset = time;
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::Clock;
+
+    #[test]
+    fn test_paused_time_is_fixed() {
+        let mut clock = Clock::new();
+        clock.pause();
+        clock.set_time(2.5);
+
+        assert!(clock.is_paused());
+        assert_eq!(clock.time(), 2.5);
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert_eq!(clock.time(), 2.5);
+    }
+
+    #[test]
+    fn test_resume_continues_from_pause() {
+        let mut clock = Clock::new();
+        clock.pause();
+        clock.set_time(10.0);
+        clock.resume();
+
+        assert!(!clock.is_paused());
+        assert!(clock.time() >= 10.0);
+        assert!(clock.time() < 11.0);
+    }
+}
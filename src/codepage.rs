@@ -0,0 +1,279 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+
+// The glyphs DOS showed for control codes 0x00-0x1F, shared by the OEM
+// codepages below. NUL stays NUL, which tilesets leave blank.
+const CONTROL_GLYPHS: &str = "\0☺☻♥♦♣♠•◘○◙♂♀♪♫☼►◄↕‼¶§▬↨↑↓→←∟↔▲▼";
+
+// 0x80-0xFF of each codepage
+const CP437_HIGH: &str = concat!(
+    "ÇüéâäàåçêëèïîìÄÅ",
+    "ÉæÆôöòûùÿÖÜ¢£¥₧ƒ",
+    "áíóúñÑªº¿⌐¬½¼¡«»",
+    "░▒▓│┤╡╢╖╕╣║╗╝╜╛┐",
+    "└┴┬├─┼╞╟╚╔╩╦╠═╬╧",
+    "╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀",
+    "αßΓπΣσµτΦΘΩδ∞φε∩",
+    "≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{A0}",
+);
+
+const CP850_HIGH: &str = concat!(
+    "ÇüéâäàåçêëèïîìÄÅ",
+    "ÉæÆôöòûùÿÖÜø£Ø×ƒ",
+    "áíóúñÑªº¿®¬½¼¡«»",
+    "░▒▓│┤ÁÂÀ©╣║╗╝¢¥┐",
+    "└┴┬├─┼ãÃ╚╔╩╦╠═╬¤",
+    "ðÐÊËÈıÍÎÏ┘┌█▄¦Ì▀",
+    "ÓßÔÒõÕµþÞÚÛÙýÝ¯´",
+    "\u{AD}±‗¾¶§÷¸°¨·¹³²■\u{A0}",
+);
+
+const CP866_HIGH: &str = concat!(
+    "АБВГДЕЖЗИЙКЛМНОП",
+    "РСТУФХЦЧШЩЪЫЬЭЮЯ",
+    "абвгдежзийклмноп",
+    "░▒▓│┤╡╢╖╕╣║╗╝╜╛┐",
+    "└┴┬├─┼╞╟╚╔╩╦╠═╬╧",
+    "╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀",
+    "рстуфхцчшщъыьэюя",
+    "ЁёЄєЇїЎў°∙·√№¤■\u{A0}",
+);
+
+
+/// Which code each tile of a tileset image stands for, counting tiles left
+/// to right and top to bottom.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Codepage {
+    /// Tile `n` is code `first + n`.
+    Sequential(char),
+    /// Tile `n` is the `n`th code. Tiles past the end are skipped.
+    Table(Vec<char>),
+}
+
+impl Codepage {
+    /// The original IBM PC codepage most ASCII tilesheets are laid out in,
+    /// with its glyphs for the control codes.
+    pub fn cp437() -> Self {
+        oem(CP437_HIGH)
+    }
+
+    /// DOS Western Europe: CP437 with most symbols traded for accented
+    /// letters.
+    pub fn cp850() -> Self {
+        oem(CP850_HIGH)
+    }
+
+    /// DOS Cyrillic.
+    pub fn cp866() -> Self {
+        oem(CP866_HIGH)
+    }
+
+    /// ISO 8859-1, where every tile is the code point of its index.
+    pub fn latin1() -> Self {
+        Codepage::Sequential('\0')
+    }
+
+    /// A built-in codepage by name: `437`, `cp437`, `850`, `866` or
+    /// `latin1`, in any case.
+    pub fn builtin(name: &str) -> Option<Self> {
+        let name = name.trim().to_ascii_lowercase();
+        match name.strip_prefix("cp").unwrap_or(&name) {
+            "437" => Some(Self::cp437()),
+            "850" => Some(Self::cp850()),
+            "866" => Some(Self::cp866()),
+            "latin1" | "iso-8859-1" | "iso8859-1" => Some(Self::latin1()),
+            _ => None,
+        }
+    }
+
+    /// Read a mapping file: code points and inclusive ranges of them in
+    /// tile order, such as `U+0020-U+007E` or `0x2500`, separated by spaces,
+    /// commas or new lines. `#` starts a comment.
+    pub fn parse(source: &str) -> Result<Self, CodepageError> {
+        let mut codes = Vec::new();
+
+        for (number, line) in source.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let entries = line
+                .split(|c: char| c.is_whitespace() || c == ',')
+                .filter(|entry| !entry.is_empty());
+
+            for entry in entries {
+                let error = |message: String| CodepageError::Parse(number + 1, message);
+                let (start, end) = match entry.split_once("..").or_else(|| split_range(entry)) {
+                    Some((start, end)) => (code_point(start), code_point(end)),
+                    None => (code_point(entry), code_point(entry)),
+                };
+                let (Some(start), Some(end)) = (start, end) else {
+                    return Err(error(format!("`{}` is not a code point or range", entry)));
+                };
+                if start > end {
+                    return Err(error(format!("range `{}` runs backwards", entry)));
+                }
+
+                // Surrogates are no characters; skip them inside ranges
+                codes.extend((start..=end).filter_map(char::from_u32));
+            }
+        }
+
+        if codes.is_empty() {
+            return Err(CodepageError::Parse(0, "no code points".into()));
+        }
+        Ok(Codepage::Table(codes))
+    }
+
+    /// Read and parse the mapping file at `path`, like [`Codepage::parse`].
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, CodepageError> {
+        let path = path.as_ref();
+        let source = std::fs::read_to_string(path)
+            .map_err(|error| CodepageError::Io(path.into(), error))?;
+        Self::parse(&source)
+    }
+
+    /// The code of tile `index`, if it has one.
+    pub fn code(&self, index: usize) -> Option<char> {
+        match self {
+            Codepage::Sequential(first) => char::from_u32(*first as u32 + index as u32),
+            Codepage::Table(codes) => codes.get(index).copied(),
+        }
+    }
+
+    /// The tile that stands for `code`, if any.
+    pub fn index_of(&self, code: char) -> Option<usize> {
+        match self {
+            Codepage::Sequential(first) => (code as u32).checked_sub(*first as u32).map(|i| i as usize),
+            Codepage::Table(codes) => codes.iter().position(|c| *c == code),
+        }
+    }
+
+    /// The code of the first tile.
+    pub fn first(&self) -> char {
+        self.code(0).unwrap_or('\0')
+    }
+}
+
+
+/// Why a codepage mapping file could not be used.
+#[derive(Debug)]
+pub enum CodepageError {
+    /// The file could not be read.
+    Io(PathBuf, io::Error),
+    /// Line `n` is not valid; 0 for the file as a whole.
+    Parse(usize, String),
+}
+
+impl fmt::Display for CodepageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodepageError::Io(path, error) => {
+                write!(f, "Could not read codepage {}: {}", path.display(), error)
+            }
+            CodepageError::Parse(0, message) => {
+                write!(f, "Invalid codepage: {}", message)
+            }
+            CodepageError::Parse(line, message) => {
+                write!(f, "Invalid codepage at line {}: {}", line, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CodepageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CodepageError::Io(_, error) => Some(error),
+            CodepageError::Parse(..) => None,
+        }
+    }
+}
+
+
+/// An OEM codepage: the control glyphs, ASCII, the house glyph at 0x7F,
+/// then `high` for 0x80-0xFF.
+fn oem(high: &str) -> Codepage {
+    let codes = CONTROL_GLYPHS
+        .chars()
+        .chain(' '..='~')
+        .chain(['⌂'])
+        .chain(high.chars())
+        .collect();
+    Codepage::Table(codes)
+}
+
+/// Split `U+0020-U+007E` at the dash. Only the dash between two code
+/// points counts, not one in a lone `U+...`.
+fn split_range(entry: &str) -> Option<(&str, &str)> {
+    let (start, end) = entry.split_once('-')?;
+    (!start.is_empty() && !end.is_empty()).then_some((start, end))
+}
+
+/// `U+263A`, `0x263A` or `9786`.
+fn code_point(text: &str) -> Option<u32> {
+    let text = text.trim();
+    let hex = text
+        .strip_prefix("U+")
+        .or_else(|| text.strip_prefix("u+"))
+        .or_else(|| text.strip_prefix("0x"))
+        .or_else(|| text.strip_prefix("0X"));
+    match hex {
+        Some(digits) => u32::from_str_radix(digits, 16).ok(),
+        None => text.parse().ok(),
+    }
+    .filter(|value| *value <= char::MAX as u32)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::codepage::Codepage;
+
+    #[test]
+    fn test_builtin_tables_are_complete() {
+        for name in ["437", "CP850", "cp866"] {
+            let Codepage::Table(codes) = Codepage::builtin(name).unwrap() else {
+                panic!("{} is not a table", name);
+            };
+            assert_eq!(codes.len(), 256, "{}", name);
+        }
+        assert!(Codepage::builtin("1252").is_none());
+    }
+
+    #[test]
+    fn test_cp437_glyphs() {
+        let cp437 = Codepage::cp437();
+        assert_eq!(cp437.code(0x01), Some('☺'));
+        assert_eq!(cp437.code(0x41), Some('A'));
+        assert_eq!(cp437.code(0x7F), Some('⌂'));
+        assert_eq!(cp437.code(0xB3), Some('│'));
+        assert_eq!(cp437.code(0xDB), Some('█'));
+        assert_eq!(cp437.code(0xFF), Some('\u{A0}'));
+        assert_eq!(cp437.index_of('♥'), Some(0x03));
+
+        assert_eq!(Codepage::cp866().code(0x80), Some('А'));
+        assert_eq!(Codepage::cp850().code(0x9B), Some('ø'));
+    }
+
+    #[test]
+    fn test_parse_ranges_in_tile_order() {
+        let codepage = Codepage::parse("
+            # Digits, then the light box lines
+            0x30-0x39, U+2500..U+2502
+            9786
+        ").unwrap();
+
+        assert_eq!(codepage.code(0), Some('0'));
+        assert_eq!(codepage.code(10), Some('─'));
+        assert_eq!(codepage.code(13), Some('☺'));
+        assert_eq!(codepage.code(14), None);
+    }
+
+    #[test]
+    fn test_parse_errors_name_the_line() {
+        let error = Codepage::parse("0x20\nU+0041-U+0030").unwrap_err();
+        assert_eq!(error.to_string(), "Invalid codepage at line 2: range `U+0041-U+0030` runs backwards");
+        assert!(Codepage::parse("nonsense").is_err());
+        assert!(Codepage::parse("# nothing").is_err());
+    }
+}
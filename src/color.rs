@@ -0,0 +1,66 @@
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8,
+}
+
+impl Color {
+    pub const WHITE: Color = Color::new(255, 255, 255, 255);
+    pub const BLACK: Color = Color::new(0, 0, 0, 255);
+    pub const TRANSPARENT: Color = Color::new(0, 0, 0, 0);
+
+    pub const fn new(r: u8, g: u8, b: u8, a: u8) -> Self {
+        Self { r, g, b, a }
+    }
+
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b, a: 255 }
+    }
+
+    /// Convert to linear RGBA floats for the GPU. The surface is sRGB, so
+    /// the color channels are linearized; alpha is passed through as is.
+    pub fn to_linear(self) -> [f32; 4] {
+        fn linearize(channel: u8) -> f32 {
+            let c = channel as f32 / 255.0;
+            if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            }
+        }
+
+        [
+            linearize(self.r),
+            linearize(self.g),
+            linearize(self.b),
+            self.a as f32 / 255.0,
+        ]
+    }
+}
+
+impl Default for Color {
+    fn default() -> Self {
+        Self::WHITE
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::color::Color;
+
+    #[test]
+    fn test_to_linear_bounds() {
+        assert_eq!(Color::WHITE.to_linear(), [1.0, 1.0, 1.0, 1.0]);
+        assert_eq!(Color::TRANSPARENT.to_linear(), [0.0, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn test_to_linear_midtone() {
+        let [r, _, _, a] = Color::new(128, 0, 0, 128).to_linear();
+        assert!((r - 0.2158).abs() < 0.001);
+        assert!((a - 0.502).abs() < 0.001);
+    }
+}
@@ -0,0 +1,182 @@
+use std::path::PathBuf;
+
+use crate::error::Error;
+use crate::size::Size;
+use crate::texture::TextureFilter;
+
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VSync {
+    /// Wait for vertical blank; never tears.
+    On,
+    /// Present immediately, tearing if need be.
+    Off,
+    /// Wait for vertical blank unless the frame is late, then tear.
+    Adaptive,
+}
+
+impl VSync {
+    /// Present modes that honor this setting, most preferred first. `Fifo` is
+    /// the last resort since every surface supports it.
+    fn preferred_modes(self) -> &'static [wgpu::PresentMode] {
+        use wgpu::PresentMode::*;
+
+        match self {
+            VSync::On => &[Fifo],
+            VSync::Off => &[Immediate, Mailbox, Fifo],
+            VSync::Adaptive => &[FifoRelaxed, Fifo],
+        }
+    }
+
+    /// Pick the first supported present mode for this setting, falling back
+    /// to whatever the surface offers first.
+    pub fn choose_present_mode(
+        self,
+        supported: &[wgpu::PresentMode],
+    ) -> wgpu::PresentMode {
+        self.preferred_modes()
+            .iter()
+            .copied()
+            .find(|mode| supported.contains(mode))
+            .unwrap_or(supported[0])
+    }
+}
+
+
+/// Which graphics API to draw with.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Let wgpu choose, falling back to a software adapter if there is no
+    /// hardware one.
+    #[default]
+    Auto,
+    Vulkan,
+    Gl,
+    /// Always use the software adapter, for machines without a GPU.
+    Software,
+}
+
+impl Backend {
+    pub fn backends(self) -> wgpu::Backends {
+        match self {
+            Backend::Auto | Backend::Software => wgpu::Backends::all(),
+            Backend::Vulkan => wgpu::Backends::VULKAN,
+            Backend::Gl => wgpu::Backends::GL,
+        }
+    }
+
+    /// The values of `force_fallback_adapter` to request adapters with, in
+    /// order, until one is found.
+    pub fn fallback_attempts(self) -> &'static [bool] {
+        match self {
+            Backend::Software => &[true],
+            _ => &[false, true],
+        }
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub vsync: VSync,
+    pub backend: Backend,
+    /// Upper bound on frames per second while something is animating.
+    /// `None` renders as fast as the present mode allows.
+    pub fps_cap: Option<u32>,
+    pub cell_size: Size<i32>,
+    pub texture_filter: TextureFilter,
+    /// Empty pixels kept between tiles in the atlas.
+    pub tile_padding: u32,
+    /// Pixels each tile's edges are repeated outward in the atlas, so
+    /// linear filtering at an edge blends with the tile itself. Mipmapped
+    /// filtering only uses the mip levels this covers.
+    pub tile_extrusion: u32,
+    /// File the packed atlas is kept in between runs by
+    /// [`crate::Terminal::restore_atlas`], so startup can skip drawing and
+    /// packing tiles when its sources did not change. `None` builds the
+    /// atlas every time.
+    pub atlas_cache: Option<PathBuf>,
+}
+
+impl Config {
+    /// Reject settings the renderer cannot work with.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.cell_size.width <= 0 || self.cell_size.height <= 0 {
+            return Err(Error::Config(format!(
+                "cell size must be positive, got {}x{}",
+                self.cell_size.width, self.cell_size.height,
+            )));
+        }
+
+        if self.fps_cap == Some(0) {
+            return Err(Error::Config("fps cap must be at least 1".into()));
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            vsync: VSync::On,
+            backend: Backend::Auto,
+            fps_cap: None,
+            cell_size: Size { width: 16, height: 16 },
+            texture_filter: TextureFilter::Nearest,
+            tile_padding: 1,
+            tile_extrusion: 1,
+            atlas_cache: None,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Backend, Config, VSync};
+    use crate::size::Size;
+    use wgpu::PresentMode::*;
+
+    #[test]
+    fn test_vsync_off_prefers_immediate() {
+        let supported = [Fifo, Mailbox, Immediate];
+        assert_eq!(VSync::Off.choose_present_mode(&supported), Immediate);
+    }
+
+    #[test]
+    fn test_vsync_off_falls_back_to_mailbox() {
+        let supported = [Fifo, Mailbox];
+        assert_eq!(VSync::Off.choose_present_mode(&supported), Mailbox);
+    }
+
+    #[test]
+    fn test_adaptive_falls_back_to_fifo() {
+        let supported = [Mailbox, Fifo];
+        assert_eq!(VSync::Adaptive.choose_present_mode(&supported), Fifo);
+    }
+
+    #[test]
+    fn test_unsupported_falls_back_to_first() {
+        let supported = [Mailbox];
+        assert_eq!(VSync::On.choose_present_mode(&supported), Mailbox);
+    }
+
+    #[test]
+    fn test_software_backend_skips_hardware() {
+        assert_eq!(Backend::Software.fallback_attempts(), &[true]);
+        assert_eq!(Backend::Gl.fallback_attempts(), &[false, true]);
+        assert_eq!(Backend::Vulkan.backends(), wgpu::Backends::VULKAN);
+    }
+
+    #[test]
+    fn test_validate_rejects_bad_values() {
+        assert!(Config::default().validate().is_ok());
+
+        let config = Config { cell_size: Size::new(0, 16), ..Config::default() };
+        assert!(config.validate().is_err());
+
+        let config = Config { fps_cap: Some(0), ..Config::default() };
+        assert!(config.validate().is_err());
+    }
+}
@@ -0,0 +1,122 @@
+use std::time::Duration;
+
+use crate::color::Color;
+use crate::point::Point;
+use crate::size::Size;
+
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum CursorShape {
+    /// Covers the whole cell.
+    #[default]
+    Block,
+    /// A line along the bottom of the cell.
+    Underline,
+    /// A line along the left edge of the cell.
+    Bar,
+}
+
+
+/// The text cursor, drawn over every layer without touching the cells below.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Cursor {
+    /// The cell the cursor is in.
+    pub position: Point<i32>,
+    pub visible: bool,
+    pub shape: CursorShape,
+    /// How long one on/off cycle takes. Zero keeps the cursor steady.
+    pub blink_period: Duration,
+    pub color: Color,
+}
+
+impl Cursor {
+    /// The cursor's top-left corner and size in pixels.
+    pub fn rect(&self, cell_size: Size<i32>) -> ([f32; 2], [f32; 2]) {
+        let (width, height) = (cell_size.width as f32, cell_size.height as f32);
+        let x = (self.position.x * cell_size.width) as f32;
+        let y = (self.position.y * cell_size.height) as f32;
+
+        // Lines are an eighth of the cell, but never thinner than a pixel
+        let thickness = |length: f32| (length / 8.0).round().max(1.0);
+
+        match self.shape {
+            CursorShape::Block => ([x, y], [width, height]),
+            CursorShape::Underline => {
+                let line = thickness(height);
+                ([x, y + height - line], [width, line])
+            }
+            CursorShape::Bar => ([x, y], [thickness(width), height]),
+        }
+    }
+
+    pub fn is_blinking(&self) -> bool {
+        self.visible && !self.blink_period.is_zero()
+    }
+
+    /// How long after `time` the blinking cursor next turns on or off, if
+    /// the blink started at `epoch`. Both are clock times in seconds.
+    pub fn time_to_toggle(&self, time: f32, epoch: f32) -> Option<Duration> {
+        if !self.is_blinking() {
+            return None;
+        }
+
+        let half = self.blink_period.as_secs_f32() / 2.0;
+        let elapsed = (time - epoch).max(0.0);
+        let next = ((elapsed / half).floor() + 1.0) * half;
+        Some(Duration::from_secs_f32(next - elapsed))
+    }
+}
+
+impl Default for Cursor {
+    fn default() -> Self {
+        Self {
+            position: Point::new(0, 0),
+            visible: false,
+            shape: CursorShape::Block,
+            blink_period: Duration::from_millis(1000),
+            color: Color::WHITE,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::cursor::{Cursor, CursorShape};
+    use crate::point::Point;
+    use crate::size::Size;
+
+    #[test]
+    fn test_rect_follows_shape() {
+        let mut cursor = Cursor {
+            position: Point::new(2, 1),
+            ..Cursor::default()
+        };
+        let cell_size = Size::new(8, 16);
+
+        assert_eq!(cursor.rect(cell_size), ([16.0, 16.0], [8.0, 16.0]));
+
+        cursor.shape = CursorShape::Underline;
+        assert_eq!(cursor.rect(cell_size), ([16.0, 30.0], [8.0, 2.0]));
+
+        cursor.shape = CursorShape::Bar;
+        assert_eq!(cursor.rect(cell_size), ([16.0, 16.0], [1.0, 16.0]));
+    }
+
+    #[test]
+    fn test_time_to_toggle() {
+        let mut cursor = Cursor {
+            visible: true,
+            blink_period: Duration::from_secs(1),
+            ..Cursor::default()
+        };
+
+        let wait = cursor.time_to_toggle(10.2, 10.0).unwrap();
+        assert!((wait.as_secs_f32() - 0.3).abs() < 1e-4);
+
+        cursor.blink_period = Duration::ZERO;
+        assert_eq!(cursor.time_to_toggle(10.2, 10.0), None);
+    }
+}
@@ -0,0 +1,154 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use crate::codepage::CodepageError;
+use crate::font::FontError;
+use crate::size::Size;
+use crate::texture::TextureError;
+
+
+/// Everything that can go wrong while setting up or running the terminal.
+#[derive(Debug)]
+pub enum Error {
+    /// The window could not be created.
+    Window(winit::error::OsError),
+    /// No surface could be created for the window.
+    CreateSurface(wgpu::CreateSurfaceError),
+    /// No graphics adapter can draw to the window.
+    NoAdapter,
+    /// The adapter refused to create a device, usually a driver problem.
+    RequestDevice(wgpu::RequestDeviceError),
+    /// The surface offers no format or present mode to render with.
+    UnsupportedSurfaceFormat,
+    /// No frame could be taken from the surface. `Lost` and `Outdated` are
+    /// fixed by reconfiguring it.
+    Surface(wgpu::SurfaceError),
+    /// The graphics device was lost and could not be recreated yet.
+    DeviceLost,
+    /// wgpu reported an error outside of any call that returns one.
+    Gpu(String),
+    /// An asset, cache or dump file could not be read or written.
+    Io(PathBuf, io::Error),
+    /// An image is not in a supported format, or is corrupt.
+    Decode(String, image::ImageError),
+    /// A tileset image cannot be cut into tiles as asked.
+    Grid(String),
+    /// An image is bigger than the device's texture size limit, given last.
+    TextureTooLarge(String, Size<u32>, u32),
+    /// A texture needs more layers than the device's limit, given last.
+    TextureTooManyLayers(String, u32),
+    /// A codepage mapping file could not be read or parsed.
+    Codepage(CodepageError),
+    /// A font could not be read or used.
+    Font(FontError),
+    /// A layer's fragment shader failed to compile.
+    Shader(u32, String),
+    /// A [`Config`](crate::Config) value is out of range.
+    Config(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Window(error) => {
+                write!(f, "Could not create the window: {}", error)
+            }
+            Error::CreateSurface(error) => {
+                write!(f, "Could not create a surface for the window: {}", error)
+            }
+            Error::NoAdapter => {
+                write!(f, "No graphics adapter can draw to the window")
+            }
+            Error::RequestDevice(error) => {
+                write!(f, "Could not open the graphics device: {}", error)
+            }
+            Error::UnsupportedSurfaceFormat => {
+                write!(f, "The window surface has no supported format")
+            }
+            Error::Surface(error) => {
+                write!(f, "Could not get a frame from the window surface: {}", error)
+            }
+            Error::DeviceLost => {
+                write!(f, "The graphics device was lost")
+            }
+            Error::Gpu(message) => {
+                write!(f, "Graphics error: {}", message)
+            }
+            Error::Io(path, error) => {
+                write!(f, "Could not access {}: {}", path.display(), error)
+            }
+            Error::Decode(label, error) => {
+                write!(f, "Could not decode image {}: {}", label, error)
+            }
+            Error::Grid(message) => {
+                write!(f, "Invalid tile grid: {}", message)
+            }
+            Error::TextureTooLarge(label, size, max) => {
+                write!(
+                    f,
+                    "Image {} is {}x{}, larger than the device's limit of {} pixels per side",
+                    label, size.width, size.height, max,
+                )
+            }
+            Error::TextureTooManyLayers(label, max) => {
+                write!(f, "Texture {} needs more than the device's limit of {} layers", label, max)
+            }
+            Error::Codepage(error) => {
+                write!(f, "{}", error)
+            }
+            Error::Font(error) => {
+                write!(f, "{}", error)
+            }
+            Error::Shader(layer, message) => {
+                write!(f, "Invalid shader for layer {}: {}", layer, message)
+            }
+            Error::Config(message) => {
+                write!(f, "Invalid configuration: {}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Window(error) => Some(error),
+            Error::CreateSurface(error) => Some(error),
+            Error::RequestDevice(error) => Some(error),
+            Error::Surface(error) => Some(error),
+            Error::Io(_, error) => Some(error),
+            Error::Decode(_, error) => Some(error),
+            Error::Codepage(error) => Some(error),
+            Error::Font(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<TextureError> for Error {
+    fn from(error: TextureError) -> Self {
+        match error {
+            TextureError::NotFound(path) => {
+                Error::Io(path, io::ErrorKind::NotFound.into())
+            }
+            TextureError::Io(path, error) => Error::Io(path, error),
+            TextureError::Decode(label, error) => Error::Decode(label, error),
+            TextureError::Grid(message) => Error::Grid(message),
+            TextureError::TooLarge(label, size, max) => Error::TextureTooLarge(label, size, max),
+            TextureError::TooManyLayers(label, max) => Error::TextureTooManyLayers(label, max),
+        }
+    }
+}
+
+impl From<CodepageError> for Error {
+    fn from(error: CodepageError) -> Self {
+        Error::Codepage(error)
+    }
+}
+
+impl From<FontError> for Error {
+    fn from(error: FontError) -> Self {
+        Error::Font(error)
+    }
+}
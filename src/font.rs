@@ -0,0 +1,439 @@
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use image::{Rgba, RgbaImage};
+use swash::scale::{Render, ScaleContext, Source};
+use swash::zeno::{Format, Vector};
+use swash::FontRef;
+
+use crate::cache::CacheKey;
+use crate::size::Size;
+
+
+// Coverage at or above this is ink in monochrome mode
+const MONO_THRESHOLD: f32 = 0.5;
+
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub enum FontMode {
+    /// Edges are blended by how much of each pixel the outline covers.
+    #[default]
+    Antialiased,
+    /// Every pixel is ink or empty, for crisp pixel fonts.
+    Monochrome,
+}
+
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FontOptions {
+    /// Pixels from the highest ascender to the lowest descender.
+    pub size: f32,
+    pub mode: FontMode,
+    /// Coverage is raised to `1 / gamma`. Above 1 thickens thin strokes,
+    /// which linear blending otherwise shows too light.
+    pub gamma: f32,
+    /// Run the font's hinting instructions, which fit stems and edges to
+    /// the pixel grid, and put the baseline and each glyph's origin on
+    /// whole pixels for them to work from.
+    pub hinting: bool,
+}
+
+impl Default for FontOptions {
+    fn default() -> Self {
+        Self {
+            size: 16.0,
+            mode: FontMode::Antialiased,
+            gamma: 1.0,
+            hinting: true,
+        }
+    }
+}
+
+
+/// A TrueType or OpenType font drawn one glyph per cell, white on
+/// transparent like every other tile, so cells tint it with their color.
+#[derive(Clone)]
+pub struct Font {
+    // The font file, read in place on every use
+    data: Arc<[u8]>,
+    // Where in `data` the font is, and its key in swash's caches
+    offset: u32,
+    key: swash::CacheKey,
+    // Pixels per em that make the font `options.size` pixels high
+    ppem: f32,
+    options: FontOptions,
+    cell_size: Size<i32>,
+    // Pixels from the top of the cell down to the baseline
+    baseline: f32,
+    // Hash of the font file, so atlas caches notice a changed font
+    digest: u64,
+}
+
+impl Font {
+    /// Parse a TTF or OTF font held in memory. `label` names it in errors.
+    pub fn from_bytes(bytes: Vec<u8>, label: &str, options: FontOptions) -> Result<Self, FontError> {
+        if options.size <= 0.0 || options.gamma <= 0.0 {
+            return Err(FontError::Options(format!(
+                "size and gamma must be positive, got {} and {}",
+                options.size, options.gamma,
+            )));
+        }
+
+        let mut digest = CacheKey::new();
+        digest.write(&bytes);
+        let digest = digest.finish();
+        let font = FontRef::from_index(&bytes, 0)
+            .ok_or_else(|| FontError::Invalid(label.into()))?;
+
+        let metrics = font.metrics(&[]);
+        let height = metrics.ascent + metrics.descent;
+        if metrics.units_per_em == 0 || height <= 0.0 {
+            return Err(FontError::Invalid(label.into()));
+        }
+        let ppem = options.size * metrics.units_per_em as f32 / height;
+        let metrics = metrics.scale(ppem);
+        let glyphs = font.glyph_metrics(&[]).scale(ppem);
+        let charmap = font.charmap();
+        // The widest printable ASCII glyph, so proportional fonts fit too
+        let advance = (' '..='~')
+            .map(|code| glyphs.advance_width(charmap.map(code)))
+            .fold(0.0, f32::max);
+        let (cell_size, baseline) = cell_metrics(
+            advance,
+            metrics.ascent,
+            -metrics.descent,
+            metrics.leading,
+            options.hinting,
+        );
+
+        let (offset, key) = (font.offset, font.key);
+        Ok(Self {
+            data: bytes.into(),
+            offset,
+            key,
+            ppem,
+            options,
+            cell_size,
+            baseline,
+            digest,
+        })
+    }
+
+    /// Read the font file at `path`, like [`Font::from_bytes`].
+    pub fn load(path: impl AsRef<Path>, options: FontOptions) -> Result<Self, FontError> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path).map_err(|error| FontError::Io(path.into(), error))?;
+        Self::from_bytes(bytes, &path.display().to_string(), options)
+    }
+
+    pub fn options(&self) -> &FontOptions {
+        &self.options
+    }
+
+    /// The cell that fits every glyph's advance and a full line.
+    pub fn cell_size(&self) -> Size<i32> {
+        self.cell_size
+    }
+
+    /// Whether the font has a glyph for `code`.
+    pub fn provides(&self, code: char) -> bool {
+        self.font().charmap().map(code) != 0
+    }
+
+    /// Draw `code` on the baseline and centered on its advance, in a tile
+    /// as tall as the cell and as many cells wide as the advance needs, so
+    /// wide glyphs such as CJK ideographs take two. Ink beyond the tile,
+    /// such as a tall accent or a swash past the advance, is clipped.
+    /// `None` if the font has no glyph for it.
+    pub fn rasterize(&self, code: char) -> Option<RgbaImage> {
+        let font = self.font();
+        let id = font.charmap().map(code);
+        if id == 0 {
+            return None;
+        }
+
+        let advance = font.glyph_metrics(&[]).scale(self.ppem).advance_width(id);
+        let cells = (advance / self.cell_size.width as f32).ceil().max(1.0) as i32;
+        let (width, height) = (self.cell_size.width * cells, self.cell_size.height);
+        let mut tile = RgbaImage::new(width as u32, height as u32);
+
+        let mut x = (width as f32 - advance) / 2.0;
+        if self.options.hinting {
+            x = x.round();
+        }
+
+        let mut context = ScaleContext::new();
+        let mut scaler = context
+            .builder(font)
+            .size(self.ppem)
+            .hint(self.options.hinting)
+            .build();
+        // Whole pixels of the origin go into the placement, the rest into
+        // the outline. Its y axis points up, and swash only shifts it right
+        // and up cleanly, so the origin starts below the baseline
+        let (left, top) = (x.floor(), self.baseline.ceil());
+        let shift = Vector::new(x - left, top - self.baseline);
+        let image = Render::new(&[Source::Outline])
+            .format(Format::Alpha)
+            .offset(shift)
+            .render(&mut scaler, id);
+        // Blank glyphs such as the space have no outline
+        let Some(image) = image else {
+            return Some(tile);
+        };
+
+        let placement = image.placement;
+        for (index, coverage) in image.data.iter().enumerate() {
+            let px = left as i32 + placement.left + (index as u32 % placement.width) as i32;
+            let py = top as i32 - placement.top + (index as u32 / placement.width) as i32;
+            if px < 0 || py < 0 || px >= width || py >= height {
+                continue;
+            }
+            let alpha = shade(*coverage as f32 / 255.0, self.options.mode, self.options.gamma);
+            tile.put_pixel(px as u32, py as u32, Rgba([255, 255, 255, alpha]));
+        }
+
+        Some(tile)
+    }
+
+    fn font(&self) -> FontRef<'_> {
+        FontRef { data: &self.data, offset: self.offset, key: self.key }
+    }
+}
+
+
+impl fmt::Debug for Font {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Font")
+            .field("options", &self.options)
+            .field("cell_size", &self.cell_size)
+            .field("baseline", &self.baseline)
+            .finish()
+    }
+}
+
+
+/// Fonts hash by their file and options, which decide every glyph.
+impl Hash for Font {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.digest.hash(state);
+        self.options.size.to_bits().hash(state);
+        self.options.mode.hash(state);
+        self.options.gamma.to_bits().hash(state);
+        self.options.hinting.hash(state);
+    }
+}
+
+
+/// Why a font could not be used.
+#[derive(Debug)]
+pub enum FontError {
+    /// The font file could not be read.
+    Io(PathBuf, io::Error),
+    /// The data is not a TrueType or OpenType font.
+    Invalid(String),
+    /// A [`FontOptions`] value is out of range.
+    Options(String),
+}
+
+impl fmt::Display for FontError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FontError::Io(path, error) => {
+                write!(f, "Could not read font {}: {}", path.display(), error)
+            }
+            FontError::Invalid(label) => {
+                write!(f, "Not a TrueType or OpenType font: {}", label)
+            }
+            FontError::Options(message) => {
+                write!(f, "Invalid font options: {}", message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FontError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FontError::Io(_, error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+
+/// The cell size and baseline for glyphs `advance` wide, with the line
+/// gap split evenly above and below. The cell is whole pixels either way;
+/// hinting rounds the baseline too.
+fn cell_metrics(
+    advance: f32,
+    ascent: f32,
+    descent: f32,
+    line_gap: f32,
+    hinting: bool,
+) -> (Size<i32>, f32) {
+    let line = ascent - descent + line_gap.max(0.0);
+    let size = Size::new(advance.ceil().max(1.0) as i32, line.ceil().max(1.0) as i32);
+
+    let baseline = line_gap.max(0.0) / 2.0 + ascent;
+    let baseline = if hinting { baseline.round() } else { baseline };
+    (size, baseline)
+}
+
+/// The alpha of a pixel the outline covers `coverage` of.
+fn shade(coverage: f32, mode: FontMode, gamma: f32) -> u8 {
+    let coverage = coverage.clamp(0.0, 1.0);
+    match mode {
+        FontMode::Monochrome if coverage >= MONO_THRESHOLD => 255,
+        FontMode::Monochrome => 0,
+        FontMode::Antialiased => (coverage.powf(1.0 / gamma) * 255.0).round() as u8,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use image::RgbaImage;
+
+    use crate::font::{cell_metrics, shade, Font, FontMode, FontOptions};
+    use crate::size::Size;
+
+    fn fixture(options: FontOptions) -> Font {
+        let bytes = include_bytes!("../tests/fixtures/DejaVuSansMono.ttf");
+        Font::from_bytes(bytes.to_vec(), "DejaVuSansMono", options).unwrap()
+    }
+
+    // The first and last rows, then columns, with any ink
+    fn ink_bounds(tile: &RgbaImage) -> Option<(u32, u32, u32, u32)> {
+        let inked = || tile.enumerate_pixels().filter(|(_, _, pixel)| pixel[3] > 0);
+        let top = inked().map(|(_, y, _)| y).min()?;
+        let bottom = inked().map(|(_, y, _)| y).max()?;
+        let left = inked().map(|(x, _, _)| x).min()?;
+        let right = inked().map(|(x, _, _)| x).max()?;
+        Some((top, bottom, left, right))
+    }
+
+    #[test]
+    fn test_cell_metrics_fit_advance_and_line() {
+        let (size, baseline) = cell_metrics(9.6, 12.2, -3.4, 1.0, false);
+        assert_eq!(size, Size::new(10, 17));
+        assert!((baseline - 12.7).abs() < 1e-4);
+
+        let (_, baseline) = cell_metrics(9.6, 12.2, -3.4, 1.0, true);
+        assert_eq!(baseline, 13.0);
+    }
+
+    #[test]
+    fn test_shade_by_mode_and_gamma() {
+        assert_eq!(shade(0.6, FontMode::Monochrome, 1.0), 255);
+        assert_eq!(shade(0.4, FontMode::Monochrome, 1.0), 0);
+        assert_eq!(shade(0.5, FontMode::Antialiased, 1.0), 128);
+        assert_eq!(shade(0.25, FontMode::Antialiased, 2.0), 128);
+        assert_eq!(shade(1.5, FontMode::Antialiased, 1.0), 255);
+    }
+
+    #[test]
+    fn test_invalid_font_is_rejected() {
+        let options = FontOptions::default();
+        assert!(Font::from_bytes(b"not a font".to_vec(), "junk", options).is_err());
+
+        let options = FontOptions { size: 0.0, ..FontOptions::default() };
+        assert!(Font::from_bytes(Vec::new(), "empty", options).is_err());
+    }
+
+    #[test]
+    fn test_fixture_cell_and_baseline() {
+        let font = fixture(FontOptions::default());
+        assert_eq!(font.cell_size(), Size::new(9, 16));
+        assert_eq!(font.baseline, 13.0);
+
+        let font = fixture(FontOptions { hinting: false, ..FontOptions::default() });
+        assert_eq!(font.cell_size(), Size::new(9, 16));
+        assert!(font.baseline > 12.0 && font.baseline < 13.0);
+    }
+
+    #[test]
+    fn test_glyphs_sit_on_the_baseline_in_their_cell() {
+        let font = fixture(FontOptions::default());
+        let tile = font.rasterize('H').unwrap();
+        assert_eq!(tile.dimensions(), (9, 16));
+
+        let (top, bottom, left, right) = ink_bounds(&tile).unwrap();
+        assert_eq!((top, bottom), (3, 12));
+        // Centered on the advance, give or take the rounded origin
+        assert!((left as i32 - (8 - right as i32)).abs() <= 1);
+
+        let (_, bottom, _, _) = ink_bounds(&font.rasterize('g').unwrap()).unwrap();
+        assert!(bottom >= 13);
+    }
+
+    #[test]
+    fn test_wide_glyphs_take_whole_cells() {
+        let mut font = fixture(FontOptions::default());
+        // Narrower than every glyph's advance
+        font.cell_size = Size::new(5, 16);
+        let tile = font.rasterize('H').unwrap();
+        assert_eq!(tile.dimensions(), (10, 16));
+
+        let (_, _, left, right) = ink_bounds(&tile).unwrap();
+        assert!(left > 0 && right < 9);
+        assert!((left as i32 - (9 - right as i32)).abs() <= 1);
+    }
+
+    #[test]
+    fn test_ink_beyond_the_tile_is_clipped() {
+        let mut font = fixture(FontOptions::default());
+        // Most of the glyph is above the cell
+        font.baseline = 4.0;
+        let tile = font.rasterize('H').unwrap();
+        assert_eq!(tile.dimensions(), (9, 16));
+        assert_eq!(ink_bounds(&tile).map(|(top, bottom, _, _)| (top, bottom)), Some((0, 3)));
+    }
+
+    #[test]
+    fn test_hinting_fits_edges_to_whole_pixels() {
+        // The most ink in the first and last inked rows
+        let edges = |hinting: bool| -> (u8, u8) {
+            let options = FontOptions { hinting, ..FontOptions::default() };
+            let tile = fixture(options).rasterize('H').unwrap();
+            let (top, bottom, _, _) = ink_bounds(&tile).unwrap();
+            let ink = |y: u32| (0..tile.width()).map(|x| tile.get_pixel(x, y)[3]).max().unwrap();
+            (ink(top), ink(bottom))
+        };
+
+        assert_eq!(edges(true), (255, 255));
+        let (top, bottom) = edges(false);
+        assert!(top < 255 && bottom < 255);
+    }
+
+    #[test]
+    fn test_glyph_coverage_by_mode_and_gamma() {
+        let alphas = |options: FontOptions| -> Vec<u8> {
+            let tile = fixture(options).rasterize('O').unwrap();
+            assert!(tile.pixels().all(|pixel| pixel[0] == 255 || pixel[3] == 0));
+            tile.pixels().map(|pixel| pixel[3]).collect()
+        };
+        let total = |alphas: &[u8]| alphas.iter().map(|alpha| *alpha as u32).sum::<u32>();
+
+        let smooth = alphas(FontOptions::default());
+        assert!(smooth.iter().any(|alpha| *alpha > 0 && *alpha < 255));
+
+        let mono = alphas(FontOptions { mode: FontMode::Monochrome, ..FontOptions::default() });
+        assert!(mono.iter().all(|alpha| *alpha == 0 || *alpha == 255));
+        assert!(mono.contains(&255));
+
+        let bold = alphas(FontOptions { gamma: 2.2, ..FontOptions::default() });
+        assert!(total(&bold) > total(&smooth));
+    }
+
+    #[test]
+    fn test_blank_and_missing_glyphs() {
+        let font = fixture(FontOptions::default());
+        assert!(ink_bounds(&font.rasterize(' ').unwrap()).is_none());
+        assert!(!font.provides('\u{E000}'));
+        assert!(font.rasterize('\u{E000}').is_none());
+    }
+}
@@ -0,0 +1,563 @@
+use std::ops::RangeInclusive;
+
+use image::{Rgba, RgbaImage};
+
+use crate::size::Size;
+
+
+/// Box drawing (U+2500–U+257F) and block elements (U+2580–U+259F), which are
+/// drawn here instead of taken from a font so that lines join seamlessly
+/// between neighbouring cells.
+pub const GENERATED: RangeInclusive<char> = '\u{2500}'..='\u{259F}';
+
+// Arm weights of a box-drawing line toward one edge of the cell
+const NONE: u8 = 0;
+const LIGHT: u8 = 1;
+const HEAVY: u8 = 2;
+const DOUBLE: u8 = 3;
+
+const UP: usize = 0;
+const RIGHT: usize = 1;
+const DOWN: usize = 2;
+const LEFT: usize = 3;
+
+
+/// Every generated glyph at `cell_size`.
+pub fn generate_all(cell_size: Size<i32>) -> impl Iterator<Item = (char, RgbaImage)> {
+    GENERATED.filter_map(move |code| Some((code, generate(code, cell_size)?)))
+}
+
+/// Draw `code` white on transparent at `cell_size`, or `None` if it is not
+/// in [`GENERATED`].
+pub fn generate(code: char, cell_size: Size<i32>) -> Option<RgbaImage> {
+    if !GENERATED.contains(&code) || cell_size.width <= 0 || cell_size.height <= 0 {
+        return None;
+    }
+
+    let mut canvas = Canvas::new(cell_size.width, cell_size.height);
+    let index = code as u32;
+
+    if let Some(arms) = arms(index) {
+        canvas.lines(arms);
+    } else if let Some((vertical, weight, segments)) = dashes(index) {
+        canvas.dashes(vertical, weight, segments);
+    } else {
+        match index {
+            0x256D => canvas.arc(1, 1),
+            0x256E => canvas.arc(-1, 1),
+            0x256F => canvas.arc(-1, -1),
+            0x2570 => canvas.arc(1, -1),
+            0x2571 => canvas.diagonal(true),
+            0x2572 => canvas.diagonal(false),
+            0x2573 => {
+                canvas.diagonal(true);
+                canvas.diagonal(false);
+            }
+            _ => canvas.block(index),
+        }
+    }
+
+    Some(canvas.into_image())
+}
+
+/// The up, right, down and left arm weights of the solid box-drawing lines.
+fn arms(index: u32) -> Option<[u8; 4]> {
+    let arms: &[u8; 4] = match index {
+        0x2500 => b"0101",
+        0x2501 => b"0202",
+        0x2502 => b"1010",
+        0x2503 => b"2020",
+        0x250C => b"0110",
+        0x250D => b"0210",
+        0x250E => b"0120",
+        0x250F => b"0220",
+        0x2510 => b"0011",
+        0x2511 => b"0012",
+        0x2512 => b"0021",
+        0x2513 => b"0022",
+        0x2514 => b"1100",
+        0x2515 => b"1200",
+        0x2516 => b"2100",
+        0x2517 => b"2200",
+        0x2518 => b"1001",
+        0x2519 => b"1002",
+        0x251A => b"2001",
+        0x251B => b"2002",
+        0x251C => b"1110",
+        0x251D => b"1210",
+        0x251E => b"2110",
+        0x251F => b"1120",
+        0x2520 => b"2120",
+        0x2521 => b"2210",
+        0x2522 => b"1220",
+        0x2523 => b"2220",
+        0x2524 => b"1011",
+        0x2525 => b"1012",
+        0x2526 => b"2011",
+        0x2527 => b"1021",
+        0x2528 => b"2021",
+        0x2529 => b"2012",
+        0x252A => b"1022",
+        0x252B => b"2022",
+        0x252C => b"0111",
+        0x252D => b"0112",
+        0x252E => b"0211",
+        0x252F => b"0212",
+        0x2530 => b"0121",
+        0x2531 => b"0122",
+        0x2532 => b"0221",
+        0x2533 => b"0222",
+        0x2534 => b"1101",
+        0x2535 => b"1102",
+        0x2536 => b"1201",
+        0x2537 => b"1202",
+        0x2538 => b"2101",
+        0x2539 => b"2102",
+        0x253A => b"2201",
+        0x253B => b"2202",
+        0x253C => b"1111",
+        0x253D => b"1112",
+        0x253E => b"1211",
+        0x253F => b"1212",
+        0x2540 => b"2111",
+        0x2541 => b"1121",
+        0x2542 => b"2121",
+        0x2543 => b"2112",
+        0x2544 => b"2211",
+        0x2545 => b"1122",
+        0x2546 => b"1221",
+        0x2547 => b"2212",
+        0x2548 => b"1222",
+        0x2549 => b"2122",
+        0x254A => b"2221",
+        0x254B => b"2222",
+        0x2550 => b"0303",
+        0x2551 => b"3030",
+        0x2552 => b"0310",
+        0x2553 => b"0130",
+        0x2554 => b"0330",
+        0x2555 => b"0013",
+        0x2556 => b"0031",
+        0x2557 => b"0033",
+        0x2558 => b"1300",
+        0x2559 => b"3100",
+        0x255A => b"3300",
+        0x255B => b"1003",
+        0x255C => b"3001",
+        0x255D => b"3003",
+        0x255E => b"1310",
+        0x255F => b"3130",
+        0x2560 => b"3330",
+        0x2561 => b"1013",
+        0x2562 => b"3031",
+        0x2563 => b"3033",
+        0x2564 => b"0313",
+        0x2565 => b"0131",
+        0x2566 => b"0333",
+        0x2567 => b"1303",
+        0x2568 => b"3101",
+        0x2569 => b"3303",
+        0x256A => b"1313",
+        0x256B => b"3131",
+        0x256C => b"3333",
+        0x2574 => b"0001",
+        0x2575 => b"1000",
+        0x2576 => b"0100",
+        0x2577 => b"0010",
+        0x2578 => b"0002",
+        0x2579 => b"2000",
+        0x257A => b"0200",
+        0x257B => b"0020",
+        0x257C => b"0201",
+        0x257D => b"1020",
+        0x257E => b"0102",
+        0x257F => b"2010",
+        _ => return None,
+    };
+
+    Some(arms.map(|digit| digit - b'0'))
+}
+
+/// Whether the dashed line is vertical, its weight and its number of dashes.
+fn dashes(index: u32) -> Option<(bool, u8, i32)> {
+    match index {
+        0x2504 => Some((false, LIGHT, 3)),
+        0x2505 => Some((false, HEAVY, 3)),
+        0x2506 => Some((true, LIGHT, 3)),
+        0x2507 => Some((true, HEAVY, 3)),
+        0x2508 => Some((false, LIGHT, 4)),
+        0x2509 => Some((false, HEAVY, 4)),
+        0x250A => Some((true, LIGHT, 4)),
+        0x250B => Some((true, HEAVY, 4)),
+        0x254C => Some((false, LIGHT, 2)),
+        0x254D => Some((false, HEAVY, 2)),
+        0x254E => Some((true, LIGHT, 2)),
+        0x254F => Some((true, HEAVY, 2)),
+        _ => None,
+    }
+}
+
+
+/// A coverage mask the size of one cell.
+struct Canvas {
+    width: i32,
+    height: i32,
+    alpha: Vec<u8>,
+    // The stroke width of light lines; heavy lines are twice as wide and
+    // double lines are two light lines with a light line's gap between them
+    light: i32,
+}
+
+impl Canvas {
+    fn new(width: i32, height: i32) -> Self {
+        Self {
+            width,
+            height,
+            alpha: vec![0; (width * height) as usize],
+            light: (width.min(height) / 8).max(1),
+        }
+    }
+
+    fn fill(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, alpha: u8) {
+        for y in y0.max(0)..y1.min(self.height) {
+            for x in x0.max(0)..x1.min(self.width) {
+                self.alpha[(y * self.width + x) as usize] = alpha;
+            }
+        }
+    }
+
+    fn thickness(&self, weight: u8) -> i32 {
+        match weight {
+            NONE => 0,
+            LIGHT => self.light,
+            HEAVY => self.light * 2,
+            _ => self.light * 3,
+        }
+    }
+
+    /// The span across a line of `weight` centered in a cell `length` wide.
+    /// Every glyph uses the same spans, which is what makes them join.
+    fn band(&self, length: i32, weight: u8) -> (i32, i32) {
+        let thickness = self.thickness(weight);
+        let start = length / 2 - thickness / 2;
+        (start, start + thickness)
+    }
+
+    /// The span covered by the widest of two perpendicular arms, or by a
+    /// line of `own` weight when there are none, so arms end in the middle.
+    fn junction(&self, length: i32, first: u8, second: u8, own: u8) -> (i32, i32) {
+        match (first, second) {
+            (NONE, NONE) => self.band(length, own),
+            _ => {
+                let a = self.band(length, first);
+                let b = self.band(length, second);
+                match (first, second) {
+                    (NONE, _) => b,
+                    (_, NONE) => a,
+                    _ => (a.0.min(b.0), a.1.max(b.1)),
+                }
+            }
+        }
+    }
+
+    /// Draw the arms of a box-drawing character. Double arms are drawn as
+    /// a solid band with their gap cleared afterwards, which opens up the
+    /// junctions between double lines; single arms go on top so they can
+    /// cross the gaps where they pass straight through.
+    fn lines(&mut self, arms: [u8; 4]) {
+        let (width, height) = (self.width, self.height);
+        let light = self.light;
+
+        let (hy0, hy1) = self.junction(height, arms[LEFT], arms[RIGHT], arms[UP].max(arms[DOWN]));
+        let (vx0, vx1) = self.junction(width, arms[UP], arms[DOWN], arms[LEFT].max(arms[RIGHT]));
+
+        // Where each arm starts and ends along its own axis
+        let extent = |arm: usize| match arm {
+            UP => (0, hy1),
+            RIGHT => (vx0, width),
+            DOWN => (hy0, height),
+            _ => (0, vx1),
+        };
+
+        for arm in [UP, RIGHT, DOWN, LEFT] {
+            if arms[arm] == DOUBLE {
+                let (start, end) = extent(arm);
+                self.arm(arm, arms[arm], start, end);
+            }
+        }
+
+        for arm in [UP, RIGHT, DOWN, LEFT] {
+            if arms[arm] != DOUBLE {
+                continue;
+            }
+
+            // The gap reaches into the junction up to the inner line
+            let (start, end) = match arm {
+                UP => (0, hy1 - light),
+                RIGHT => (vx0 + light, width),
+                DOWN => (hy0 + light, height),
+                _ => (0, vx1 - light),
+            };
+            let (across, _) = self.band(
+                if arm == UP || arm == DOWN { width } else { height },
+                DOUBLE,
+            );
+            match arm {
+                UP | DOWN => self.fill(across + light, start, across + 2 * light, end, 0),
+                _ => self.fill(start, across + light, end, across + 2 * light, 0),
+            }
+        }
+
+        for arm in [UP, RIGHT, DOWN, LEFT] {
+            let weight = arms[arm];
+            if weight == NONE || weight == DOUBLE {
+                continue;
+            }
+
+            let (mut start, mut end) = extent(arm);
+            let opposite = arms[(arm + 2) % 4];
+            let (side_a, side_b) = (arms[(arm + 1) % 4], arms[(arm + 3) % 4]);
+
+            // A single arm meeting a double line that runs straight through
+            // stops at the near line, unless it crosses to the other side
+            if side_a == DOUBLE && side_b == DOUBLE && opposite == NONE {
+                match arm {
+                    UP => end = hy0 + light,
+                    RIGHT => start = vx1 - light,
+                    DOWN => start = hy1 - light,
+                    _ => end = vx0 + light,
+                }
+            }
+
+            self.arm(arm, weight, start, end);
+        }
+    }
+
+    fn arm(&mut self, arm: usize, weight: u8, start: i32, end: i32) {
+        match arm {
+            UP | DOWN => {
+                let (x0, x1) = self.band(self.width, weight);
+                self.fill(x0, start, x1, end, 255);
+            }
+            _ => {
+                let (y0, y1) = self.band(self.height, weight);
+                self.fill(start, y0, end, y1, 255);
+            }
+        }
+    }
+
+    fn dashes(&mut self, vertical: bool, weight: u8, segments: i32) {
+        let length = if vertical { self.height } else { self.width };
+        let (across0, across1) = self.band(
+            if vertical { self.width } else { self.height },
+            weight,
+        );
+
+        // Each dash gets an equal share of the cell with its gap split around
+        // it, so the spacing stays even across neighbouring cells
+        let gap = (length / segments / 4).max(1);
+        for i in 0..segments {
+            let start = i * length / segments + gap / 2;
+            let end = (i + 1) * length / segments - (gap - gap / 2);
+            if vertical {
+                self.fill(across0, start, across1, end, 255);
+            } else {
+                self.fill(start, across0, end, across1, 255);
+            }
+        }
+    }
+
+    /// A rounded light corner with arms toward `dx` (1 right, -1 left) and
+    /// `dy` (1 down, -1 up).
+    fn arc(&mut self, dx: i32, dy: i32) {
+        let (x0, x1) = self.band(self.width, LIGHT);
+        let (y0, y1) = self.band(self.height, LIGHT);
+        let (cx, cy) = ((x0 + x1) as f32 / 2.0, (y0 + y1) as f32 / 2.0);
+
+        // The largest radius that fits both arms of the corner
+        let reach_x = if dx > 0 { self.width as f32 - cx } else { cx };
+        let reach_y = if dy > 0 { self.height as f32 - cy } else { cy };
+        let radius = reach_x.min(reach_y).max(1.0);
+        let (ox, oy) = (cx + dx as f32 * radius, cy + dy as f32 * radius);
+        let half = self.light as f32 / 2.0;
+
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let (px, py) = (x as f32 + 0.5, y as f32 + 0.5);
+                // Only the quarter of the circle facing the corner
+                if (px - ox) * dx as f32 > 0.0 || (py - oy) * dy as f32 > 0.0 {
+                    continue;
+                }
+
+                let distance = ((px - ox).powi(2) + (py - oy).powi(2)).sqrt();
+                if (distance - radius).abs() <= half {
+                    self.alpha[(y * self.width + x) as usize] = 255;
+                }
+            }
+        }
+
+        // Straight runs from where the arc ends to the edges
+        let ox = ox.round() as i32;
+        let oy = oy.round() as i32;
+        if dx > 0 {
+            self.fill(ox, y0, self.width, y1, 255);
+        } else {
+            self.fill(0, y0, ox, y1, 255);
+        }
+        if dy > 0 {
+            self.fill(x0, oy, x1, self.height, 255);
+        } else {
+            self.fill(x0, 0, x1, oy, 255);
+        }
+    }
+
+    /// A light diagonal from corner to corner, rising to the right if
+    /// `rising`. Each row covers every pixel the line passes through.
+    fn diagonal(&mut self, rising: bool) {
+        let (width, height) = (self.width as f32, self.height as f32);
+        let x_at = |y: f32| {
+            let x = y / height * width;
+            if rising { width - x } else { x }
+        };
+
+        for y in 0..self.height {
+            let (a, b) = (x_at(y as f32), x_at((y + 1) as f32));
+            let (left, right) = (a.min(b), a.max(b));
+            let x0 = left.floor() as i32 - (self.light - 1) / 2;
+            let x1 = (right.ceil() as i32).max(x0 + 1) + self.light / 2;
+            self.fill(x0, y, x1, y + 1, 255);
+        }
+    }
+
+    fn block(&mut self, index: u32) {
+        let (width, height) = (self.width, self.height);
+        let eighth_x = |n: i32| (width * n + 4) / 8;
+        let eighth_y = |n: i32| (height * n + 4) / 8;
+        let (mid_x, mid_y) = (width / 2, height / 2);
+
+        match index {
+            0x2580 => self.fill(0, 0, width, mid_y, 255),
+            0x2581..=0x2588 => {
+                let n = (index - 0x2580) as i32;
+                self.fill(0, height - eighth_y(n), width, height, 255);
+            }
+            0x2589..=0x258F => {
+                let n = (0x2590 - index) as i32;
+                self.fill(0, 0, eighth_x(n), height, 255);
+            }
+            0x2590 => self.fill(mid_x, 0, width, height, 255),
+            // Shades are partial coverage rather than dither patterns, which
+            // would not line up between cells of odd sizes
+            0x2591 => self.fill(0, 0, width, height, 64),
+            0x2592 => self.fill(0, 0, width, height, 128),
+            0x2593 => self.fill(0, 0, width, height, 191),
+            0x2594 => self.fill(0, 0, width, eighth_y(1), 255),
+            0x2595 => self.fill(width - eighth_x(1), 0, width, height, 255),
+            0x2596..=0x259F => {
+                // Upper left, upper right, lower left, lower right
+                let quadrants: [bool; 4] = match index {
+                    0x2596 => [false, false, true, false],
+                    0x2597 => [false, false, false, true],
+                    0x2598 => [true, false, false, false],
+                    0x2599 => [true, false, true, true],
+                    0x259A => [true, false, false, true],
+                    0x259B => [true, true, true, false],
+                    0x259C => [true, true, false, true],
+                    0x259D => [false, true, false, false],
+                    0x259E => [false, true, true, false],
+                    _ => [false, true, true, true],
+                };
+
+                let rects = [
+                    (0, 0, mid_x, mid_y),
+                    (mid_x, 0, width, mid_y),
+                    (0, mid_y, mid_x, height),
+                    (mid_x, mid_y, width, height),
+                ];
+                for (filled, (x0, y0, x1, y1)) in quadrants.iter().zip(rects) {
+                    if *filled {
+                        self.fill(x0, y0, x1, y1, 255);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn into_image(self) -> RgbaImage {
+        RgbaImage::from_fn(self.width as u32, self.height as u32, |x, y| {
+            let alpha = self.alpha[(y as i32 * self.width + x as i32) as usize];
+            Rgba([255, 255, 255, alpha])
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use image::RgbaImage;
+
+    use crate::glyphs::{generate, generate_all, GENERATED};
+    use crate::size::Size;
+
+    fn column(image: &RgbaImage, x: u32) -> Vec<u8> {
+        (0..image.height()).map(|y| image.get_pixel(x, y)[3]).collect()
+    }
+
+    fn row(image: &RgbaImage, y: u32) -> Vec<u8> {
+        (0..image.width()).map(|x| image.get_pixel(x, y)[3]).collect()
+    }
+
+    #[test]
+    fn test_every_code_is_generated() {
+        let cell_size = Size::new(8, 16);
+        assert_eq!(generate_all(cell_size).count(), GENERATED.count());
+        assert!(generate('a', cell_size).is_none());
+    }
+
+    #[test]
+    fn test_lines_join_across_cells() {
+        let cell_size = Size::new(9, 17);
+        let horizontal = generate('─', cell_size).unwrap();
+        let cross = generate('┼', cell_size).unwrap();
+        let corner = generate('┐', cell_size).unwrap();
+
+        // Edges that meet must carry the line at the same place
+        assert_eq!(column(&horizontal, 8), column(&cross, 0));
+        assert_eq!(column(&cross, 8), column(&corner, 0));
+
+        let vertical = generate('│', cell_size).unwrap();
+        assert_eq!(row(&vertical, 16), row(&corner, 16));
+        assert_eq!(row(&cross, 0), row(&vertical, 0));
+    }
+
+    #[test]
+    fn test_double_lines_join_across_cells() {
+        let cell_size = Size::new(16, 16);
+        let horizontal = generate('═', cell_size).unwrap();
+        let corner = generate('╔', cell_size).unwrap();
+        let tee = generate('╦', cell_size).unwrap();
+
+        assert_eq!(column(&corner, 15), column(&horizontal, 0));
+        assert_eq!(column(&horizontal, 15), column(&tee, 0));
+
+        // Two lines with a gap between them
+        let edge = column(&horizontal, 0);
+        let runs = edge.windows(2).filter(|w| w[0] != w[1]).count();
+        assert_eq!(runs, 4);
+    }
+
+    #[test]
+    fn test_blocks() {
+        let cell_size = Size::new(8, 16);
+        let full = generate('█', cell_size).unwrap();
+        assert!(full.pixels().all(|p| p[3] == 255));
+
+        let lower = generate('▄', cell_size).unwrap();
+        assert_eq!(lower.get_pixel(0, 7)[3], 0);
+        assert_eq!(lower.get_pixel(0, 8)[3], 255);
+
+        let shade = generate('▒', cell_size).unwrap();
+        assert!(shade.pixels().all(|p| p[3] == 128));
+    }
+}
@@ -7,17 +7,30 @@ extern crate env_logger;
 use std::borrow::Cow;
 
 use winit::{
-    event::{Event, WindowEvent, KeyboardInput, ElementState, VirtualKeyCode},
+    event::{Event, StartCause, WindowEvent, KeyboardInput, ElementState, VirtualKeyCode},
     event_loop::{ControlFlow, EventLoop},
     window::{Window, WindowBuilder},
 };
 
 use wgpu::Surface;
 
+use std::time::Instant;
+
 // Library Internal
 mod atlas;
+mod cache;
+mod clock;
+mod codepage;
+mod color;
+mod config;
+mod cursor;
+mod error;
+mod font;
+mod glyphs;
+mod particles;
 mod point;
 mod rectangle;
+mod scene;
 mod size;
 mod state;
 mod terminal;
@@ -26,16 +39,37 @@ mod vertex;
 mod shape;
 mod tileset;
 
-use state::State;
-
-
-pub async fn run() {
+pub use atlas::{PageStats, TileAlignment};
+pub use cache::CacheKey;
+pub use codepage::{Codepage, CodepageError};
+pub use color::Color;
+pub use config::{Backend, Config, VSync};
+pub use cursor::{Cursor, CursorShape};
+pub use error::Error;
+pub use font::{Font, FontError, FontMode, FontOptions};
+pub use particles::{Emitter, EmitterId};
+pub use point::Point;
+pub use scene::Transform;
+pub use size::Size;
+pub use terminal::{Terminal, TerminalState};
+pub use texture::{TextureError, TextureFilter};
+pub use tileset::{Frame, TileGrid, Tileset};
+
+
+/// Open a window and run the terminal until it is closed. Only errors during
+/// setup are returned; once the event loop starts this never returns.
+/// Nothing is loaded besides the default sheet, so [`Config::atlas_cache`]
+/// goes unused; applications that load tilesets or fonts call
+/// [`Terminal::restore_atlas`] once they have.
+pub async fn run(config: Config) -> Result<(), Error> {
     env_logger::init();
 
     let event_loop = EventLoop::new();
-    let window = WindowBuilder::new().build(&event_loop).unwrap();
+    let window = WindowBuilder::new()
+        .build(&event_loop)
+        .map_err(Error::Window)?;
 
-    let mut terminal = State::new(window).await;
+    let mut terminal = Terminal::new(window, &config).await?;
 
     event_loop.run(move | event, _, control_flow | {
         match event {
@@ -44,69 +78,115 @@ pub async fn run() {
                 window_id,
                 ref event,
             } if window_id == terminal.window().id() => {
-                if !terminal.input(event) {
-                    match event {
-
-                        WindowEvent::CloseRequested
-                        | WindowEvent::KeyboardInput {
-                            input: KeyboardInput {
-                                state: ElementState::Pressed,
-                                virtual_keycode: Some(VirtualKeyCode::Escape),
-                                ..
-                            },
-                            ..
-                        } => {
-                            *control_flow = ControlFlow::Exit;
-                        }
+                if terminal.input(event) {
+                    return;
+                }
 
-                        WindowEvent::Resized(physical_size) => {
-                            terminal.resize(*physical_size);
-                        }
+                match event {
 
-                        WindowEvent::ScaleFactorChanged {
-                            new_inner_size,
+                    WindowEvent::CloseRequested
+                    | WindowEvent::KeyboardInput {
+                        input: KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::Escape),
                             ..
-                        } => {
-                            terminal.resize(**new_inner_size);
-                        }
+                        },
+                        ..
+                    } => {
+                        *control_flow = ControlFlow::Exit;
+                    }
 
-                        _ => {}
+                    WindowEvent::KeyboardInput {
+                        input: KeyboardInput {
+                            state: ElementState::Pressed,
+                            virtual_keycode: Some(VirtualKeyCode::F12),
+                            ..
+                        },
+                        ..
+                    } => {
+                        terminal.set_atlas_overlay(!terminal.atlas_overlay());
+                    }
+
+                    WindowEvent::Resized(physical_size) => {
+                        terminal.resize(*physical_size);
+                    }
 
+                    WindowEvent::ScaleFactorChanged {
+                        new_inner_size,
+                        ..
+                    } => {
+                        terminal.resize(**new_inner_size);
                     }
+
+                    _ => {}
+
                 }
             }
 
             Event::RedrawRequested(
                 window_id,
             ) if window_id == terminal.window().id() => {
-                terminal.update();
+                // A device that could not be recovered is tried again on
+                // the next frame
+                if let Err(error) = terminal.update() {
+                    eprintln!("{}", error);
+                }
                 match terminal.render() {
 
                     Ok(_) => {}
 
-                    // Reconfigure the surface if lost
-                    Err(wgpu::SurfaceError::Lost) => {
-                        terminal.resize(terminal.size);
+                    // Reconfigure the surface if lost or outdated
+                    Err(Error::Surface(
+                        wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated
+                    )) => {
+                        terminal.reconfigure();
+                        terminal.window().request_redraw();
+                    }
+
+                    Err(Error::DeviceLost) => {
+                        terminal.window().request_redraw();
                     }
 
                     // The system is out of memory, we should probably quit...
-                    Err(wgpu::SurfaceError::OutOfMemory) => {
+                    Err(Error::Surface(wgpu::SurfaceError::OutOfMemory)) => {
                         *control_flow = ControlFlow::Exit;
                     }
 
-                    // All other errors (Outdated, Timeout) should be resolved
+                    // All other errors (Timeout) should be resolved
                     // by the next frame
                     Err(e) => eprintln!("{:?}", e),
 
                 }
             }
 
-            Event::MainEventsCleared => {
-                // RedrawRequested will only trigger once unless we manually
-                // request it
+            Event::NewEvents(StartCause::ResumeTimeReached { .. }) => {
                 terminal.window().request_redraw();
             }
 
+            // Scene changes request their own redraw on refresh; only
+            // time-driven effects need a new frame every iteration, otherwise
+            // sleep until the next event
+            Event::MainEventsCleared => {
+                // A blinking cursor only needs a frame when it toggles
+                if !terminal.is_animating() {
+                    *control_flow = match terminal.next_cursor_toggle() {
+                        Some(toggle) => ControlFlow::WaitUntil(toggle),
+                        None => ControlFlow::Wait,
+                    };
+                    return;
+                }
+
+                match terminal.next_frame_time() {
+                    Some(next) if Instant::now() < next => {
+                        *control_flow = ControlFlow::WaitUntil(next);
+                    }
+                    _ => {
+                        *control_flow = ControlFlow::Poll;
+                        terminal.window().request_redraw();
+                    }
+                }
+            }
+
             _ => {}
         }
     });
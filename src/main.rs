@@ -1,6 +1,10 @@
+#[allow(clippy::single_component_path_imports)]
 use pollster;
-use nocterminal::run;
+use nocterminal::{run, Config};
 
 fn main() {
-    pollster::block_on(run());
+    if let Err(error) = pollster::block_on(run(Config::default())) {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    }
 }
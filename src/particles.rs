@@ -0,0 +1,371 @@
+use std::collections::BTreeMap;
+
+use crate::color::Color;
+use crate::point::Point;
+
+
+// Longest simulation step, so a stall (or a minimized window) does not throw
+// every particle across the screen at once
+const MAX_STEP: f32 = 0.25;
+
+
+/// Spawns particles at a point. Particles are drawn over every layer of
+/// cells and never touch them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Emitter {
+    /// Where particles spawn, in pixels.
+    pub position: Point<f32>,
+    /// Particles spawned at once when the emitter is added, for explosions.
+    pub burst: u32,
+    /// Particles spawned per second.
+    pub rate: f32,
+    /// Seconds to keep spawning for; `None` spawns until removed.
+    pub duration: Option<f32>,
+    /// Seconds each particle lives.
+    pub lifetime: f32,
+    /// Initial velocity in pixels per second.
+    pub velocity: Point<f32>,
+    /// Random change to the initial direction, in radians either way.
+    pub spread: f32,
+    /// Random change to the initial speed, as a fraction of it.
+    pub speed_variance: f32,
+    /// Constant acceleration in pixels per second squared, such as gravity.
+    pub acceleration: Point<f32>,
+    /// Colors over a particle's life, evenly spaced and blended.
+    pub colors: Vec<Color>,
+    /// The tile particles are drawn with.
+    pub code: char,
+    /// Particle size relative to the cell size.
+    pub scale: f32,
+    /// Turn each tile so its top faces the way it moves.
+    pub align_to_velocity: bool,
+}
+
+impl Emitter {
+    /// The color of a particle `progress` of the way through its life.
+    pub fn color_at(&self, progress: f32) -> [f32; 4] {
+        match self.colors.len() {
+            0 => Color::WHITE.to_linear(),
+            1 => self.colors[0].to_linear(),
+            count => {
+                let position = progress.clamp(0.0, 1.0) * (count - 1) as f32;
+                let index = (position as usize).min(count - 2);
+                let t = position - index as f32;
+
+                let from = self.colors[index].to_linear();
+                let to = self.colors[index + 1].to_linear();
+                [0, 1, 2, 3].map(|i| from[i] + (to[i] - from[i]) * t)
+            }
+        }
+    }
+
+    fn is_spawning(&self, age: f32) -> bool {
+        self.duration.is_none_or(|duration| age < duration)
+    }
+}
+
+impl Default for Emitter {
+    fn default() -> Self {
+        Self {
+            position: Point::new(0.0, 0.0),
+            burst: 0,
+            rate: 10.0,
+            duration: None,
+            lifetime: 1.0,
+            velocity: Point::new(0.0, -32.0),
+            spread: 0.0,
+            speed_variance: 0.0,
+            acceleration: Point::new(0.0, 0.0),
+            colors: vec![Color::WHITE],
+            code: '*',
+            scale: 1.0,
+            align_to_velocity: false,
+        }
+    }
+}
+
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EmitterId(u32);
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Particle {
+    pub emitter: EmitterId,
+    pub position: Point<f32>,
+    pub velocity: Point<f32>,
+    pub age: f32,
+}
+
+
+#[derive(Debug, Clone)]
+struct EmitterState {
+    emitter: Emitter,
+    age: f32,
+    // Fraction of a particle owed from previous steps
+    pending: f32,
+    burst_done: bool,
+    removed: bool,
+    live: usize,
+}
+
+impl EmitterState {
+    fn is_finished(&self) -> bool {
+        let spawning = !self.removed
+            && (!self.burst_done
+                || (self.emitter.rate > 0.0 && self.emitter.is_spawning(self.age)));
+        !spawning && self.live == 0
+    }
+}
+
+
+/// Particles simulated on the CPU from the terminal clock, so pausing or
+/// setting the time affects them like every other animation.
+#[derive(Debug, Clone)]
+pub struct ParticleSystem {
+    emitters: BTreeMap<EmitterId, EmitterState>,
+    particles: Vec<Particle>,
+    next_id: u32,
+    time: Option<f32>,
+    seed: u64,
+}
+
+impl ParticleSystem {
+    pub fn new() -> Self {
+        Self {
+            emitters: BTreeMap::new(),
+            particles: Vec::new(),
+            next_id: 0,
+            time: None,
+            seed: 0x9E37_79B9_7F4A_7C15,
+        }
+    }
+
+    pub fn add_emitter(&mut self, emitter: Emitter) -> EmitterId {
+        let id = EmitterId(self.next_id);
+        self.next_id += 1;
+        self.emitters.insert(id, EmitterState {
+            emitter,
+            age: 0.0,
+            pending: 0.0,
+            burst_done: false,
+            removed: false,
+            live: 0,
+        });
+        id
+    }
+
+    /// Stop `id` from spawning. Its particles live out their lifetime.
+    pub fn remove_emitter(&mut self, id: EmitterId) {
+        if let Some(state) = self.emitters.get_mut(&id) {
+            state.removed = true;
+        }
+    }
+
+    /// The emitter behind `id`, to move it or change its settings.
+    pub fn emitter_mut(&mut self, id: EmitterId) -> Option<&mut Emitter> {
+        self.emitters
+            .get_mut(&id)
+            .filter(|state| !state.removed)
+            .map(|state| &mut state.emitter)
+    }
+
+    pub fn emitter(&self, id: EmitterId) -> Option<&Emitter> {
+        self.emitters.get(&id).map(|state| &state.emitter)
+    }
+
+    /// Every emitter still spawning or with particles alive.
+    pub fn emitters(&self) -> impl Iterator<Item = &Emitter> {
+        self.emitters.values().map(|state| &state.emitter)
+    }
+
+    /// Remove every emitter and particle at once.
+    pub fn clear(&mut self) {
+        self.emitters.clear();
+        self.particles.clear();
+    }
+
+    /// Whether anything is left to simulate or draw.
+    pub fn is_active(&self) -> bool {
+        !self.emitters.is_empty()
+    }
+
+    pub fn particles(&self) -> &[Particle] {
+        &self.particles
+    }
+
+    /// Advance the simulation to clock `time`, in seconds.
+    pub fn step(&mut self, time: f32) {
+        let dt = match self.time {
+            Some(last) => (time - last).clamp(0.0, MAX_STEP),
+            None => 0.0,
+        };
+        self.time = Some(time);
+
+        let emitters = &mut self.emitters;
+        self.particles.retain_mut(|particle| {
+            let state = emitters.get_mut(&particle.emitter).unwrap();
+            let emitter = &state.emitter;
+
+            particle.age += dt;
+            if particle.age >= emitter.lifetime {
+                state.live -= 1;
+                return false;
+            }
+
+            particle.velocity.x += emitter.acceleration.x * dt;
+            particle.velocity.y += emitter.acceleration.y * dt;
+            particle.position.x += particle.velocity.x * dt;
+            particle.position.y += particle.velocity.y * dt;
+            true
+        });
+
+        let ids: Vec<EmitterId> = self.emitters.keys().copied().collect();
+        for id in ids {
+            let state = self.emitters.get_mut(&id).unwrap();
+            let mut count = 0;
+
+            // Emitters start at the step that first sees them, however long
+            // ago the previous one was
+            let dt = if state.burst_done { dt } else { 0.0 };
+
+            if !state.removed {
+                if !state.burst_done {
+                    count += state.emitter.burst;
+                    state.burst_done = true;
+                }
+
+                if state.emitter.is_spawning(state.age) {
+                    state.pending += state.emitter.rate * dt;
+                    let whole = state.pending.floor();
+                    state.pending -= whole;
+                    count += whole as u32;
+                }
+            }
+            state.age += dt;
+
+            for _ in 0..count {
+                self.spawn(id);
+            }
+        }
+
+        self.emitters.retain(|_, state| !state.is_finished());
+    }
+
+    fn spawn(&mut self, id: EmitterId) {
+        let angle_jitter = self.random() * 2.0 - 1.0;
+        let speed_jitter = self.random() * 2.0 - 1.0;
+
+        let state = self.emitters.get_mut(&id).unwrap();
+        let emitter = &state.emitter;
+
+        let velocity = emitter.velocity;
+        let speed = (velocity.x * velocity.x + velocity.y * velocity.y).sqrt()
+            * (1.0 + speed_jitter * emitter.speed_variance);
+        let angle = velocity.y.atan2(velocity.x) + angle_jitter * emitter.spread;
+
+        self.particles.push(Particle {
+            emitter: id,
+            position: emitter.position,
+            velocity: Point::new(angle.cos() * speed, angle.sin() * speed),
+            age: 0.0,
+        });
+        state.live += 1;
+    }
+
+    /// A number in `[0, 1)` from a xorshift generator. Seeded the same every
+    /// run, so effects replay identically with a paused or set clock.
+    fn random(&mut self) -> f32 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        (self.seed >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+impl Default for ParticleSystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::color::Color;
+    use crate::particles::{Emitter, ParticleSystem};
+    use crate::point::Point;
+
+    #[test]
+    fn test_rate_and_lifetime() {
+        let mut system = ParticleSystem::new();
+        system.add_emitter(Emitter {
+            rate: 8.0,
+            lifetime: 0.5,
+            ..Emitter::default()
+        });
+
+        system.step(0.0);
+        for i in 1..=8 {
+            system.step(i as f32 * 0.125);
+        }
+
+        // Eight per second, each living half a second
+        assert_eq!(system.particles().len(), 4);
+    }
+
+    #[test]
+    fn test_burst_dies_out() {
+        let mut system = ParticleSystem::new();
+        system.add_emitter(Emitter {
+            burst: 20,
+            rate: 0.0,
+            lifetime: 0.2,
+            velocity: Point::new(10.0, 0.0),
+            acceleration: Point::new(0.0, 100.0),
+            ..Emitter::default()
+        });
+
+        system.step(0.0);
+        assert_eq!(system.particles().len(), 20);
+
+        system.step(0.1);
+        let particle = &system.particles()[0];
+        assert!((particle.position.x - 1.0).abs() < 1e-4);
+        assert!(particle.position.y > 0.0);
+
+        system.step(0.3);
+        assert!(system.particles().is_empty());
+        assert!(!system.is_active());
+    }
+
+    #[test]
+    fn test_removed_emitter_lets_particles_finish() {
+        let mut system = ParticleSystem::new();
+        let id = system.add_emitter(Emitter { burst: 3, ..Emitter::default() });
+
+        system.step(0.0);
+        system.remove_emitter(id);
+        system.step(0.1);
+        assert_eq!(system.particles().len(), 3);
+        assert!(system.emitter_mut(id).is_none());
+
+        system.step(0.3);
+        system.step(0.55);
+        system.step(0.8);
+        system.step(1.05);
+        assert!(!system.is_active());
+    }
+
+    #[test]
+    fn test_color_ramp() {
+        let emitter = Emitter {
+            colors: vec![Color::WHITE, Color::TRANSPARENT],
+            ..Emitter::default()
+        };
+
+        assert_eq!(emitter.color_at(0.0), [1.0, 1.0, 1.0, 1.0]);
+        assert_eq!(emitter.color_at(0.5), [0.5, 0.5, 0.5, 0.5]);
+        assert_eq!(emitter.color_at(1.0), [0.0, 0.0, 0.0, 0.0]);
+    }
+}
@@ -133,6 +133,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::bool_comparison)]
     fn test_equality() {
         let p1 = Point { x: 10, y: 10 };
         let p2 = Point { x: 10, y: 10 };
@@ -0,0 +1,432 @@
+use std::collections::BTreeMap;
+
+use crate::color::Color;
+use crate::point::Point;
+use crate::size::Size;
+
+
+/// Flip, rotation and scale applied to a tile when it is drawn. Scaling and
+/// rotation pivot on the tile's alignment anchor.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Transform {
+    pub flip_x: bool,
+    pub flip_y: bool,
+    /// Clockwise rotation in radians.
+    pub rotation: f32,
+    pub scale: f32,
+}
+
+impl Transform {
+    pub const IDENTITY: Transform = Transform {
+        flip_x: false,
+        flip_y: false,
+        rotation: 0.0,
+        scale: 1.0,
+    };
+
+    /// Rotate clockwise by `turns` multiples of 90 degrees.
+    pub fn quarter_turns(turns: i32) -> Self {
+        Self {
+            rotation: turns.rem_euclid(4) as f32 * std::f32::consts::FRAC_PI_2,
+            ..Self::IDENTITY
+        }
+    }
+
+    pub fn flipped_x(self) -> Self {
+        Self { flip_x: !self.flip_x, ..self }
+    }
+
+    pub fn flipped_y(self) -> Self {
+        Self { flip_y: !self.flip_y, ..self }
+    }
+
+    pub fn scaled(self, scale: f32) -> Self {
+        Self { scale: self.scale * scale, ..self }
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Cell {
+    pub code: char,
+    pub fore: Color,
+    pub back: Color,
+    /// Pixel offset of the tile from the cell's position.
+    pub offset: Point<i32>,
+    pub transform: Transform,
+}
+
+impl Cell {
+    pub fn is_empty(&self) -> bool {
+        self.code == ' ' && self.back.a == 0
+    }
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Self {
+            code: ' ',
+            fore: Color::WHITE,
+            back: Color::TRANSPARENT,
+            offset: Point::new(0, 0),
+            transform: Transform::IDENTITY,
+        }
+    }
+}
+
+
+/// A tile drawn at an arbitrary pixel position rather than in a cell.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Sprite {
+    pub code: char,
+    /// Top-left corner of the unrotated sprite, in pixels.
+    pub position: Point<f32>,
+    pub scale: f32,
+    /// Clockwise rotation about the sprite's center, in radians.
+    pub rotation: f32,
+    pub color: Color,
+    /// Draw order within the layer; higher is drawn later.
+    pub z: i32,
+}
+
+
+#[derive(Debug, Clone)]
+pub struct Layer {
+    cells: Vec<Cell>,
+    sprites: Vec<Sprite>,
+    dirty: bool,
+}
+
+impl Layer {
+    pub fn new(size: Size<i32>) -> Self {
+        Self {
+            cells: vec![Cell::default(); size.area() as usize],
+            sprites: Vec::new(),
+            dirty: true,
+        }
+    }
+
+    pub fn cells(&self) -> &[Cell] {
+        &self.cells
+    }
+
+    /// Sprites in the order they are drawn, sorted by `z`. Sprites with the
+    /// same `z` keep the order they were added in.
+    pub fn sprites(&self) -> &[Sprite] {
+        &self.sprites
+    }
+
+    /// Whether any cell changed since the scene was last marked clean.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    fn set(&mut self, index: usize, cell: Cell) {
+        if self.cells[index] != cell {
+            self.cells[index] = cell;
+            self.dirty = true;
+        }
+    }
+
+    fn add_sprite(&mut self, sprite: Sprite) {
+        let index = self.sprites.partition_point(|s| s.z <= sprite.z);
+        self.sprites.insert(index, sprite);
+        self.dirty = true;
+    }
+}
+
+
+/// The cell grid for every layer, addressed in cell coordinates.
+#[derive(Debug, Clone)]
+pub struct Scene {
+    size: Size<i32>,
+    layers: BTreeMap<u32, Layer>,
+    // Whether a layer was removed since the scene was last marked clean
+    dirty: bool,
+}
+
+impl Scene {
+    pub fn new(size: Size<i32>) -> Self {
+        Self {
+            size,
+            layers: BTreeMap::new(),
+            dirty: false,
+        }
+    }
+
+    pub fn size(&self) -> Size<i32> {
+        self.size
+    }
+
+    /// Layers in ascending order, which is also the order they are drawn in.
+    pub fn layers(&self) -> impl Iterator<Item = (u32, &Layer)> {
+        self.layers.iter().map(|(index, layer)| (*index, layer))
+    }
+
+    pub fn get(&self, layer: u32, position: Point<i32>) -> Option<&Cell> {
+        let index = self.index_of(position)?;
+        self.layers.get(&layer).map(|l| &l.cells[index])
+    }
+
+    pub fn put(&mut self, layer: u32, position: Point<i32>, cell: Cell) {
+        if let Some(index) = self.index_of(position) {
+            let size = self.size;
+            self.layers
+                .entry(layer)
+                .or_insert_with(|| Layer::new(size))
+                .set(index, cell);
+        }
+    }
+
+    pub fn add_sprite(&mut self, layer: u32, sprite: Sprite) {
+        let size = self.size;
+        self.layers
+            .entry(layer)
+            .or_insert_with(|| Layer::new(size))
+            .add_sprite(sprite);
+    }
+
+    /// Remove every layer. Redrawing the same layers with the same content
+    /// afterwards uploads nothing, as the renderer compares them with what
+    /// it already has.
+    pub fn clear(&mut self) {
+        self.dirty |= !self.layers.is_empty();
+        self.layers.clear();
+    }
+
+    pub fn clear_layer(&mut self, layer: u32) {
+        self.dirty |= self.layers.remove(&layer).is_some();
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty || self.layers.values().any(Layer::is_dirty)
+    }
+
+    /// Forget all changes, typically once they have been uploaded.
+    pub fn mark_clean(&mut self) {
+        self.dirty = false;
+        for layer in self.layers.values_mut() {
+            layer.dirty = false;
+        }
+    }
+
+    /// Make `front` a copy of this scene, carrying over which layers changed,
+    /// and start tracking changes here afresh. Edits made here afterwards
+    /// stay out of `front` until the next commit.
+    pub fn commit(&mut self, front: &mut Scene) {
+        front.clone_from(self);
+        self.mark_clean();
+    }
+
+    /// Resize the grid, keeping the cells that still fit.
+    pub fn resize(&mut self, size: Size<i32>) {
+        if size == self.size {
+            return;
+        }
+
+        let old_size = self.size;
+        for layer in self.layers.values_mut() {
+            let mut cells = vec![Cell::default(); size.area() as usize];
+            for y in 0..old_size.height.min(size.height) {
+                for x in 0..old_size.width.min(size.width) {
+                    cells[(y * size.width + x) as usize] =
+                        layer.cells[(y * old_size.width + x) as usize];
+                }
+            }
+            layer.cells = cells;
+            layer.dirty = true;
+        }
+        self.size = size;
+    }
+
+    pub fn index_of(&self, position: Point<i32>) -> Option<usize> {
+        if position.x < 0
+            || position.y < 0
+            || position.x >= self.size.width
+            || position.y >= self.size.height
+        {
+            return None;
+        }
+
+        Some((position.y * self.size.width + position.x) as usize)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::color::Color;
+    use crate::point::Point;
+    use crate::scene::{Cell, Scene, Sprite, Transform};
+    use crate::size::Size;
+
+    fn cell(code: char) -> Cell {
+        Cell { code, ..Default::default() }
+    }
+
+    #[test]
+    fn test_put_and_get() {
+        let mut scene = Scene::new(Size::new(4, 3));
+        scene.put(1, Point::new(2, 1), cell('@'));
+
+        assert_eq!(scene.get(1, Point::new(2, 1)).unwrap().code, '@');
+        assert!(scene.get(0, Point::new(2, 1)).is_none());
+    }
+
+    #[test]
+    fn test_put_out_of_bounds() {
+        let mut scene = Scene::new(Size::new(4, 3));
+        scene.put(0, Point::new(4, 0), cell('@'));
+        scene.put(0, Point::new(-1, 0), cell('@'));
+
+        assert_eq!(scene.layers().count(), 0);
+    }
+
+    #[test]
+    fn test_resize_keeps_cells() {
+        let mut scene = Scene::new(Size::new(4, 3));
+        scene.put(0, Point::new(1, 1), cell('a'));
+        scene.put(0, Point::new(3, 2), cell('b'));
+        scene.resize(Size::new(2, 5));
+
+        assert_eq!(scene.get(0, Point::new(1, 1)).unwrap().code, 'a');
+        assert_eq!(scene.get(0, Point::new(1, 4)).unwrap().code, ' ');
+    }
+
+    #[test]
+    fn test_dirty_tracking() {
+        let mut scene = Scene::new(Size::new(4, 3));
+        scene.put(0, Point::new(1, 1), cell('a'));
+        assert!(scene.is_dirty());
+
+        scene.mark_clean();
+        scene.put(0, Point::new(1, 1), cell('a'));
+        assert!(!scene.is_dirty());
+
+        scene.put(0, Point::new(1, 1), cell('b'));
+        assert!(scene.is_dirty());
+    }
+
+    #[test]
+    fn test_edits_stay_hidden_until_commit() {
+        let mut back = Scene::new(Size::new(4, 3));
+        let mut front = back.clone();
+        back.put(0, Point::new(1, 1), cell('a'));
+
+        assert!(front.get(0, Point::new(1, 1)).is_none());
+        assert!(!front.is_dirty());
+
+        back.commit(&mut front);
+        assert_eq!(front.get(0, Point::new(1, 1)).unwrap().code, 'a');
+        assert!(front.is_dirty());
+        assert!(!back.is_dirty());
+
+        // The next frame is drawn in the back scene while the front one is
+        // shown, and redrawn, unchanged
+        front.mark_clean();
+        back.put(0, Point::new(1, 1), cell('b'));
+        back.put(1, Point::new(0, 0), cell('c'));
+        assert_eq!(front.get(0, Point::new(1, 1)).unwrap().code, 'a');
+        assert_eq!(front.layers().count(), 1);
+        assert!(!front.is_dirty());
+
+        back.commit(&mut front);
+        assert_eq!(front.get(0, Point::new(1, 1)).unwrap().code, 'b');
+        assert_eq!(front.layers().count(), 2);
+    }
+
+    #[test]
+    fn test_commit_carries_only_new_changes() {
+        let mut back = Scene::new(Size::new(4, 3));
+        let mut front = back.clone();
+        back.put(0, Point::new(0, 0), cell('a'));
+        back.put(1, Point::new(0, 0), cell('b'));
+        back.commit(&mut front);
+        front.mark_clean();
+
+        back.put(1, Point::new(2, 2), cell('c'));
+        back.commit(&mut front);
+        let dirty: Vec<u32> = front.layers()
+            .filter(|(_, layer)| layer.is_dirty())
+            .map(|(index, _)| index)
+            .collect();
+        assert_eq!(dirty, vec![1]);
+
+        // Committing again without edits changes nothing
+        front.mark_clean();
+        back.commit(&mut front);
+        assert!(!front.is_dirty());
+
+        back.clear_layer(0);
+        back.commit(&mut front);
+        assert!(front.is_dirty());
+        assert!(front.get(0, Point::new(0, 0)).is_none());
+    }
+
+    #[test]
+    fn test_clear_removes_layers() {
+        let mut scene = Scene::new(Size::new(4, 3));
+        scene.put(1, Point::new(0, 0), cell('a'));
+        scene.put(2, Point::new(0, 0), cell('b'));
+        scene.mark_clean();
+
+        scene.clear_layer(1);
+        assert!(scene.is_dirty());
+        assert_eq!(scene.layers().count(), 1);
+        assert!(scene.get(1, Point::new(0, 0)).is_none());
+
+        scene.mark_clean();
+        scene.clear();
+        assert!(scene.is_dirty());
+        assert_eq!(scene.layers().count(), 0);
+
+        scene.mark_clean();
+        scene.clear();
+        assert!(!scene.is_dirty());
+    }
+
+    #[test]
+    fn test_sprites_sorted_by_z() {
+        let sprite = |code, z| Sprite {
+            code,
+            position: Point::new(0.0, 0.0),
+            scale: 1.0,
+            rotation: 0.0,
+            color: Color::WHITE,
+            z,
+        };
+
+        let mut scene = Scene::new(Size::new(4, 3));
+        scene.add_sprite(0, sprite('a', 2));
+        scene.add_sprite(0, sprite('b', -1));
+        scene.add_sprite(0, sprite('c', 2));
+        scene.add_sprite(0, sprite('d', 0));
+
+        let (_, layer) = scene.layers().next().unwrap();
+        let codes: String = layer.sprites().iter().map(|s| s.code).collect();
+        assert_eq!(codes, "bdac");
+
+        scene.clear();
+        assert!(scene.layers().next().is_none());
+    }
+
+    #[test]
+    fn test_quarter_turns_wrap() {
+        let half_pi = std::f32::consts::FRAC_PI_2;
+        assert_eq!(Transform::quarter_turns(1).rotation, half_pi);
+        assert_eq!(Transform::quarter_turns(5).rotation, half_pi);
+        assert_eq!(Transform::quarter_turns(-1).rotation, 3.0 * half_pi);
+    }
+
+    #[test]
+    fn test_empty_cell() {
+        assert!(Cell::default().is_empty());
+        assert!(!Cell { back: Color::BLACK, ..Default::default() }.is_empty());
+    }
+}
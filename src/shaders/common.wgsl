@@ -0,0 +1,137 @@
+struct Globals {
+    viewport: vec2<f32>,
+    cell_size: vec2<f32>,
+    time: f32,
+    // Blink period of the cursor in seconds, 0 when it does not blink
+    cursor_blink: f32,
+    // The time the cursor last moved, where its blink restarts
+    cursor_epoch: f32,
+};
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+};
+
+struct InstanceInput {
+    @location(2) position: vec2<f32>,
+    @location(3) size: vec2<f32>,
+    @location(4) anchor: vec2<f32>,
+    @location(5) scale: f32,
+    @location(6) rotation: f32,
+    @location(7) flags: u32,
+    @location(8) tex_rect: vec4<f32>,
+    @location(9) fore: vec4<f32>,
+    @location(10) back: vec4<f32>,
+    @location(11) animation: u32,
+    @location(12) page: u32,
+};
+
+struct AnimationFrame {
+    tex_rect: vec4<f32>,
+    end: f32,
+    page: u32,
+};
+
+const FLIP_X: u32 = 1u;
+const FLIP_Y: u32 = 2u;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+    @location(1) fore: vec4<f32>,
+    @location(2) back: vec4<f32>,
+    @location(3) local: vec2<f32>,
+    // The atlas page tex_coords are on
+    @location(4) @interpolate(flat) page: u32,
+};
+
+// Every atlas page, one per layer
+@group(0) @binding(0)
+var t_diffuse: texture_2d_array<f32>;
+
+@group(0) @binding(1)
+var s_diffuse: sampler;
+
+@group(1) @binding(0)
+var<uniform> globals: Globals;
+
+// One texel per animation, (first texel of its frames, frame count,
+// duration, 0), then two per frame, its tex_rect and (end, page, 0, 0)
+@group(1) @binding(1)
+var animation_table: texture_2d<f32>;
+
+const ANIMATION_TABLE_WIDTH: u32 = 256u;
+
+// The instance's tile at `in.tex_coords`, on whichever page it is
+fn sample_atlas(in: VertexOutput) -> vec4<f32> {
+    return textureSample(t_diffuse, s_diffuse, in.tex_coords, i32(in.page));
+}
+
+fn table_texel(index: u32) -> vec4<f32> {
+    let texel = vec2<i32>(
+        i32(index % ANIMATION_TABLE_WIDTH),
+        i32(index / ANIMATION_TABLE_WIDTH),
+    );
+    return textureLoad(animation_table, texel, 0);
+}
+
+fn current_frame(instance: InstanceInput) -> AnimationFrame {
+    if instance.animation == 0u {
+        return AnimationFrame(instance.tex_rect, 0.0, instance.page);
+    }
+
+    let animation = table_texel(instance.animation - 1u);
+    let first = u32(animation.x);
+    let count = u32(animation.y);
+    let t = globals.time % max(animation.z, 0.0001);
+
+    var i = 0u;
+    loop {
+        let last = i + 1u >= count;
+        if last || t < table_texel(first + i * 2u + 1u).x {
+            break;
+        }
+        i += 1u;
+    }
+
+    let timing = table_texel(first + i * 2u + 1u);
+    return AnimationFrame(table_texel(first + i * 2u), timing.x, u32(timing.y));
+}
+
+@vertex
+fn vs_main(model: VertexInput, instance: InstanceInput) -> VertexOutput {
+    var out: VertexOutput;
+    let frame = current_frame(instance);
+
+    // Scale and rotate about the anchor; y points down, so positive angles
+    // turn clockwise on screen
+    let pivot = instance.anchor * instance.size;
+    let corner = (model.position.xy * instance.size - pivot) * instance.scale;
+    let c = cos(instance.rotation);
+    let s = sin(instance.rotation);
+    let rotated = vec2<f32>(
+        corner.x * c - corner.y * s,
+        corner.x * s + corner.y * c,
+    );
+
+    let pixel = instance.position + pivot + rotated;
+    let ndc = pixel / globals.viewport * 2.0 - 1.0;
+
+    out.clip_position = vec4<f32>(ndc.x, -ndc.y, 0.0, 1.0);
+    var uv = model.tex_coords;
+    if (instance.flags & FLIP_X) != 0u {
+        uv.x = 1.0 - uv.x;
+    }
+    if (instance.flags & FLIP_Y) != 0u {
+        uv.y = 1.0 - uv.y;
+    }
+
+    out.tex_coords = mix(frame.tex_rect.xy, frame.tex_rect.zw, uv);
+    out.fore = instance.fore;
+    out.back = instance.back;
+    out.local = model.tex_coords;
+    out.page = frame.page;
+
+    return out;
+}
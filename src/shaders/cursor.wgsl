@@ -0,0 +1,13 @@
+// Solid cursor in the instance's fore color, shown for the first half of
+// each blink period counted from when it last moved
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    if globals.cursor_blink > 0.0 {
+        let elapsed = max(globals.time - globals.cursor_epoch, 0.0);
+        if fract(elapsed / globals.cursor_blink) >= 0.5 {
+            discard;
+        }
+    }
+
+    return vec4<f32>(in.fore.rgb * in.fore.a, in.fore.a);
+}
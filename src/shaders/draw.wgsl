@@ -1,18 +1,62 @@
+// Particles: one atlas tile per particle, centered on its position and
+// optionally turned to face its velocity. Also draws the atlas overlay,
+// whose solid quads sample nothing.
+
+const ALIGN_TO_VELOCITY: u32 = 1u;
+const SOLID: u32 = 2u;
+
+struct ParticleInput {
+    @location(2) particle_pos: vec2<f32>,
+    @location(3) particle_vel: vec2<f32>,
+    @location(4) size: vec2<f32>,
+    @location(5) tex_coords: vec4<f32>,
+    @location(6) color: vec4<f32>,
+    @location(7) flags: u32,
+    @location(8) page: u32,
+};
+
+struct ParticleOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+    @location(1) color: vec4<f32>,
+    @location(2) @interpolate(flat) flags: u32,
+    @location(3) @interpolate(flat) page: u32,
+};
+
 @vertex
-fn main_vs(
-    @location(0) particle_pos: vec2<f32>,
-    @location(1) particle_vel: vec2<f32>,
-    @location(2) position: vec2<f32>,
-) -> @builtin(position) vec4<f32> {
-    let angle = -atan2(particle_vel.x, particle_vel.y);
+fn main_vs(model: VertexInput, particle: ParticleInput) -> ParticleOutput {
+    var out: ParticleOutput;
+
+    // Tiles point up at rest; y points down, so the angle that turns up
+    // toward the velocity is measured from -y
+    var angle = 0.0;
+    let moving = any(particle.particle_vel != vec2<f32>(0.0));
+    if (particle.flags & ALIGN_TO_VELOCITY) != 0u && moving {
+        angle = atan2(particle.particle_vel.x, -particle.particle_vel.y);
+    }
+
+    let corner = (model.position.xy - 0.5) * particle.size;
     let pos = vec2<f32>(
-        position.x * cos(angle) - position.y * sin(angle),
-        position.x * sin(angle) + position.y * cos(angle)
+        corner.x * cos(angle) - corner.y * sin(angle),
+        corner.x * sin(angle) + corner.y * cos(angle)
     );
-    return vec4<f32>(pos + particle_pos, 0.0, 1.0);
+
+    let ndc = (pos + particle.particle_pos) / globals.viewport * 2.0 - 1.0;
+    out.clip_position = vec4<f32>(ndc.x, -ndc.y, 0.0, 1.0);
+    out.tex_coords = mix(particle.tex_coords.xy, particle.tex_coords.zw, model.tex_coords);
+    out.color = particle.color;
+    out.flags = particle.flags;
+    out.page = particle.page;
+
+    return out;
 }
 
 @fragment
-fn main_fs() -> @location(0) vec4<f32> {
-    return vec4<f32>(1.0, 1.0, 1.0, 1.0);
-}
\ No newline at end of file
+fn main_fs(in: ParticleOutput) -> @location(0) vec4<f32> {
+    var texel = textureSample(t_diffuse, s_diffuse, in.tex_coords, i32(in.page));
+    if (in.flags & SOLID) != 0u {
+        texel = vec4<f32>(1.0);
+    }
+    let color = in.color * texel;
+    return vec4<f32>(color.rgb * color.a, color.a);
+}
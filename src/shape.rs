@@ -1,5 +1,6 @@
 
 pub trait Within<T> {
+    #[allow(clippy::needless_arbitrary_self_type)]
     fn within(self: &Self, other: T) -> bool;
 }
 
@@ -3,33 +3,81 @@ use winit::{
     window::Window,
     event::WindowEvent,
 };
-use bytemuck;
+
+use image::RgbaImage;
 
 use std::borrow::Cow;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+use crate::atlas::{Atlas, PageStats, TexCoords, TileAlignment, TileSource};
+use crate::cache::CacheKey;
+use crate::clock::Clock;
+use crate::config::{Backend, Config, VSync};
+use crate::error::Error;
+use crate::font::Font;
+use crate::glyphs;
+use crate::particles::ParticleSystem;
+use crate::point::Point;
+use crate::color::Color;
+use crate::cursor::Cursor;
+use crate::scene::{Cell, Layer, Scene, Sprite};
+use crate::size::Size;
+use crate::tileset::{TileLayout, Tileset};
+use crate::vertex::{
+    AnimationEntry,
+    AnimationFrame,
+    Globals,
+    Instance,
+    ParticleInstance,
+    Vertex,
+};
+use crate::texture::{TextureError, TextureFilter};
+
+
+/// Bindings, vertex stage and shared structs that every fragment shader,
+/// built-in or user-supplied, is compiled against.
+const SHADER_PRELUDE: &str = include_str!("shaders/common.wgsl");
+
+const DEFAULT_FRAGMENT_SHADER: &str = include_str!("shaders/shader.wgsl");
+
+const CURSOR_FRAGMENT_SHADER: &str = include_str!("shaders/cursor.wgsl");
+
+const PARTICLE_SHADER: &str = include_str!("shaders/draw.wgsl");
 
-use crate::vertex::Vertex;
-use crate::texture::Texture;
+// Texels per row of the animation table; matches the shader prelude
+const ANIMATION_TABLE_WIDTH: u32 = 256;
+
+// Atlas overlay layout, in pixels
+const OVERLAY_MARGIN: f32 = 16.0;
+const OVERLAY_BAR: f32 = 8.0;
 
 
 const VERTICES: &[Vertex] = &[
     // 0
     Vertex {
-        position: [-1.0, -1.0, 0.0],
+        position: [0.0, 1.0, 0.0],
         tex_coords: [0.0, 1.0]
     },
     // 1
     Vertex {
-        position: [-1.0, 1.0, 0.0],
+        position: [0.0, 0.0, 0.0],
         tex_coords: [0.0, 0.0]
     },
     // 2
     Vertex {
-        position: [1.0, 1.0, 0.0],
+        position: [1.0, 0.0, 0.0],
         tex_coords: [1.0, 0.0]
     },
     // 3
     Vertex {
-        position: [1.0, -1.0, 0.0],
+        position: [1.0, 1.0, 0.0],
         tex_coords: [1.0, 1.0]
     },
 ];
@@ -41,43 +89,84 @@ const INDICES: &[u16] = &[
 ];
 
 
-pub struct State {
-    pub window: Window,
-    pub size: winit::dpi::PhysicalSize<u32>,
+/// What the uncaptured error handler saw, for the next frame to act on.
+#[derive(Debug, Default)]
+struct DeviceStatus {
+    lost: AtomicBool,
+    // The first other error since the last frame, which render returns
+    error: Mutex<Option<String>>,
+}
+
+
+/// Everything that lives on the GPU. It is rebuilt from the state kept on the
+/// CPU when the device is lost.
+struct Gpu {
+    adapter_info: wgpu::AdapterInfo,
     surface: wgpu::Surface,
     device: wgpu::Device,
     queue: wgpu::Queue,
     config: wgpu::SurfaceConfiguration,
+    render_pipeline_layout: wgpu::PipelineLayout,
     render_pipeline: wgpu::RenderPipeline,
+    cursor_pipeline: wgpu::RenderPipeline,
+    particle_pipeline: wgpu::RenderPipeline,
+    layer_pipelines: HashMap<u32, wgpu::RenderPipeline>,
     vertex_buffer: wgpu::Buffer,
     index_buffer: wgpu::Buffer,
     num_indices: u32,
+    instance_buffer: wgpu::Buffer,
+    cursor_buffer: wgpu::Buffer,
+    particle_buffer: wgpu::Buffer,
+    globals_buffer: wgpu::Buffer,
+    globals_bind_group_layout: wgpu::BindGroupLayout,
+    globals_bind_group: wgpu::BindGroup,
+    animation_table: wgpu::Texture,
+    texture_bind_group_layout: wgpu::BindGroupLayout,
+    // Every atlas page, as the layers of one texture
     diffuse_bind_group: wgpu::BindGroup,
-    diffuse_texture: Texture,
 }
 
-impl State {
-    pub async fn new(window: Window) -> Self {
+impl Gpu {
+    /// Create the device and every GPU resource, restoring the atlas pages,
+    /// animation tables, layer shaders and instances in `retained`.
+    async fn new(
+        window: &Window,
+        backend: Backend,
+        vsync: VSync,
+        status: &Arc<DeviceStatus>,
+        atlas: &mut Atlas,
+        retained: Retained<'_>,
+    ) -> Result<Self, Error> {
         let size = window.inner_size();
 
         let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
-            backends: wgpu::Backends::all(),
+            backends: backend.backends(),
             dx12_shader_compiler: Default::default(),
         });
 
-        let surface = unsafe { instance.create_surface(&window) }.unwrap();
+        let surface = unsafe { instance.create_surface(window) }
+            .map_err(Error::CreateSurface)?;
 
         // ! Adapter
-        let adapter = instance
-            .request_adapter(
-                &wgpu::RequestAdapterOptions {
-                    power_preference: wgpu::PowerPreference::default(),
-                    compatible_surface: Some(&surface),
-                    force_fallback_adapter: false,
-                },
-            )
-            .await
-            .unwrap();
+        // Machines without a GPU only have the software adapter, which is
+        // never returned unless asked for explicitly
+        let mut adapter = None;
+        for &force_fallback_adapter in backend.fallback_attempts() {
+            adapter = instance
+                .request_adapter(
+                    &wgpu::RequestAdapterOptions {
+                        power_preference: wgpu::PowerPreference::default(),
+                        compatible_surface: Some(&surface),
+                        force_fallback_adapter,
+                    },
+                )
+                .await;
+
+            if adapter.is_some() {
+                break;
+            }
+        }
+        let adapter = adapter.ok_or(Error::NoAdapter)?;
 
         // ! Logical Device
         let (device, queue) = adapter
@@ -94,15 +183,33 @@ impl State {
                 None,  // trace path
             )
             .await
-            .unwrap();
+            .map_err(Error::RequestDevice)?;
+
+        // Losing the device surfaces as an uncaptured error; flag it so the
+        // next frame can rebuild everything. Any other error is kept for
+        // render to return instead of panicking
+        let handler_status = Arc::clone(status);
+        device.on_uncaptured_error(Box::new(move |error| {
+            if is_device_lost(&error) {
+                handler_status.lost.store(true, Ordering::SeqCst);
+            } else {
+                let mut slot = handler_status.error.lock().unwrap();
+                slot.get_or_insert_with(|| error.to_string());
+            }
+        }));
 
         // ! Surface Configuration
         let surface_caps = surface.get_capabilities(&adapter);
+        if surface_caps.formats.is_empty()
+            || surface_caps.present_modes.is_empty()
+            || surface_caps.alpha_modes.is_empty()
+        {
+            return Err(Error::UnsupportedSurfaceFormat);
+        }
 
         let surface_format = surface_caps.formats.iter()
             .copied()
-            .filter(|f| f.describe().srgb)
-            .next()
+            .find(|f| f.describe().srgb)
             .unwrap_or(surface_caps.formats[0]);
 
         let config = wgpu::SurfaceConfiguration {
@@ -110,21 +217,17 @@ impl State {
             format: surface_format,
             width: size.width,
             height: size.height,
-            present_mode: surface_caps.present_modes[0],
+            present_mode: vsync.choose_present_mode(&surface_caps.present_modes),
             alpha_mode: surface_caps.alpha_modes[0],
             view_formats: vec![],
         };
 
-        surface.configure(&device, &config);
+        if size.width > 0 && size.height > 0 {
+            surface.configure(&device, &config);
+        }
 
         // ! Texture Loading
-        let diffuse_bytes = include_bytes!("images/yatta.png");
-        let diffuse_texture = Texture::from_bytes(
-            &device,
-            &queue,
-            diffuse_bytes,
-            "yatta.png",
-        ).unwrap();
+        atlas.recreate(&device, &queue)?;
 
         let texture_bind_group_layout = device
             .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -135,7 +238,7 @@ impl State {
                         visibility: wgpu::ShaderStages::FRAGMENT,
                         ty: wgpu::BindingType::Texture {
                             multisampled: false,
-                            view_dimension: wgpu::TextureViewDimension::D2,
+                            view_dimension: wgpu::TextureViewDimension::D2Array,
                             sample_type: wgpu::TextureSampleType::Float {
                                 filterable: true,
                             },
@@ -153,102 +256,115 @@ impl State {
                 ],
             });
 
-        let diffuse_bind_group = device
-            .create_bind_group(&wgpu::BindGroupDescriptor {
-                label: Some("Diffuse Bind Group"),
-                layout: &texture_bind_group_layout,
+        let diffuse_bind_group = Self::create_texture_bind_group(
+            &device,
+            &texture_bind_group_layout,
+            atlas,
+        );
+
+        // ! Globals
+        let globals_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Globals Buffer"),
+                contents: bytemuck::bytes_of(&Globals::default()),
+                usage: (
+                    wgpu::BufferUsages::UNIFORM |
+                    wgpu::BufferUsages::COPY_DST
+                ),
+            }
+        );
+
+        let globals_bind_group_layout = device
+            .create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Globals Bind Group Layout"),
                 entries: &[
-                    wgpu::BindGroupEntry {
+                    wgpu::BindGroupLayoutEntry {
                         binding: 0,
-                        resource: wgpu::BindingResource::TextureView(
-                            &diffuse_texture.view,
+                        visibility: (
+                            wgpu::ShaderStages::VERTEX |
+                            wgpu::ShaderStages::FRAGMENT
                         ),
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
                     },
-                    wgpu::BindGroupEntry {
+                    wgpu::BindGroupLayoutEntry {
                         binding: 1,
-                        resource: wgpu::BindingResource::Sampler(
-                            &diffuse_texture.sampler,
-                        ),
+                        visibility: wgpu::ShaderStages::VERTEX,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
                     },
                 ],
             });
 
-        // ! Render Pipeline
-        let shader_resource = wgpu::ShaderSource::Wgsl(
-            Cow::Borrowed(include_str!("shaders/shader.wgsl"))
+        let animation_table = Self::create_animation_table(
+            &device,
+            &queue,
+            retained.animation_entries,
+            retained.animation_frames,
         );
 
-        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
-            label: Some("Shader"),
-            source: shader_resource,
-        });
+        let globals_bind_group = Self::create_globals_bind_group(
+            &device,
+            &globals_bind_group_layout,
+            &globals_buffer,
+            &animation_table,
+        );
 
+        // ! Render Pipeline
         let render_pipeline_layout = device
             .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("Render Pipeline Layout"),
                 bind_group_layouts: &[
                     &texture_bind_group_layout,
+                    &globals_bind_group_layout,
                 ],
                 push_constant_ranges: &[],
             });
 
-        let render_pipeline = device
-            .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-                label: Some(("Render Pipeline")),
-
-                // The layout of bind groups for this pipeline.
-                layout: Some(&render_pipeline_layout),
-
-                // The compiled vertex stage, its entry point, and the input
-                // buffers layout.
-                vertex: wgpu::VertexState {
-                    module: &shader,
-                    entry_point: "vs_main",
-                    buffers: &[
-                        Vertex::desc(),
-                    ],
-                },
-
-                // The compiled fragment stage, its entry point, and the color
-                // targets.
-                fragment: Some(wgpu::FragmentState {
-                    module: &shader,
-                    entry_point: "fs_main",
-                    targets: &[Some(wgpu::ColorTargetState {
-                        format: config.format,
-                        blend: Some(wgpu::BlendState::REPLACE),
-                        write_mask: wgpu::ColorWrites::ALL,
-                    })],
-                }),
-
-                // The properties of the pipeline at the primitive assembly
-                // and rasterization level.
-                primitive: wgpu::PrimitiveState {
-                    topology: wgpu::PrimitiveTopology::TriangleList,
-                    strip_index_format: None,
-                    front_face: wgpu::FrontFace::Ccw,
-                    cull_mode: Some(wgpu::Face::Back),
-                    // Setting this to anything other than Fill requires
-                    // Features::NON_FILL_POLYGON_MODE
-                    polygon_mode: wgpu::PolygonMode::Fill,
-                    // Requires Features::DEPTH_CLIP_CONTROL
-                    unclipped_depth: false,
-                    // Requires Features::CONSERVATIVE_RASTERIZATION
-                    conservative: false,
-                },
+        let render_pipeline = Self::create_pipeline(
+            &device,
+            &render_pipeline_layout,
+            config.format,
+            DEFAULT_FRAGMENT_SHADER,
+            "Render Pipeline",
+        );
 
-                // The effect of draw calls on the depth and stencil aspects of
-                // the output target, if any.
-                depth_stencil: None,
+        let cursor_pipeline = Self::create_pipeline(
+            &device,
+            &render_pipeline_layout,
+            config.format,
+            CURSOR_FRAGMENT_SHADER,
+            "Cursor Pipeline",
+        );
 
+        let particle_pipeline = Self::create_particle_pipeline(
+            &device,
+            &render_pipeline_layout,
+            config.format,
+        );
 
-                multisample: wgpu::MultisampleState {
-                    count: 1,
-                    mask: !0,  // Use all samples
-                    alpha_to_coverage_enabled: false,
-                },
-                multiview: None,
-            });
+        // Layer shaders were validated when they were first set
+        let layer_pipelines = retained.layer_shaders
+            .iter()
+            .map(|(layer, source)| {
+                let pipeline = Self::create_pipeline(
+                    &device,
+                    &render_pipeline_layout,
+                    config.format,
+                    source,
+                    &format!("Layer {} Pipeline", layer),
+                );
+                (*layer, pipeline)
+            })
+            .collect();
 
         // ! Buffers
         let vertex_buffer = device.create_buffer_init(
@@ -269,19 +385,492 @@ impl State {
             }
         );
 
-        Self {
-            window,
+        let instance_buffer = Self::create_instance_buffer(
+            &device,
+            retained.instance_capacity,
+        );
+        queue.write_buffer(
+            &instance_buffer,
+            0,
+            bytemuck::cast_slice(retained.instances),
+        );
+
+        let cursor_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Cursor Buffer"),
+                contents: bytemuck::bytes_of(&retained.cursor),
+                usage: (
+                    wgpu::BufferUsages::VERTEX |
+                    wgpu::BufferUsages::COPY_DST
+                ),
+            }
+        );
+
+        // Particles are rewritten every frame, so only the size is kept
+        let particle_buffer = Self::create_particle_buffer(
+            &device,
+            retained.particle_capacity,
+        );
+
+        Ok(Self {
+            adapter_info: adapter.get_info(),
             surface,
             device,
             queue,
             config,
-            size,
+            render_pipeline_layout,
             render_pipeline,
+            cursor_pipeline,
+            particle_pipeline,
+            layer_pipelines,
             vertex_buffer,
             index_buffer,
             num_indices,
+            instance_buffer,
+            cursor_buffer,
+            particle_buffer,
+            globals_buffer,
+            globals_bind_group_layout,
+            globals_bind_group,
+            animation_table,
+            texture_bind_group_layout,
             diffuse_bind_group,
-            diffuse_texture,
+        })
+    }
+
+    /// The animation and frame tables packed into a float texture, which
+    /// unlike storage buffers the vertex stage can read on every backend,
+    /// WebGL2 included. See [`pack_animation_table`] for the layout.
+    fn create_animation_table(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        entries: &[AnimationEntry],
+        frames: &[AnimationFrame],
+    ) -> wgpu::Texture {
+        let texels = pack_animation_table(entries, frames);
+        let rows = texels.len() as u32 / ANIMATION_TABLE_WIDTH;
+
+        device.create_texture_with_data(
+            queue,
+            &wgpu::TextureDescriptor {
+                label: Some("Animation Table"),
+                size: wgpu::Extent3d {
+                    width: ANIMATION_TABLE_WIDTH,
+                    height: rows,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba32Float,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING,
+                view_formats: &[],
+            },
+            bytemuck::cast_slice(&texels),
+        )
+    }
+
+    fn create_pipeline(
+        device: &wgpu::Device,
+        layout: &wgpu::PipelineLayout,
+        format: wgpu::TextureFormat,
+        fragment_source: &str,
+        label: &str,
+    ) -> wgpu::RenderPipeline {
+        Self::create_quad_pipeline(
+            device,
+            layout,
+            format,
+            fragment_source,
+            label,
+            ("vs_main", "fs_main"),
+            Instance::desc(),
+        )
+    }
+
+    fn create_particle_pipeline(
+        device: &wgpu::Device,
+        layout: &wgpu::PipelineLayout,
+        format: wgpu::TextureFormat,
+    ) -> wgpu::RenderPipeline {
+        Self::create_quad_pipeline(
+            device,
+            layout,
+            format,
+            PARTICLE_SHADER,
+            "Particle Pipeline",
+            ("main_vs", "main_fs"),
+            ParticleInstance::desc(),
+        )
+    }
+
+    /// A pipeline drawing the unit quad once per instance, from `source`
+    /// compiled against the shader prelude.
+    fn create_quad_pipeline(
+        device: &wgpu::Device,
+        layout: &wgpu::PipelineLayout,
+        format: wgpu::TextureFormat,
+        source: &str,
+        label: &str,
+        (vertex_entry, fragment_entry): (&str, &str),
+        instance_layout: wgpu::VertexBufferLayout,
+    ) -> wgpu::RenderPipeline {
+        let source = format!("{}\n{}", SHADER_PRELUDE, source);
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(label),
+            source: wgpu::ShaderSource::Wgsl(Cow::Owned(source)),
+        });
+
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some(label),
+
+            // The layout of bind groups for this pipeline.
+            layout: Some(layout),
+
+            // The compiled vertex stage, its entry point, and the input
+            // buffers layout.
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: vertex_entry,
+                buffers: &[
+                    Vertex::desc(),
+                    instance_layout,
+                ],
+            },
+
+            // The compiled fragment stage, its entry point, and the color
+            // targets.
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: fragment_entry,
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+
+            // The properties of the pipeline at the primitive assembly
+            // and rasterization level.
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                // Cells are laid out with y pointing down, which flips the
+                // winding; quads are never back-facing anyway.
+                cull_mode: None,
+                // Setting this to anything other than Fill requires
+                // Features::NON_FILL_POLYGON_MODE
+                polygon_mode: wgpu::PolygonMode::Fill,
+                // Requires Features::DEPTH_CLIP_CONTROL
+                unclipped_depth: false,
+                // Requires Features::CONSERVATIVE_RASTERIZATION
+                conservative: false,
+            },
+
+            // The effect of draw calls on the depth and stencil aspects of
+            // the output target, if any.
+            depth_stencil: None,
+
+
+            multisample: wgpu::MultisampleState {
+                count: 1,
+                mask: !0,  // Use all samples
+                alpha_to_coverage_enabled: false,
+            },
+            multiview: None,
+        })
+    }
+
+    fn create_particle_buffer(
+        device: &wgpu::Device,
+        capacity: usize,
+    ) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Particle Buffer"),
+            size: (capacity * std::mem::size_of::<ParticleInstance>())
+                as wgpu::BufferAddress,
+            usage: (
+                wgpu::BufferUsages::VERTEX |
+                wgpu::BufferUsages::COPY_DST
+            ),
+            mapped_at_creation: false,
+        })
+    }
+
+    fn create_texture_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        atlas: &Atlas,
+    ) -> wgpu::BindGroup {
+        let texture = atlas.texture().expect("the atlas is built with the device");
+
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Diffuse Bind Group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(
+                        &texture.view,
+                    ),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(
+                        &texture.sampler,
+                    ),
+                },
+            ],
+        })
+    }
+
+    fn create_globals_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        globals: &wgpu::Buffer,
+        animation_table: &wgpu::Texture,
+    ) -> wgpu::BindGroup {
+        let animation_view = animation_table.create_view(&wgpu::TextureViewDescriptor::default());
+
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Globals Bind Group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: globals.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&animation_view),
+                },
+            ],
+        })
+    }
+
+    fn create_instance_buffer(
+        device: &wgpu::Device,
+        capacity: usize,
+    ) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Instance Buffer"),
+            size: (capacity * std::mem::size_of::<Instance>())
+                as wgpu::BufferAddress,
+            usage: (
+                wgpu::BufferUsages::VERTEX |
+                wgpu::BufferUsages::COPY_DST
+            ),
+            mapped_at_creation: false,
+        })
+    }
+
+}
+
+
+/// The CPU-side data that GPU resources are restored from.
+struct Retained<'a> {
+    animation_entries: &'a [AnimationEntry],
+    animation_frames: &'a [AnimationFrame],
+    layer_shaders: &'a HashMap<u32, String>,
+    instances: &'a [Instance],
+    instance_capacity: usize,
+    particle_capacity: usize,
+    cursor: Instance,
+}
+
+
+pub struct State {
+    pub window: Window,
+    pub size: winit::dpi::PhysicalSize<u32>,
+    minimized: bool,
+    gpu: Gpu,
+    status: Arc<DeviceStatus>,
+    backend: Backend,
+    vsync: VSync,
+    layer_shaders: HashMap<u32, String>,
+    instance_capacity: usize,
+    instances: Vec<Instance>,
+    layer_order: Vec<u32>,
+    // Instances of non-empty cells per layer, then sprites per layer
+    cell_counts: Vec<usize>,
+    sprite_counts: Vec<usize>,
+    scene_size: Size<i32>,
+    batches: Vec<(u32, Range<u32>)>,
+    animation_ids: HashMap<char, u32>,
+    animation_entries: Vec<AnimationEntry>,
+    animation_frames: Vec<AnimationFrame>,
+    // The codes behind every cell instance, sprite and animation frame,
+    // counted as references in the atlas
+    cell_codes: Vec<char>,
+    sprite_codes: Vec<char>,
+    animation_codes: Vec<char>,
+    tile_layouts: Vec<TileLayout>,
+    has_animated_tiles: bool,
+    invalidated: bool,
+    atlas: Atlas,
+    // Where restore_atlas keeps the atlas between runs
+    atlas_cache: Option<PathBuf>,
+    cell_size: Size<i32>,
+    fps_cap: Option<u32>,
+    clock: Clock,
+    cursor: Cursor,
+    cursor_epoch: f32,
+    particles: ParticleSystem,
+    particle_capacity: usize,
+    particle_count: u32,
+    atlas_overlay: bool,
+}
+
+impl State {
+    pub async fn new(window: Window, settings: &Config) -> Result<Self, Error> {
+        settings.validate()?;
+
+        let size = window.inner_size();
+        let status = Arc::new(DeviceStatus::default());
+        let layer_shaders = HashMap::new();
+        let instance_capacity = 1;
+        let mut atlas = Atlas::new(
+            settings.texture_filter,
+            settings.tile_padding,
+            settings.tile_extrusion,
+        );
+        atlas.extend_generated(glyphs::generate_all(settings.cell_size));
+
+        let gpu = Gpu::new(
+            &window,
+            settings.backend,
+            settings.vsync,
+            &status,
+            &mut atlas,
+            Retained {
+                animation_entries: &[],
+                animation_frames: &[],
+                layer_shaders: &layer_shaders,
+                instances: &[],
+                instance_capacity,
+                particle_capacity: 1,
+                cursor: Instance::default(),
+            },
+        ).await?;
+
+        Ok(Self {
+            window,
+            size,
+            minimized: size.width == 0 || size.height == 0,
+            gpu,
+            status,
+            backend: settings.backend,
+            vsync: settings.vsync,
+            layer_shaders,
+            instance_capacity,
+            instances: Vec::new(),
+            layer_order: Vec::new(),
+            cell_counts: Vec::new(),
+            sprite_counts: Vec::new(),
+            scene_size: Size::new(0, 0),
+            batches: Vec::new(),
+            animation_ids: HashMap::new(),
+            animation_entries: Vec::new(),
+            animation_frames: Vec::new(),
+            cell_codes: Vec::new(),
+            sprite_codes: Vec::new(),
+            animation_codes: Vec::new(),
+            tile_layouts: Vec::new(),
+            has_animated_tiles: false,
+            invalidated: false,
+            atlas,
+            atlas_cache: settings.atlas_cache.clone(),
+            cell_size: settings.cell_size,
+            fps_cap: settings.fps_cap,
+            clock: Clock::new(),
+            cursor: Cursor::default(),
+            cursor_epoch: 0.0,
+            particles: ParticleSystem::new(),
+            particle_capacity: 1,
+            particle_count: 0,
+            atlas_overlay: false,
+        })
+    }
+
+    /// Load the atlas from [`Config::atlas_cache`] if it was saved from the
+    /// same sources, or write it there if that is missing or stale. Returns
+    /// whether the cache was used. A cache that cannot be decoded counts as
+    /// stale; failing to read or write the file is an error.
+    pub fn restore_atlas(&mut self) -> Result<bool, Error> {
+        let Some(path) = self.atlas_cache.clone() else {
+            return Ok(false);
+        };
+        let key = atlas_cache_key(&self.atlas);
+        let loaded = match self.load_atlas_cache(&path, key) {
+            Ok(loaded) => loaded,
+            Err(error) if error.kind() == io::ErrorKind::InvalidData => false,
+            Err(error) => return Err(Error::Io(path, error)),
+        };
+
+        if !loaded {
+            self.atlas.save_cache(&path, key).map_err(|error| Error::Io(path, error))?;
+        }
+        Ok(loaded)
+    }
+
+    /// Replace the atlas with the cache at `path` if it was saved under
+    /// `key`. Tiles may have moved, so instances are rebuilt on the next
+    /// prepare.
+    pub fn load_atlas_cache(&mut self, path: &Path, key: u64) -> io::Result<bool> {
+        let loaded = self.atlas.load_cache(&self.gpu.device, &self.gpu.queue, path, key)?;
+        if loaded {
+            self.rebuild_texture_bind_groups();
+            self.invalidated = true;
+        }
+        Ok(loaded)
+    }
+
+    pub fn save_atlas_cache(&self, path: &Path, key: u64) -> io::Result<()> {
+        self.atlas.save_cache(path, key)
+    }
+
+    /// Whether the GPU device was lost and [`State::recover`] has to run
+    /// before anything else can be drawn.
+    pub fn is_device_lost(&self) -> bool {
+        self.status.lost.load(Ordering::SeqCst)
+    }
+
+    /// Recreate the device and every GPU resource from the data kept on the
+    /// CPU: atlas pages, animation tables, layer shaders and instances. On
+    /// failure the device stays marked as lost.
+    pub async fn recover(&mut self) -> Result<(), Error> {
+        self.status.lost.store(false, Ordering::SeqCst);
+        self.status.error.lock().unwrap().take();
+
+        let cursor = self.cursor_instance();
+        let gpu = Gpu::new(
+            &self.window,
+            self.backend,
+            self.vsync,
+            &self.status,
+            &mut self.atlas,
+            Retained {
+                animation_entries: &self.animation_entries,
+                animation_frames: &self.animation_frames,
+                layer_shaders: &self.layer_shaders,
+                instances: &self.instances,
+                instance_capacity: self.instance_capacity,
+                particle_capacity: self.particle_capacity,
+                cursor,
+            },
+        ).await;
+
+        match gpu {
+            Ok(gpu) => {
+                self.gpu = gpu;
+                Ok(())
+            }
+            Err(error) => {
+                self.status.lost.store(true, Ordering::SeqCst);
+                Err(error)
+            }
         }
     }
 
@@ -289,35 +878,768 @@ impl State {
         &self.window
     }
 
+    pub fn cell_size(&self) -> Size<i32> {
+        self.cell_size
+    }
+
+    /// Draw cells `cell_size` big from now on, with the generated glyphs
+    /// redrawn to match. The caller resizes its scenes to the new grid.
+    /// Fails if a glyph does not fit in the atlas; the cell size is changed
+    /// all the same.
+    pub fn set_cell_size(&mut self, cell_size: Size<i32>) -> Result<(), TextureError> {
+        self.cell_size = cell_size;
+        let glyphs = glyphs::generate_all(cell_size);
+        let changed = self.atlas.set_generated(&self.gpu.device, &self.gpu.queue, glyphs);
+        let changed = self.refresh_pages(changed);
+        self.gpu.queue.write_buffer(
+            &self.gpu.cursor_buffer,
+            0,
+            bytemuck::bytes_of(&self.cursor_instance()),
+        );
+        self.invalidated = true;
+        changed.map(|_| ())
+    }
+
+    /// The present mode in use, which may differ from the one requested if
+    /// the surface does not support it.
+    pub fn present_mode(&self) -> wgpu::PresentMode {
+        self.gpu.config.present_mode
+    }
+
+    /// The adapter drawing the window, including its name and backend.
+    pub fn adapter_info(&self) -> &wgpu::AdapterInfo {
+        &self.gpu.adapter_info
+    }
+
+    pub fn fps_cap(&self) -> Option<u32> {
+        self.fps_cap
+    }
+
+    pub fn texture_filter(&self) -> TextureFilter {
+        self.atlas.filter()
+    }
+
+    /// Change how the atlas is sampled, rebuilding its sampler and bind
+    /// groups. Tile coordinates are inset to suit the filter, so instances
+    /// and animation tables have to be rebuilt.
+    pub fn set_texture_filter(&mut self, filter: TextureFilter) {
+        self.atlas.apply_texture_filter(&self.gpu.device, &self.gpu.queue, filter);
+        self.rebuild_texture_bind_groups();
+        self.invalidated = true;
+    }
+
+    /// Replace the tile sheet with the image at `path`. On error the current
+    /// sheet is kept. Tiles may have moved in the atlas, so instances and
+    /// animation tables have to be rebuilt.
+    pub fn load_texture(&mut self, path: &Path) -> Result<(), TextureError> {
+        self.atlas.load_path(&self.gpu.device, &self.gpu.queue, path)?;
+        self.rebuild_texture_bind_groups();
+        self.invalidated = true;
+        Ok(())
+    }
+
+    /// Replace the tile sheet with an encoded image held in memory, like
+    /// [`State::load_texture`].
+    pub fn load_texture_bytes(
+        &mut self,
+        bytes: &[u8],
+        label: &str,
+    ) -> Result<(), TextureError> {
+        self.atlas.load_bytes(&self.gpu.device, &self.gpu.queue, bytes, label)?;
+        self.rebuild_texture_bind_groups();
+        self.invalidated = true;
+        Ok(())
+    }
+
+    /// Recreate the bind groups unless `changed` says the pages are as they
+    /// were. A failed placement may still have grown or added pages.
+    fn refresh_pages(&mut self, changed: Result<bool, TextureError>) -> Result<bool, TextureError> {
+        if !matches!(changed, Ok(false)) {
+            self.rebuild_texture_bind_groups();
+        }
+        changed
+    }
+
+    fn rebuild_texture_bind_groups(&mut self) {
+        self.gpu.diffuse_bind_group = Gpu::create_texture_bind_group(
+            &self.gpu.device,
+            &self.gpu.texture_bind_group_layout,
+            &self.atlas,
+        );
+    }
+
+    /// The number of whole cells that fit in the window.
+    pub fn grid_size(&self) -> Size<i32> {
+        Size {
+            width: (self.size.width as i32 / self.cell_size.width).max(1),
+            height: (self.size.height as i32 / self.cell_size.height).max(1),
+        }
+    }
+
+    /// A minimized window reports a zero size. The surface cannot be
+    /// configured that way, so it keeps its last size and nothing is drawn
+    /// until the window is restored.
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
-        if new_size.width > 0 && new_size.height > 0 {
+        self.minimized = new_size.width == 0 || new_size.height == 0;
+        if !self.minimized {
             self.size = new_size;
-            self.config.width = new_size.width;
-            self.config.height = new_size.height;
-            self.surface.configure(&self.device, &self.config);
+            self.gpu.config.width = new_size.width;
+            self.gpu.config.height = new_size.height;
+            self.reconfigure();
+        }
+    }
+
+    /// Configure the surface again with the current settings, which is how
+    /// a lost or outdated surface is brought back.
+    pub fn reconfigure(&mut self) {
+        if !self.minimized {
+            self.gpu.surface.configure(&self.gpu.device, &self.gpu.config);
+        }
+    }
+
+    pub fn is_minimized(&self) -> bool {
+        self.minimized
+    }
+
+    /// Compile `fragment_source` against the standard shader prelude and use
+    /// it to draw `layer`. The source must define
+    /// `fn fs_main(in: VertexOutput) -> @location(0) vec4<f32>`, and reads
+    /// the cell's tile with `sample_atlas(in)`.
+    pub fn set_layer_shader(
+        &mut self,
+        layer: u32,
+        fragment_source: &str,
+    ) -> Result<(), Error> {
+        // Invalid WGSL would reach the uncaptured error handler, which
+        // panics, so check it with naga before wgpu sees it.
+        validate_layer_shader(layer, fragment_source)?;
+
+        let pipeline = Gpu::create_pipeline(
+            &self.gpu.device,
+            &self.gpu.render_pipeline_layout,
+            self.gpu.config.format,
+            fragment_source,
+            &format!("Layer {} Pipeline", layer),
+        );
+
+        self.gpu.layer_pipelines.insert(layer, pipeline);
+        self.layer_shaders.insert(layer, fragment_source.to_string());
+        Ok(())
+    }
+
+    pub fn remove_layer_shader(&mut self, layer: u32) {
+        self.gpu.layer_pipelines.remove(&layer);
+        self.layer_shaders.remove(&layer);
+    }
+
+    /// Bring the instance buffer in line with `scene`, re-uploading only what
+    /// changed. Every layer owns a run of instances for its non-empty cells,
+    /// in layer order. A layer whose number of non-empty cells stays the same
+    /// re-uploads the cells that changed; anything that moves the runs, like
+    /// a change in the set of layers or in the grid size, re-uploads
+    /// everything. The sprites of all layers follow the cells and are rebuilt
+    /// together whenever any of them changes. Returns whether anything
+    /// changed.
+    pub fn prepare(&mut self, scene: &Scene) -> bool {
+        let size = scene.size();
+        let cell_size = self.cell_size;
+        let layer_order: Vec<u32> = scene.layers().map(|(i, _)| i).collect();
+
+        let atlas = &self.atlas;
+        let animation_ids = &self.animation_ids;
+        let layouts = &self.tile_layouts;
+        let layer_instances = |layer: &Layer| -> Vec<Instance> {
+            layer.cells()
+                .iter()
+                .enumerate()
+                .filter(|(_, cell)| !cell.is_empty())
+                .map(|(i, cell)| {
+                    Self::cell_instance(size, cell_size, atlas, layouts, animation_ids, i, cell)
+                })
+                .collect()
+        };
+
+        let mut full = self.invalidated
+            || size != self.scene_size
+            || layer_order != self.layer_order;
+        let mut changed = full;
+        self.invalidated = false;
+
+        if full {
+            let runs: Vec<Vec<Instance>> = scene.layers()
+                .map(|(_, layer)| layer_instances(layer))
+                .collect();
+            self.cell_counts = runs.iter().map(Vec::len).collect();
+            self.instances = runs.into_iter().flatten().collect();
+
+            self.scene_size = size;
+            self.layer_order = layer_order;
+        } else {
+            let mut base = 0;
+            for (slot, (_, layer)) in scene.layers().enumerate() {
+                let count = self.cell_counts[slot];
+                if layer.is_dirty() {
+                    let next = layer_instances(layer);
+
+                    if next.len() == count {
+                        let spans = diff_instances(
+                            &mut self.instances[base..base + count],
+                            &next,
+                        );
+                        for span in spans {
+                            self.write_instances(base + span.start..base + span.end);
+                            changed = true;
+                        }
+                    } else {
+                        // The runs after this one move, so upload them all
+                        self.cell_counts[slot] = next.len();
+                        self.instances.splice(base..base + count, next);
+                        full = true;
+                        changed = true;
+                    }
+                }
+                base += self.cell_counts[slot];
+            }
+        }
+
+        let cell_count = self.cell_counts.iter().sum::<usize>();
+
+        if full || scene.is_dirty() {
+            let sprite_counts: Vec<usize> = scene.layers()
+                .map(|(_, layer)| layer.sprites().len())
+                .collect();
+
+            let sprites: Vec<Instance> = scene.layers()
+                .flat_map(|(_, layer)| layer.sprites())
+                .map(|sprite| {
+                    Self::sprite_instance(cell_size, atlas, animation_ids, sprite)
+                })
+                .collect();
+
+            if full
+                || sprite_counts != self.sprite_counts
+                || self.instances[cell_count..] != sprites[..]
+            {
+                self.instances.truncate(cell_count);
+                self.instances.extend(sprites);
+                self.sprite_counts = sprite_counts;
+
+                if !full {
+                    self.write_instances(cell_count..self.instances.len());
+                }
+                changed = true;
+            }
+        }
+
+        if self.instances.len() > self.instance_capacity {
+            self.instance_capacity = self.instances.len().next_power_of_two();
+            self.gpu.instance_buffer = Gpu::create_instance_buffer(
+                &self.gpu.device,
+                self.instance_capacity,
+            );
+            full = true;
+        }
+
+        if full {
+            self.write_instances(0..self.instances.len());
         }
+
+        if changed {
+            self.rebuild_batches();
+            self.has_animated_tiles = self.instances
+                .iter()
+                .any(|instance| instance.animation != 0);
+        }
+
+        self.count_references(scene, full);
+        changed
+    }
+
+    /// Keep the atlas's reference counts in line with the codes `scene`
+    /// shows. Only dirty layers are compared unless `full` is set.
+    fn count_references(&mut self, scene: &Scene, full: bool) {
+        let area = scene.size().area() as usize;
+
+        if full {
+            let codes = scene.layers()
+                .flat_map(|(_, layer)| layer.cells().iter().map(|cell| cell.code))
+                .collect();
+            recount(&mut self.atlas, &mut self.cell_codes, codes);
+        } else {
+            for (slot, (_, layer)) in scene.layers().enumerate() {
+                if !layer.is_dirty() {
+                    continue;
+                }
+
+                let codes = &mut self.cell_codes[slot * area..(slot + 1) * area];
+                for (old, cell) in codes.iter_mut().zip(layer.cells()) {
+                    if *old != cell.code {
+                        self.atlas.release(*old);
+                        self.atlas.retain(cell.code);
+                        *old = cell.code;
+                    }
+                }
+            }
+        }
+
+        if full || scene.is_dirty() {
+            let codes = scene.layers()
+                .flat_map(|(_, layer)| layer.sprites().iter().map(|sprite| sprite.code))
+                .collect();
+            recount(&mut self.atlas, &mut self.sprite_codes, codes);
+        }
+    }
+
+    /// Add a tile that is evicted by [`State::clean_up_atlas`] once nothing
+    /// shows it, listed in the atlas dump as `source`. Returns whether tiles
+    /// moved, in which case animations have to be set again. Fails if the
+    /// tile is too big for any atlas page.
+    pub fn add_tile(
+        &mut self,
+        code: char,
+        pixels: RgbaImage,
+        source: TileSource,
+    ) -> Result<bool, TextureError> {
+        let moved = self.atlas.add(&self.gpu.device, &self.gpu.queue, code, pixels, source);
+        let moved = self.refresh_pages(moved);
+        // The code may have been drawn from the sheet until now
+        self.invalidated = true;
+        moved
+    }
+
+    /// Add tiles that stay in the atlas until replaced, such as a
+    /// tileset's. They take precedence over the sheet. Fails if a tile is
+    /// too big for any atlas page; the others are added all the same.
+    pub fn insert_tiles<'a>(
+        &mut self,
+        tiles: impl IntoIterator<Item = (char, &'a RgbaImage)>,
+    ) -> Result<(), TextureError> {
+        let tiles = tiles.into_iter().map(|(code, pixels)| (code, pixels.clone()));
+        let changed = self.atlas.insert_tiles(&self.gpu.device, &self.gpu.queue, tiles);
+        let changed = self.refresh_pages(changed);
+        self.invalidated = true;
+        changed.map(|_| ())
+    }
+
+    /// Count `font` among the sources the atlas cache is keyed by.
+    pub fn add_font_source(&mut self, font: &Font) {
+        self.atlas.add_font_source(font);
+    }
+
+    /// Whether `code` has a tile of its own in the atlas.
+    pub fn has_tile(&self, code: char) -> bool {
+        self.atlas.contains(code)
+    }
+
+    /// Take `codes` out of the atlas, even if they are still shown. Cells
+    /// showing them fall back to the sheet.
+    pub fn remove_tiles(&mut self, codes: impl IntoIterator<Item = char>) {
+        for code in codes {
+            self.atlas.remove(&self.gpu.device, &self.gpu.queue, code);
+        }
+        self.invalidated = true;
+    }
+
+    /// Shift tile `code`, added with [`State::add_tile`], by `offset` pixels
+    /// wherever it is drawn. Returns whether there is such a tile.
+    pub fn set_tile_offset(&mut self, code: char, offset: Point<i32>) -> bool {
+        let found = self.atlas.set_tile_offset(code, offset);
+        self.invalidated |= found;
+        found
+    }
+
+    /// Evict tiles added with [`State::add_tile`] that no cell, sprite,
+    /// animation or particle emitter uses. Returns how many were evicted.
+    pub fn clean_up_atlas(&mut self) -> usize {
+        let emitter_codes: Vec<char> = self.particles
+            .emitters()
+            .map(|emitter| emitter.code)
+            .collect();
+
+        for code in &emitter_codes {
+            self.atlas.retain(*code);
+        }
+        let evicted = self.atlas.clean_up(&self.gpu.device, &self.gpu.queue);
+        for code in &emitter_codes {
+            self.atlas.release(*code);
+        }
+
+        evicted.len()
+    }
+
+    /// Repack the atlas without dead space. Instances are rebuilt on the
+    /// next prepare; animations have to be set again. Fails like
+    /// [`Atlas::rebuild`].
+    pub fn defragment_atlas(&mut self) -> Result<(), TextureError> {
+        let repacked = self.atlas.defragment(&self.gpu.device, &self.gpu.queue);
+        self.rebuild_texture_bind_groups();
+        self.invalidated = true;
+        repacked
+    }
+
+    /// Lay out the draw calls: each layer's cells, then its sprites.
+    fn rebuild_batches(&mut self) {
+        self.batches.clear();
+        let mut cell_start = 0;
+        let mut sprite_start = self.cell_counts.iter().sum::<usize>();
+
+        for (slot, layer) in self.layer_order.iter().enumerate() {
+            let count = self.cell_counts[slot];
+            if count > 0 {
+                let range = cell_start as u32..(cell_start + count) as u32;
+                self.batches.push((*layer, range));
+                cell_start += count;
+            }
+
+            let count = self.sprite_counts[slot];
+            if count > 0 {
+                let range = sprite_start as u32..(sprite_start + count) as u32;
+                self.batches.push((*layer, range));
+                sprite_start += count;
+            }
+        }
+    }
+
+    /// Where tile `code` is in the atlas, and on which page.
+    fn tex_coords_for(atlas: &Atlas, code: char) -> ([f32; 4], u32) {
+        let location = atlas.locate(code);
+        (location.tex_coords.to_array(), location.page as u32)
+    }
+
+    fn cell_instance(
+        size: Size<i32>,
+        cell_size: Size<i32>,
+        atlas: &Atlas,
+        layouts: &[TileLayout],
+        animation_ids: &HashMap<char, u32>,
+        index: usize,
+        cell: &Cell,
+    ) -> Instance {
+        let index = index as i32;
+        let transform = cell.transform;
+        let mut flags = 0;
+        if transform.flip_x {
+            flags |= Instance::FLIP_X;
+        }
+        if transform.flip_y {
+            flags |= Instance::FLIP_Y;
+        }
+
+        let location = atlas.locate(cell.code);
+        let x = (index % size.width) * cell_size.width + cell.offset.x + location.offset.x;
+        let y = (index / size.width) * cell_size.height + cell.offset.y + location.offset.y;
+        let cell_extent = Size::new(cell_size.width as f32, cell_size.height as f32);
+
+        // Tiles without an alignment are stretched over the cell; aligned
+        // ones keep their own size, so they may overhang it
+        let layout = layouts.iter().rev().find(|layout| layout.contains(cell.code));
+        let (position, extent, alignment) = match layout {
+            Some(layout) if layout.alignment != TileAlignment::Unknown => {
+                let tile = Size::new(location.size.width as f32, location.size.height as f32);
+                let [dx, dy] = layout.alignment.position(tile, cell_extent);
+                let position = [
+                    (x + layout.offset.x) as f32 + dx,
+                    (y + layout.offset.y) as f32 + dy,
+                ];
+                (position, tile, layout.alignment)
+            }
+            _ => ([x as f32, y as f32], cell_extent, TileAlignment::Center),
+        };
+
+        Instance {
+            position,
+            size: [extent.width, extent.height],
+            anchor: alignment.anchor(),
+            scale: transform.scale,
+            rotation: transform.rotation,
+            flags,
+            tex_coords: location.tex_coords.to_array(),
+            fore: cell.fore.to_linear(),
+            back: cell.back.to_linear(),
+            animation: animation_ids.get(&cell.code).copied().unwrap_or(0),
+            page: location.page as u32,
+        }
+    }
+
+    fn sprite_instance(
+        cell_size: Size<i32>,
+        atlas: &Atlas,
+        animation_ids: &HashMap<char, u32>,
+        sprite: &Sprite,
+    ) -> Instance {
+        let (tex_coords, page) = Self::tex_coords_for(atlas, sprite.code);
+
+        Instance {
+            position: [sprite.position.x, sprite.position.y],
+            size: [
+                cell_size.width as f32 * sprite.scale,
+                cell_size.height as f32 * sprite.scale,
+            ],
+            anchor: TileAlignment::Center.anchor(),
+            scale: 1.0,
+            rotation: sprite.rotation,
+            flags: 0,
+            tex_coords,
+            fore: sprite.color.to_linear(),
+            back: Color::TRANSPARENT.to_linear(),
+            animation: animation_ids.get(&sprite.code).copied().unwrap_or(0),
+            page,
+        }
+    }
+
+    fn write_instances(&self, range: Range<usize>) {
+        let offset = range.start * std::mem::size_of::<Instance>();
+        self.gpu.queue.write_buffer(
+            &self.gpu.instance_buffer,
+            offset as wgpu::BufferAddress,
+            bytemuck::cast_slice(&self.instances[range]),
+        );
+    }
+
+    /// Whether the picture changes over time even when the scene does not,
+    /// so the window has to keep redrawing.
+    pub fn is_animating(&self) -> bool {
+        !self.minimized
+            && !self.clock.is_paused()
+            && (
+                self.has_animated_tiles
+                || !self.gpu.layer_pipelines.is_empty()
+                || self.particles.is_active()
+            )
+    }
+
+    pub fn cursor(&self) -> &Cursor {
+        &self.cursor
+    }
+
+    pub fn atlas(&self) -> &Atlas {
+        &self.atlas
+    }
+
+    pub fn atlas_overlay(&self) -> bool {
+        self.atlas_overlay
+    }
+
+    /// Show the atlas pages over everything, scaled to the window, each with
+    /// a bar showing how full it is.
+    pub fn set_atlas_overlay(&mut self, visible: bool) {
+        self.atlas_overlay = visible;
+    }
+
+    /// Move or restyle the cursor. Moving it, or showing it, restarts the
+    /// blink so the cursor is visible right away.
+    pub fn set_cursor(&mut self, cursor: Cursor) {
+        if cursor == self.cursor {
+            return;
+        }
+
+        if cursor.position != self.cursor.position || !self.cursor.visible {
+            self.cursor_epoch = self.clock.time();
+        }
+
+        self.cursor = cursor;
+        self.gpu.queue.write_buffer(
+            &self.gpu.cursor_buffer,
+            0,
+            bytemuck::bytes_of(&self.cursor_instance()),
+        );
+    }
+
+    /// When the blinking cursor next turns on or off, if it is blinking.
+    pub fn next_cursor_toggle(&self) -> Option<Instant> {
+        if self.minimized || self.clock.is_paused() {
+            return None;
+        }
+
+        self.cursor
+            .time_to_toggle(self.clock.time(), self.cursor_epoch)
+            .map(|wait| Instant::now() + wait)
+    }
+
+    fn cursor_instance(&self) -> Instance {
+        let (position, size) = self.cursor.rect(self.cell_size);
+
+        Instance {
+            position,
+            size,
+            scale: 1.0,
+            fore: self.cursor.color.to_linear(),
+            ..Default::default()
+        }
+    }
+
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    pub fn clock_mut(&mut self) -> &mut Clock {
+        &mut self.clock
+    }
+
+    /// Take the tile layouts and animation table from `tileset`. Frames are
+    /// selected on the GPU from the time uniform, so animated cells are
+    /// never re-uploaded while they play.
+    pub fn set_tileset(&mut self, tileset: &Tileset) {
+        let (entries, frames, ids) = build_animation_tables(
+            tileset,
+            |code| Self::tex_coords_for(&self.atlas, code),
+        );
+
+        let animation_table = Gpu::create_animation_table(
+            &self.gpu.device,
+            &self.gpu.queue,
+            &entries,
+            &frames,
+        );
+
+        self.gpu.globals_bind_group = Gpu::create_globals_bind_group(
+            &self.gpu.device,
+            &self.gpu.globals_bind_group_layout,
+            &self.gpu.globals_buffer,
+            &animation_table,
+        );
+        self.gpu.animation_table = animation_table;
+
+        let codes = tileset.animations()
+            .flat_map(|(_, animation)| animation.frames().iter().map(|frame| frame.code))
+            .collect();
+        recount(&mut self.atlas, &mut self.animation_codes, codes);
+
+        self.animation_entries = entries;
+        self.animation_frames = frames;
+        self.animation_ids = ids;
+        self.tile_layouts = tileset.layouts().to_vec();
+        self.invalidated = true;
     }
 
     pub fn input(&mut self, event: &WindowEvent) -> bool {
         false
     }
 
-    pub fn update(&mut self) {}
+    pub fn update(&mut self) {
+        let globals = Globals {
+            viewport: [self.size.width as f32, self.size.height as f32],
+            cell_size: [
+                self.cell_size.width as f32,
+                self.cell_size.height as f32,
+            ],
+            time: self.clock.time(),
+            cursor_blink: if self.cursor.is_blinking() {
+                self.cursor.blink_period.as_secs_f32()
+            } else {
+                0.0
+            },
+            cursor_epoch: self.cursor_epoch,
+            ..Default::default()
+        };
+
+        self.gpu.queue.write_buffer(
+            &self.gpu.globals_buffer,
+            0,
+            bytemuck::bytes_of(&globals),
+        );
+
+        self.update_particles();
+    }
+
+    /// Step the particle simulation to the current time and upload every
+    /// live particle.
+    fn update_particles(&mut self) {
+        self.particles.step(self.clock.time());
+
+        let instances: Vec<ParticleInstance> = self.particles
+            .particles()
+            .iter()
+            .filter_map(|particle| {
+                let emitter = self.particles.emitter(particle.emitter)?;
+                let progress = particle.age / emitter.lifetime.max(f32::EPSILON);
+                let flags = if emitter.align_to_velocity {
+                    ParticleInstance::ALIGN_TO_VELOCITY
+                } else {
+                    0
+                };
+
+                let (tex_coords, page) = Self::tex_coords_for(&self.atlas, emitter.code);
+
+                Some(ParticleInstance {
+                    position: [particle.position.x, particle.position.y],
+                    velocity: [particle.velocity.x, particle.velocity.y],
+                    size: [
+                        self.cell_size.width as f32 * emitter.scale,
+                        self.cell_size.height as f32 * emitter.scale,
+                    ],
+                    tex_coords,
+                    color: emitter.color_at(progress),
+                    flags,
+                    page,
+                })
+            })
+            .collect();
+
+        if instances.len() > self.particle_capacity {
+            self.particle_capacity = instances.len().next_power_of_two();
+            self.gpu.particle_buffer = Gpu::create_particle_buffer(
+                &self.gpu.device,
+                self.particle_capacity,
+            );
+        }
+
+        self.gpu.queue.write_buffer(
+            &self.gpu.particle_buffer,
+            0,
+            bytemuck::cast_slice(&instances),
+        );
+        self.particle_count = instances.len() as u32;
+    }
+
+    pub fn particles(&self) -> &ParticleSystem {
+        &self.particles
+    }
 
-    pub fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
-        let frame = self.surface
-            .get_current_texture()
-            .expect("Failed to acquire next swap chain texture");
+    pub fn particles_mut(&mut self) -> &mut ParticleSystem {
+        &mut self.particles
+    }
+
+    /// Draw the prepared instances. Surface errors are returned to the
+    /// caller, where `Lost` and `Outdated` are fixed by
+    /// [`State::reconfigure`], as is any error wgpu raised since the last
+    /// frame. Nothing is drawn while the device is lost.
+    pub fn render(&mut self) -> Result<(), Error> {
+        if self.is_device_lost() {
+            return Err(Error::DeviceLost);
+        }
+        if let Some(message) = self.status.error.lock().unwrap().take() {
+            return Err(Error::Gpu(message));
+        }
+        if self.minimized {
+            return Ok(());
+        }
+
+        let frame = self.gpu.surface.get_current_texture().map_err(Error::Surface)?;
 
         let view = frame
             .texture
             .create_view(&wgpu::TextureViewDescriptor::default());
 
-        let mut encoder = self.device
+        let mut encoder = self.gpu.device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
                 label: Some("Render Encoder"),
             });
 
+        // Only drawn while debugging, so built fresh every frame
+        let overlay = self.atlas_overlay.then(|| {
+            let viewport = Size::new(self.size.width as f32, self.size.height as f32);
+            let instances = overlay_instances(&self.atlas.page_stats(), viewport);
+            let buffer = self.gpu.device.create_buffer_init(
+                &wgpu::util::BufferInitDescriptor {
+                    label: Some("Atlas Overlay Buffer"),
+                    contents: bytemuck::cast_slice(&instances),
+                    usage: wgpu::BufferUsages::VERTEX,
+                }
+            );
+            (buffer, instances.len() as u32)
+        });
+
         {
             let mut _render_pass = encoder.begin_render_pass(
                 &wgpu::RenderPassDescriptor {
@@ -346,19 +1668,468 @@ impl State {
                 }
             );
 
-            _render_pass.set_pipeline(&self.render_pipeline);
-            _render_pass.set_bind_group(0, &self.diffuse_bind_group, &[]);
-            _render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+            _render_pass.set_bind_group(0, &self.gpu.diffuse_bind_group, &[]);
+            _render_pass.set_bind_group(1, &self.gpu.globals_bind_group, &[]);
+            _render_pass.set_vertex_buffer(0, self.gpu.vertex_buffer.slice(..));
+            _render_pass.set_vertex_buffer(1, self.gpu.instance_buffer.slice(..));
             _render_pass.set_index_buffer(
-                self.index_buffer.slice(..),
+                self.gpu.index_buffer.slice(..),
                 wgpu::IndexFormat::Uint16,
             );
-            _render_pass.draw_indexed(0..self.num_indices, 0, 0..1);
+
+            for (layer, range) in &self.batches {
+                let pipeline = self.gpu.layer_pipelines
+                    .get(layer)
+                    .unwrap_or(&self.gpu.render_pipeline);
+
+                _render_pass.set_pipeline(pipeline);
+                _render_pass.draw_indexed(0..self.gpu.num_indices, 0, range.clone());
+            }
+
+            // Particles go over every layer of cells
+            if self.particle_count > 0 {
+                _render_pass.set_pipeline(&self.gpu.particle_pipeline);
+                _render_pass.set_vertex_buffer(1, self.gpu.particle_buffer.slice(..));
+                _render_pass.draw_indexed(0..self.gpu.num_indices, 0, 0..self.particle_count);
+            }
+
+            // The cursor goes over every layer
+            if self.cursor.visible {
+                _render_pass.set_pipeline(&self.gpu.cursor_pipeline);
+                _render_pass.set_vertex_buffer(1, self.gpu.cursor_buffer.slice(..));
+                _render_pass.draw_indexed(0..self.gpu.num_indices, 0, 0..1);
+            }
+
+            if let Some((buffer, count)) = &overlay {
+                _render_pass.set_pipeline(&self.gpu.particle_pipeline);
+                _render_pass.set_vertex_buffer(1, buffer.slice(..));
+                _render_pass.draw_indexed(0..self.gpu.num_indices, 0, 0..*count);
+            }
         }
 
-        self.queue.submit(std::iter::once(encoder.finish()));
+        self.gpu.queue.submit(std::iter::once(encoder.finish()));
         frame.present();
 
         Ok(())
     }
 }
+
+
+/// Parse and validate `source` after the shader prelude, as the pipelines
+/// compile it.
+fn validate_shader(source: &str) -> Result<naga::Module, String> {
+    let source = format!("{}\n{}", SHADER_PRELUDE, source);
+    let module = naga::front::wgsl::parse_str(&source)
+        .map_err(|e| e.emit_to_string(&source))?;
+
+    naga::valid::Validator::new(
+        naga::valid::ValidationFlags::all(),
+        naga::valid::Capabilities::empty(),
+    )
+        .validate(&module)
+        .map_err(|e| e.to_string())?;
+    Ok(module)
+}
+
+/// Like [`validate_shader`], and the source must define the `fs_main`
+/// fragment entry point layer pipelines are built with.
+fn validate_layer_shader(layer: u32, fragment_source: &str) -> Result<(), Error> {
+    let module = validate_shader(fragment_source)
+        .map_err(|message| Error::Shader(layer, message))?;
+    let has_entry = module.entry_points.iter().any(|entry| {
+        entry.name == "fs_main" && entry.stage == naga::ShaderStage::Fragment
+    });
+    if !has_entry {
+        return Err(Error::Shader(layer, "no `@fragment fn fs_main` entry point".into()));
+    }
+    Ok(())
+}
+
+
+/// Whether `error` means the device is gone rather than a misuse of the API.
+/// wgpu reports a lost device as a validation error caused by
+/// `DeviceError::Lost`; running out of memory is not a loss, and rebuilding
+/// would only run out again.
+fn is_device_lost(error: &wgpu::Error) -> bool {
+    let wgpu::Error::Validation { source, .. } = error else {
+        return false;
+    };
+
+    let mut next: Option<&(dyn std::error::Error + 'static)> = Some(source.as_ref());
+    while let Some(error) = next {
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(wgpu_core::device::DeviceError::Lost) = error.downcast_ref() {
+            return true;
+        }
+        next = error.source();
+    }
+    false
+}
+
+
+/// Flatten the animations of `tileset` into the GPU animation and frame
+/// tables, along with the 1-based animation id for each animated code.
+fn build_animation_tables(
+    tileset: &Tileset,
+    tex_coords_for: impl Fn(char) -> ([f32; 4], u32),
+) -> (Vec<AnimationEntry>, Vec<AnimationFrame>, HashMap<char, u32>) {
+    let mut entries = Vec::new();
+    let mut frames = Vec::new();
+    let mut ids = HashMap::new();
+
+    for (code, animation) in tileset.animations() {
+        let first = frames.len() as u32;
+        let mut end = 0.0;
+
+        for frame in animation.frames() {
+            end += frame.duration.as_secs_f32();
+            let (tex_coords, page) = tex_coords_for(frame.code);
+            frames.push(AnimationFrame { tex_coords, end, page });
+        }
+
+        entries.push(AnimationEntry {
+            first,
+            count: animation.frames().len() as u32,
+            duration: end,
+        });
+        ids.insert(code, entries.len() as u32);
+    }
+
+    (entries, frames, ids)
+}
+
+
+/// Lay the animation tables out as texels of a texture
+/// [`ANIMATION_TABLE_WIDTH`] wide: one per animation, `(first, count,
+/// duration, 0)` where `first` is the texel its frames start at, then two
+/// per frame, its texture coordinates and `(end, page, 0, 0)`. The last row
+/// is padded, and an empty table is one row of zeros.
+fn pack_animation_table(entries: &[AnimationEntry], frames: &[AnimationFrame]) -> Vec<[f32; 4]> {
+    let frames_start = entries.len() as u32;
+    let mut texels: Vec<[f32; 4]> = entries
+        .iter()
+        .map(|entry| [
+            (frames_start + entry.first * 2) as f32,
+            entry.count as f32,
+            entry.duration,
+            0.0,
+        ])
+        .collect();
+
+    for frame in frames {
+        texels.push(frame.tex_coords);
+        texels.push([frame.end, frame.page as f32, 0.0, 0.0]);
+    }
+
+    let width = ANIMATION_TABLE_WIDTH as usize;
+    let rows = texels.len().div_ceil(width).max(1);
+    texels.resize(rows * width, [0.0; 4]);
+    texels
+}
+
+
+/// Replace the codes in `current` with `next`, moving their references in
+/// `atlas` along.
+fn recount(atlas: &mut Atlas, current: &mut Vec<char>, next: Vec<char>) {
+    for code in current.drain(..) {
+        atlas.release(code);
+    }
+    for code in &next {
+        atlas.retain(*code);
+    }
+    *current = next;
+}
+
+
+/// The key of the atlas cache kept at [`Config::atlas_cache`]: every
+/// source of the atlas. The crate version stands in for the code drawing
+/// and packing the tiles.
+fn atlas_cache_key(atlas: &Atlas) -> u64 {
+    let mut key = CacheKey::new();
+    env!("CARGO_PKG_VERSION").hash(&mut key);
+    atlas.source_key().hash(&mut key);
+    key.finish()
+}
+
+
+/// The quads of the atlas overlay: a backdrop over the whole `viewport`,
+/// then each page side by side, as large as fits, above a bar filled as
+/// far as the page is.
+fn overlay_instances(pages: &[PageStats], viewport: Size<f32>) -> Vec<ParticleInstance> {
+    let solid = |page: usize, x: f32, y: f32, width: f32, height: f32, color: [f32; 4]| {
+        ParticleInstance {
+            position: [x + width / 2.0, y + height / 2.0],
+            size: [width, height],
+            color,
+            flags: ParticleInstance::SOLID,
+            page: page as u32,
+            ..Default::default()
+        }
+    };
+
+    let mut instances = vec![
+        solid(0, 0.0, 0.0, viewport.width, viewport.height, [0.0, 0.0, 0.0, 0.8]),
+    ];
+    if pages.is_empty() {
+        return instances;
+    }
+
+    let count = pages.len() as f32;
+    let slot_width = (viewport.width - OVERLAY_MARGIN * (count + 1.0)) / count;
+    let slot_height = viewport.height - OVERLAY_MARGIN * 3.0 - OVERLAY_BAR;
+
+    for (index, stats) in pages.iter().enumerate() {
+        let scale = (slot_width / stats.size.width as f32)
+            .min(slot_height / stats.size.height as f32)
+            .max(0.0);
+        let width = stats.size.width as f32 * scale;
+        let height = stats.size.height as f32 * scale;
+        let x = OVERLAY_MARGIN + (slot_width + OVERLAY_MARGIN) * index as f32
+            + (slot_width - width) / 2.0;
+        let y = OVERLAY_MARGIN;
+        let bar = y + height + OVERLAY_MARGIN;
+
+        instances.push(solid(index, x, y, width, height, [0.15, 0.15, 0.15, 1.0]));
+        instances.push(ParticleInstance {
+            position: [x + width / 2.0, y + height / 2.0],
+            size: [width, height],
+            tex_coords: TexCoords::FULL.to_array(),
+            color: Color::WHITE.to_linear(),
+            page: index as u32,
+            ..Default::default()
+        });
+        instances.push(solid(index, x, bar, width, OVERLAY_BAR, [0.3, 0.3, 0.3, 1.0]));
+        instances.push(solid(
+            index,
+            x,
+            bar,
+            width * stats.occupancy.clamp(0.0, 1.0),
+            OVERLAY_BAR,
+            [0.2, 0.8, 0.3, 1.0],
+        ));
+    }
+
+    instances
+}
+
+
+/// Copy `next` over `current` and return the spans of indices that differed,
+/// with adjacent changes merged into a single span.
+fn diff_instances(current: &mut [Instance], next: &[Instance]) -> Vec<Range<usize>> {
+    let mut spans: Vec<Range<usize>> = Vec::new();
+
+    for (i, (old, new)) in current.iter_mut().zip(next).enumerate() {
+        if old == new {
+            continue;
+        }
+
+        *old = *new;
+        match spans.last_mut() {
+            Some(span) if span.end == i => span.end = i + 1,
+            _ => spans.push(i..i + 1),
+        }
+    }
+
+    spans
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::state::{
+        build_animation_tables,
+        pack_animation_table,
+        diff_instances,
+        is_device_lost,
+        validate_layer_shader,
+        validate_shader,
+        overlay_instances,
+        CURSOR_FRAGMENT_SHADER,
+        DEFAULT_FRAGMENT_SHADER,
+        ANIMATION_TABLE_WIDTH,
+        PARTICLE_SHADER,
+    };
+    use crate::atlas::PageStats;
+    use crate::error::Error;
+    use crate::size::Size;
+    use crate::tileset::{Frame, Tileset};
+    use crate::vertex::{Instance, ParticleInstance};
+
+    #[test]
+    fn test_diff_instances_merges_spans() {
+        let marked = Instance { rotation: 1.0, ..Default::default() };
+        let mut current = vec![Instance::default(); 6];
+        let mut next = current.clone();
+        next[1] = marked;
+        next[2] = marked;
+        next[5] = marked;
+
+        assert_eq!(diff_instances(&mut current, &next), vec![1..3, 5..6]);
+        assert_eq!(current, next);
+        assert!(diff_instances(&mut current, &next).is_empty());
+    }
+
+    #[test]
+    fn test_animation_tables() {
+        let frame = |code, millis| Frame {
+            code,
+            duration: Duration::from_millis(millis),
+        };
+
+        let mut tileset = Tileset::new('\0');
+        tileset.add_animation('a', vec![frame('a', 250), frame('b', 250)]);
+        tileset.add_animation('t', vec![frame('t', 100)]);
+
+        let (entries, frames, ids) = build_animation_tables(
+            &tileset,
+            |code| ([code as u32 as f32; 4], 0),
+        );
+
+        assert_eq!(ids[&'a'], 1);
+        assert_eq!(ids[&'t'], 2);
+        assert_eq!(entries[1].first, 2);
+        assert_eq!(entries[0].duration, 0.5);
+        assert_eq!(frames[1].end, 0.5);
+        assert_eq!(frames[1].tex_coords[0], 'b' as u32 as f32);
+    }
+
+    #[test]
+    fn test_packed_animation_table_loops() {
+        let frame = |code, millis| Frame {
+            code,
+            duration: Duration::from_millis(millis),
+        };
+
+        let mut tileset = Tileset::new('\0');
+        tileset.add_animation('t', vec![frame('t', 100)]);
+        tileset.add_animation('~', vec![frame('a', 100), frame('b', 300)]);
+        let (entries, frames, ids) = build_animation_tables(
+            &tileset,
+            |code| ([code as u32 as f32; 4], code as u32 % 2),
+        );
+        let texels = pack_animation_table(&entries, &frames);
+        assert_eq!(texels.len(), ANIMATION_TABLE_WIDTH as usize);
+
+        // Walk the table as the vertex shader does
+        let frame_at = |id: u32, time: f32| {
+            let [first, count, duration, _] = texels[id as usize - 1];
+            let t = time % duration;
+            let mut i = 0;
+            while i + 1 < count as usize && t >= texels[first as usize + i * 2 + 1][0] {
+                i += 1;
+            }
+            let timing = texels[first as usize + i * 2 + 1];
+            (char::from_u32(texels[first as usize + i * 2][0] as u32).unwrap(), timing[1] as u32)
+        };
+
+        let id = ids[&'~'];
+        assert_eq!(frame_at(id, 0.05), ('a', 1));
+        assert_eq!(frame_at(id, 0.2), ('b', 0));
+        assert_eq!(frame_at(id, 0.45), ('a', 1));
+        assert_eq!(frame_at(ids[&'t'], 7.0).0, 't');
+
+        assert_eq!(pack_animation_table(&[], &[]).len(), ANIMATION_TABLE_WIDTH as usize);
+    }
+
+    #[test]
+    fn test_device_lost_is_told_apart_from_other_errors() {
+        let validation = |source: Box<dyn std::error::Error + Send>| wgpu::Error::Validation {
+            description: source.to_string(),
+            source,
+        };
+
+        // wgpu wraps the cause in a context naming the failed call
+        #[derive(Debug)]
+        struct Context(wgpu_core::device::DeviceError);
+        impl std::fmt::Display for Context {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "In Queue::submit")
+            }
+        }
+        impl std::error::Error for Context {
+            fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+                Some(&self.0)
+            }
+        }
+
+        let lost = Context(wgpu_core::device::DeviceError::Lost);
+        assert!(is_device_lost(&validation(Box::new(lost))));
+
+        // Mentioning a lost device is not enough
+        let message = std::io::Error::other("Parent device is lost");
+        assert!(!is_device_lost(&validation(Box::new(message))));
+
+        let out_of_memory = wgpu::Error::OutOfMemory {
+            source: Box::new(wgpu_core::device::DeviceError::OutOfMemory),
+        };
+        assert!(!is_device_lost(&out_of_memory));
+    }
+
+    #[test]
+    fn test_default_shader_is_valid() {
+        validate_shader(DEFAULT_FRAGMENT_SHADER).unwrap();
+    }
+
+    #[test]
+    fn test_cursor_shader_is_valid() {
+        validate_shader(CURSOR_FRAGMENT_SHADER).unwrap();
+    }
+
+    #[test]
+    fn test_overlay_fits_pages_side_by_side() {
+        let pages = [
+            PageStats { size: Size::new(256, 256), occupancy: 0.5 },
+            PageStats { size: Size::new(512, 256), occupancy: 1.0 },
+        ];
+        let instances = overlay_instances(&pages, Size::new(848.0, 600.0));
+
+        // Backdrop, then a frame, the page, a bar and its fill per page
+        assert_eq!(instances.len(), 9);
+        assert_eq!(instances[0].size, [848.0, 600.0]);
+        assert_eq!(instances[2].flags & ParticleInstance::SOLID, 0);
+        assert_eq!(instances[2].size, [400.0, 400.0]);
+        assert_eq!(instances[4].size, [200.0, 8.0]);
+        assert_eq!(instances[6].page, 1);
+        assert_eq!(instances[6].size, [400.0, 200.0]);
+        assert_eq!(instances[8].size, [400.0, 8.0]);
+    }
+
+    #[test]
+    fn test_particle_shader_is_valid() {
+        validate_shader(PARTICLE_SHADER).unwrap();
+    }
+
+    #[test]
+    fn test_custom_layer_shader_is_valid() {
+        validate_shader("
+            @fragment
+            fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+                let texel = sample_atlas(in);
+                let shimmer = 0.5 + 0.5 * sin(globals.time + in.local.x * 6.28);
+                return in.fore * texel * shimmer + in.back * (1.0 - texel.a);
+            }
+        ").unwrap();
+    }
+
+    #[test]
+    fn test_broken_layer_shader_is_rejected() {
+        assert!(validate_shader("fn fs_main( {").is_err());
+        assert!(matches!(
+            validate_layer_shader(3, "fn fs_main( {"),
+            Err(Error::Shader(3, _)),
+        ));
+
+        let error = validate_layer_shader(3, "
+            @fragment
+            fn main(in: VertexOutput) -> @location(0) vec4<f32> {
+                return in.fore;
+            }
+        ").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Invalid shader for layer 3: no `@fragment fn fs_main` entry point",
+        );
+    }
+}
@@ -1,13 +1,603 @@
+use image::RgbaImage;
+use std::collections::BTreeSet;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use winit::{
+    window::Window,
+    event::WindowEvent,
+};
+
+use crate::color::Color;
+use crate::config::Config;
+use crate::atlas::{PageStats, TileAlignment, TileSource};
+use crate::cursor::{Cursor, CursorShape};
+use crate::error::Error;
+use crate::font::{Font, FontOptions};
+use crate::particles::{Emitter, EmitterId};
+use crate::point::Point;
+use crate::scene::{Cell, Scene, Sprite, Transform};
+use crate::size::Size;
 use crate::state::State;
+use crate::texture::TextureFilter;
+use crate::tileset::{Frame, TileGrid, Tileset};
+
+
+/// A snapshot of the terminal's current settings, as returned by
+/// [`Terminal::get_state`].
+#[derive(Debug, Clone)]
+pub struct TerminalState {
+    pub grid_size: Size<i32>,
+    pub cell_size: Size<i32>,
+    pub present_mode: wgpu::PresentMode,
+    pub adapter_name: String,
+    pub backend: wgpu::Backend,
+    pub fps_cap: Option<u32>,
+    pub texture_filter: TextureFilter,
+}
+
 
 pub struct Terminal {
     state: State,
+    // Drawing goes to `scene`; `front` is the last refreshed copy and the
+    // only one the renderer ever sees.
+    scene: Scene,
+    front: Scene,
+    tileset: Tileset,
+    layer: u32,
+    forecolor: Color,
+    backcolor: Color,
+    last_frame: Instant,
 }
 
 impl Terminal {
-    pub async fn new(state: State) -> Self {
-        Self {
+    /// Create the window's renderer and a terminal drawing to it. Fails if
+    /// no usable graphics device is found or `config` is invalid.
+    pub async fn new(window: Window, config: &Config) -> Result<Self, Error> {
+        let state = State::new(window, config).await?;
+        let scene = Scene::new(state.grid_size());
+
+        Ok(Self {
             state,
+            front: scene.clone(),
+            scene,
+            tileset: Tileset::new('\0'),
+            layer: 0,
+            forecolor: Color::WHITE,
+            backcolor: Color::TRANSPARENT,
+            last_frame: Instant::now(),
+        })
+    }
+
+    pub fn window(&self) -> &Window {
+        self.state.window()
+    }
+
+    pub fn get_state(&self) -> TerminalState {
+        TerminalState {
+            grid_size: self.front.size(),
+            cell_size: self.state.cell_size(),
+            present_mode: self.state.present_mode(),
+            adapter_name: self.state.adapter_info().name.clone(),
+            backend: self.state.adapter_info().backend,
+            fps_cap: self.state.fps_cap(),
+            texture_filter: self.state.texture_filter(),
+        }
+    }
+
+    pub fn set_texture_filter(&mut self, filter: TextureFilter) {
+        self.state.set_texture_filter(filter);
+        self.state.set_tileset(&self.tileset);
+        self.redraw();
+    }
+
+    /// Load the tile sheet from an image file, replacing the current one.
+    pub fn load_texture(&mut self, path: impl AsRef<Path>) -> Result<(), Error> {
+        self.state.load_texture(path.as_ref())?;
+        self.state.set_tileset(&self.tileset);
+        self.redraw();
+        Ok(())
+    }
+
+    /// Load the tile sheet from an encoded PNG or JPEG image in memory,
+    /// replacing the current one.
+    pub fn load_texture_bytes(
+        &mut self,
+        bytes: &[u8],
+        label: &str,
+    ) -> Result<(), Error> {
+        self.state.load_texture_bytes(bytes, label)?;
+        self.state.set_tileset(&self.tileset);
+        self.redraw();
+        Ok(())
+    }
+
+    /// Cut the image file at `path` into tiles by `grid` and draw each code
+    /// its codepage gives with its tile, like [`Terminal::add_tileset`].
+    pub fn load_tileset(
+        &mut self,
+        path: impl AsRef<Path>,
+        grid: &TileGrid,
+    ) -> Result<(), Error> {
+        let tileset = Tileset::load(path, grid)?;
+        self.add_tileset(tileset)
+    }
+
+    /// Put the tiles of `tileset` in the atlas, so [`Terminal::put`] draws
+    /// their codes with them, and take in its animations and layouts. Its
+    /// tiles replace the sheet's and any earlier tileset's. A font only
+    /// draws the codes no tileset has a tile for, whichever was added
+    /// first. Fails if a tile is too big for the atlas; the tileset is
+    /// taken in all the same.
+    pub fn add_tileset(&mut self, tileset: Tileset) -> Result<(), Error> {
+        // Glyphs of the previous font give way to the new one's
+        if let Some(font) = tileset.font() {
+            let glyphs: Vec<char> = self.state.atlas().codes(TileSource::Font).collect();
+            self.state.remove_tiles(glyphs);
+            self.state.add_font_source(font);
+        }
+        let inserted = self.state.insert_tiles(tileset.tiles());
+        self.tileset.add_tileset(tileset);
+        self.state.set_tileset(&self.tileset);
+
+        let shown = visible_codes(&self.front, true);
+        self.rasterize(shown);
+        self.redraw();
+        Ok(inserted?)
+    }
+
+    /// Draw text with the TrueType or OpenType font at `path`. Glyphs are
+    /// drawn into the atlas as they are first shown, and evicted by
+    /// [`Terminal::clean_up_atlas`] once they are not. Cells are resized to
+    /// fit the font's ASCII advance and line height; wider glyphs, such as
+    /// CJK ideographs, spill into the cells to their right.
+    pub fn load_font(&mut self, path: impl AsRef<Path>, options: FontOptions) -> Result<(), Error> {
+        let font = Font::load(path, options)?;
+        self.resize_cells(font.cell_size())?;
+        self.add_tileset(Tileset::from_font(font))
+    }
+
+    /// Draw cells `cell_size` pixels big. The grid is refitted to the
+    /// window, keeping what fits of both scenes.
+    pub fn set_cell_size(&mut self, cell_size: Size<i32>) -> Result<(), Error> {
+        if cell_size.width <= 0 || cell_size.height <= 0 {
+            return Err(Error::Config(format!(
+                "cell size must be positive, got {}x{}",
+                cell_size.width, cell_size.height,
+            )));
         }
+        let resized = self.resize_cells(cell_size);
+        self.redraw();
+        resized
+    }
+
+    fn resize_cells(&mut self, cell_size: Size<i32>) -> Result<(), Error> {
+        let generated = self.state.set_cell_size(cell_size);
+        let grid_size = self.state.grid_size();
+        self.scene.resize(grid_size);
+        self.front.resize(grid_size);
+        self.state.set_tileset(&self.tileset);
+        Ok(generated?)
+    }
+
+    /// Draw the tiles of `codes` that only the tileset's font has into the
+    /// atlas, as evictable tiles.
+    fn rasterize(&mut self, codes: BTreeSet<char>) {
+        let Some(font) = self.tileset.font() else {
+            return;
+        };
+
+        let mut drawn = Vec::new();
+        for code in codes {
+            // Tiles of every tileset, whether added before or after the
+            // font, and tiles from add_tile win over its glyphs; glyphs
+            // already drawn are kept
+            if self.state.has_tile(code) {
+                continue;
+            }
+            if let Some(tile) = font.rasterize(code) {
+                // A glyph too big for the atlas is shown from the sheet,
+                // like a code the font lacks
+                let _ = self.state.add_tile(code, tile, TileSource::Font);
+                drawn.push(code);
+            }
+        }
+
+        // Animation frames hold texture coordinates, which move if pages grew
+        if !drawn.is_empty() {
+            self.lay_out_wide_glyphs(drawn);
+            self.state.set_tileset(&self.tileset);
+        }
+    }
+
+    /// Draw the font glyphs among `codes` that are wider than a cell at
+    /// their own size, spilling into the cells to their right, unless a
+    /// layout already places them.
+    fn lay_out_wide_glyphs(&mut self, codes: impl IntoIterator<Item = char>) {
+        let Some(font) = self.tileset.font() else {
+            return;
+        };
+
+        let cell = font.cell_size();
+        let atlas = self.state.atlas();
+        let wide: Vec<char> = codes
+            .into_iter()
+            .filter(|code| atlas.locate(*code).size.width > cell.width)
+            .filter(|code| self.tileset.layout(*code).is_none())
+            .collect();
+        for code in wide {
+            self.tileset.set_alignment(code..=code, TileAlignment::TopLeft, Point::new(0, 0));
+        }
+    }
+
+    /// Draw `code` with `pixels` instead of the sheet tile, until nothing
+    /// shows it and [`Terminal::clean_up_atlas`] evicts it. Fails if the
+    /// tile is too big for the atlas.
+    pub fn add_tile(&mut self, code: char, pixels: RgbaImage) -> Result<(), Error> {
+        let added = self.state.add_tile(code, pixels, TileSource::Added);
+        // Animations may show the new tile, or tiles may have moved
+        self.state.set_tileset(&self.tileset);
+        self.redraw();
+        added?;
+        Ok(())
+    }
+
+    /// Shift tile `code`, added with [`Terminal::add_tile`], by `offset`
+    /// pixels wherever it is drawn. Returns whether there is such a tile.
+    pub fn set_tile_offset(&mut self, code: char, offset: Point<i32>) -> bool {
+        let found = self.state.set_tile_offset(code, offset);
+        self.redraw();
+        found
+    }
+
+    /// Draw the tiles for `codes` at their own size, placed in their cells
+    /// by `alignment` and then shifted by `offset` pixels, instead of
+    /// stretching them over the cell. For sprites larger than the grid,
+    /// such as 16x16 creatures in an 8x16 text grid. Later calls win where
+    /// ranges overlap; `TileAlignment::Unknown` stretches again.
+    pub fn set_alignment(
+        &mut self,
+        codes: RangeInclusive<char>,
+        alignment: TileAlignment,
+        offset: Point<i32>,
+    ) {
+        self.tileset.set_alignment(codes, alignment, offset);
+        self.state.set_tileset(&self.tileset);
+        self.redraw();
+    }
+
+    /// Free the atlas space of tiles added with [`Terminal::add_tile`] that
+    /// are no longer shown. Returns how many were evicted.
+    pub fn clean_up_atlas(&mut self) -> usize {
+        self.state.clean_up_atlas()
+    }
+
+    /// Repack the atlas so the space freed by evicted tiles can hold large
+    /// tiles again. Everything on screen stays as it is. Fails if a tile no
+    /// longer fits, in which case it is shown from the sheet.
+    pub fn defragment_atlas(&mut self) -> Result<(), Error> {
+        let repacked = self.state.defragment_atlas();
+        self.state.set_tileset(&self.tileset);
+        self.redraw();
+        Ok(repacked?)
+    }
+
+    /// Write the atlas pages to `dir` as PNG images, with a JSON manifest
+    /// of where every tile is.
+    pub fn dump_atlas(&self, dir: impl AsRef<Path>) -> Result<(), Error> {
+        let dir = dir.as_ref();
+        self.state.atlas().dump(dir).map_err(|error| Error::Io(dir.into(), error))
+    }
+
+    /// Replace the atlas with the cache at `path` if it was saved under
+    /// `key`, a [`crate::CacheKey`] hash of whatever the tiles were made
+    /// from. Returns `false` if there is no such cache, in which case the
+    /// tiles have to be added as usual.
+    pub fn load_atlas_cache(&mut self, path: impl AsRef<Path>, key: u64) -> Result<bool, Error> {
+        let path = path.as_ref();
+        let loaded = self.state
+            .load_atlas_cache(path, key)
+            .map_err(|error| Error::Io(path.into(), error))?;
+        if loaded {
+            let glyphs: Vec<char> = self.state.atlas().codes(TileSource::Font).collect();
+            self.lay_out_wide_glyphs(glyphs);
+            self.state.set_tileset(&self.tileset);
+            self.redraw();
+        }
+        Ok(loaded)
+    }
+
+    /// Load the atlas from [`crate::Config::atlas_cache`] if it was saved
+    /// from the same sheet, tilesets and fonts, or else write it there for
+    /// next time. Call once they are all loaded, since the cache is keyed
+    /// by them. Returns whether the cache was used.
+    pub fn restore_atlas(&mut self) -> Result<bool, Error> {
+        let loaded = self.state.restore_atlas()?;
+        if loaded {
+            let glyphs: Vec<char> = self.state.atlas().codes(TileSource::Font).collect();
+            self.lay_out_wide_glyphs(glyphs);
+            self.state.set_tileset(&self.tileset);
+            self.redraw();
+        }
+        Ok(loaded)
+    }
+
+    /// Write the atlas to `path` for [`Terminal::load_atlas_cache`] to
+    /// restore next time the tiles' sources hash to `key`.
+    pub fn save_atlas_cache(&self, path: impl AsRef<Path>, key: u64) -> Result<(), Error> {
+        let path = path.as_ref();
+        self.state
+            .save_atlas_cache(path, key)
+            .map_err(|error| Error::Io(path.into(), error))
+    }
+
+    /// Size and fill of every atlas page.
+    pub fn atlas_stats(&self) -> Vec<PageStats> {
+        self.state.atlas().page_stats()
+    }
+
+    pub fn atlas_overlay(&self) -> bool {
+        self.state.atlas_overlay()
+    }
+
+    /// Show the atlas pages over the terminal, each with a bar showing how
+    /// full it is. F12 toggles it in [`crate::run`].
+    pub fn set_atlas_overlay(&mut self, visible: bool) {
+        self.state.set_atlas_overlay(visible);
+        self.state.window().request_redraw();
+    }
+
+    pub fn set_layer(&mut self, layer: u32) {
+        self.layer = layer;
+    }
+
+    pub fn set_forecolor(&mut self, color: Color) {
+        self.forecolor = color;
+    }
+
+    pub fn set_backcolor(&mut self, color: Color) {
+        self.backcolor = color;
+    }
+
+    /// Place `code` at cell `(x, y)` of the current layer using the current
+    /// colors.
+    pub fn put(&mut self, x: i32, y: i32, code: char) {
+        self.put_extended(x, y, 0, 0, code, Transform::IDENTITY);
+    }
+
+    /// Place `code` at cell `(x, y)` of the current layer, shifted by
+    /// `(dx, dy)` pixels and drawn with `transform`. The cell's background
+    /// color fills the transformed tile.
+    pub fn put_extended(
+        &mut self,
+        x: i32,
+        y: i32,
+        dx: i32,
+        dy: i32,
+        code: char,
+        transform: Transform,
+    ) {
+        let cell = Cell {
+            code,
+            fore: self.forecolor,
+            back: self.backcolor,
+            offset: Point::new(dx, dy),
+            transform,
+        };
+        self.scene.put(self.layer, Point::new(x, y), cell);
+    }
+
+    /// Draw tile `code` on the current layer at an arbitrary pixel position,
+    /// independent of the cell grid. `scale` is relative to the cell size and
+    /// `rotation` is clockwise in radians about the sprite's center. Within a
+    /// layer, sprites are drawn over the cells in ascending `z`.
+    pub fn draw_sprite(
+        &mut self,
+        code: char,
+        position: Point<f32>,
+        scale: f32,
+        rotation: f32,
+        color: Color,
+        z: i32,
+    ) {
+        let sprite = Sprite { code, position, scale, rotation, color, z };
+        self.scene.add_sprite(self.layer, sprite);
+    }
+
+    /// Animate `code`: wherever it is drawn, `frames` play in a loop driven
+    /// by the terminal clock. An empty list makes the code static again.
+    pub fn add_animation(&mut self, code: char, frames: Vec<Frame>) {
+        self.tileset.add_animation(code, frames);
+        self.state.set_tileset(&self.tileset);
+        self.redraw();
+    }
+
+    /// Freeze animations and time-driven shaders at the current time.
+    pub fn pause_time(&mut self) {
+        self.state.clock_mut().pause();
+        self.state.window().request_redraw();
+    }
+
+    pub fn resume_time(&mut self) {
+        self.state.clock_mut().resume();
+    }
+
+    /// Set the time, in seconds, seen by animations and shaders. Combined
+    /// with [`Terminal::pause_time`] this makes output deterministic.
+    pub fn set_time(&mut self, time: f32) {
+        self.state.clock_mut().set_time(time);
+        self.state.window().request_redraw();
+    }
+
+    pub fn time(&self) -> f32 {
+        self.state.clock().time()
+    }
+
+    pub fn cursor(&self) -> &Cursor {
+        self.state.cursor()
+    }
+
+    /// Replace every cursor setting at once. Unlike cells, the cursor is
+    /// shown without waiting for [`Terminal::refresh`].
+    pub fn set_cursor(&mut self, cursor: Cursor) {
+        self.state.set_cursor(cursor);
+        self.state.window().request_redraw();
+    }
+
+    /// Move the cursor to cell `(x, y)`.
+    pub fn set_cursor_position(&mut self, x: i32, y: i32) {
+        self.set_cursor(Cursor {
+            position: Point::new(x, y),
+            ..*self.cursor()
+        });
+    }
+
+    pub fn set_cursor_visible(&mut self, visible: bool) {
+        self.set_cursor(Cursor { visible, ..*self.cursor() });
+    }
+
+    pub fn set_cursor_shape(&mut self, shape: CursorShape) {
+        self.set_cursor(Cursor { shape, ..*self.cursor() });
+    }
+
+    /// Blink with one on/off cycle every `period`. `Duration::ZERO` stops
+    /// the blinking.
+    pub fn set_cursor_blink(&mut self, period: Duration) {
+        self.set_cursor(Cursor {
+            blink_period: period,
+            ..*self.cursor()
+        });
+    }
+
+    /// Start spawning particles from `emitter`. Particles are drawn over
+    /// every layer of cells and are animated by the terminal clock.
+    pub fn add_emitter(&mut self, emitter: Emitter) -> EmitterId {
+        self.rasterize(BTreeSet::from([emitter.code]));
+        let id = self.state.particles_mut().add_emitter(emitter);
+        self.state.window().request_redraw();
+        id
+    }
+
+    /// Stop `id` from spawning; its particles live out their lifetime.
+    pub fn remove_emitter(&mut self, id: EmitterId) {
+        self.state.particles_mut().remove_emitter(id);
+    }
+
+    /// The emitter behind `id`, to move it or change its settings while it
+    /// runs. `None` once it was removed or has finished.
+    pub fn emitter_mut(&mut self, id: EmitterId) -> Option<&mut Emitter> {
+        self.state.particles_mut().emitter_mut(id)
+    }
+
+    /// Remove every emitter and particle at once.
+    pub fn clear_particles(&mut self) {
+        self.state.particles_mut().clear();
+        self.state.window().request_redraw();
+    }
+
+    pub fn clear(&mut self) {
+        self.scene.clear();
+    }
+
+    /// Draw `layer` with a custom fragment shader. See
+    /// [`State::set_layer_shader`] for the bindings available to it.
+    pub fn set_layer_shader(
+        &mut self,
+        layer: u32,
+        source: &str,
+    ) -> Result<(), Error> {
+        self.state.set_layer_shader(layer, source)
+    }
+
+    pub fn remove_layer_shader(&mut self, layer: u32) {
+        self.state.remove_layer_shader(layer);
+    }
+
+    /// Commit everything drawn since the last refresh as the new frame. Until
+    /// then the window keeps showing the previous one, however it is redrawn.
+    pub fn refresh(&mut self) {
+        self.scene.commit(&mut self.front);
+        self.redraw();
+    }
+
+    /// Show the committed scene, sending only the cells that changed to the
+    /// renderer. Nothing is redrawn if the scene is unchanged.
+    fn redraw(&mut self) {
+        if self.tileset.font().is_some() {
+            let shown = visible_codes(&self.front, false);
+            self.rasterize(shown);
+        }
+
+        if self.state.prepare(&self.front) {
+            self.state.window().request_redraw();
+        }
+        self.front.mark_clean();
+    }
+
+    /// Resize the window surface and both scenes. The committed scene is
+    /// redrawn at the new size without waiting for the application.
+    pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
+        self.state.resize(new_size);
+        let grid_size = self.state.grid_size();
+        self.scene.resize(grid_size);
+        self.front.resize(grid_size);
+        self.redraw();
+    }
+
+    /// Bring back a surface that was lost or outdated.
+    pub fn reconfigure(&mut self) {
+        self.state.reconfigure();
+    }
+
+    pub fn is_animating(&self) -> bool {
+        self.state.is_animating()
+    }
+
+    /// The earliest time the next animation frame may be rendered under the
+    /// configured frame rate cap, if there is one.
+    pub fn next_frame_time(&self) -> Option<Instant> {
+        let fps = self.state.fps_cap()?.max(1);
+        Some(self.last_frame + Duration::from_secs(1) / fps)
+    }
+
+    /// When the blinking cursor next needs a frame, if it is blinking.
+    pub fn next_cursor_toggle(&self) -> Option<Instant> {
+        self.state.next_cursor_toggle()
+    }
+
+    pub fn input(&mut self, event: &WindowEvent) -> bool {
+        self.state.input(event)
+    }
+
+    /// Prepare the next frame. If the GPU device was lost it is recreated
+    /// first, together with everything uploaded to it; if that fails the
+    /// error is returned, the device stays lost and the next update tries
+    /// again.
+    pub fn update(&mut self) -> Result<(), Error> {
+        if self.state.is_device_lost() {
+            pollster::block_on(self.state.recover())?;
+        }
+        self.state.update();
+        Ok(())
+    }
+
+    /// Draw the frame. Fails with [`Error::DeviceLost`] while the device is
+    /// lost, and with [`Error::Surface`] when the surface has to be
+    /// reconfigured.
+    pub fn render(&mut self) -> Result<(), Error> {
+        self.last_frame = Instant::now();
+        self.state.render()
+    }
+}
+
+
+/// The codes shown by the cells and sprites of `scene`, of every layer or
+/// only those changed since it was last drawn.
+fn visible_codes(scene: &Scene, all: bool) -> BTreeSet<char> {
+    let mut codes = BTreeSet::new();
+    for (_, layer) in scene.layers().filter(|(_, layer)| all || layer.is_dirty()) {
+        codes.extend(layer.cells().iter().filter(|cell| !cell.is_empty()).map(|cell| cell.code));
+        codes.extend(layer.sprites().iter().map(|sprite| sprite.code));
     }
+    codes
 }
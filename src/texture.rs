@@ -1,22 +1,167 @@
 use image::GenericImageView;
-use anyhow::*;
 
+use std::borrow::Cow;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
 
+use crate::size::Size;
+
+
+const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum TextureFilter {
+    /// Sharp texels at any scale; what pixel-art tilesets need.
+    #[default]
+    Nearest,
+    /// Smooth magnification and minification.
+    Linear,
+    /// Linear filtering over a generated mipmap chain, for heavy
+    /// minification without shimmering. Atlas pages only use the levels
+    /// their tile extrusion keeps from bleeding, so minifying by more than
+    /// half needs an extrusion of at least 3.
+    LinearMipmapped,
+}
+
+#[derive(Debug)]
+pub enum TextureError {
+    /// The image file does not exist.
+    NotFound(PathBuf),
+    /// The image file exists but could not be read.
+    Io(PathBuf, io::Error),
+    /// The data is not an image in a supported format, or is corrupt.
+    Decode(String, image::ImageError),
+    /// The image cannot be cut into tiles as asked.
+    Grid(String),
+    /// The image is bigger than the device's texture size limit, given
+    /// last, in pixels per side.
+    TooLarge(String, Size<u32>, u32),
+    /// The texture needs more layers than the device's limit, given last.
+    TooManyLayers(String, u32),
+}
+
+impl fmt::Display for TextureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TextureError::NotFound(path) => {
+                write!(f, "Image not found: {}", path.display())
+            }
+            TextureError::Io(path, error) => {
+                write!(f, "Could not read image {}: {}", path.display(), error)
+            }
+            TextureError::Decode(label, error) => {
+                write!(f, "Could not decode image {}: {}", label, error)
+            }
+            TextureError::Grid(message) => {
+                write!(f, "Invalid tile grid: {}", message)
+            }
+            TextureError::TooLarge(label, size, max) => {
+                write!(
+                    f,
+                    "Image {} is {}x{}, larger than the device's limit of {} pixels per side",
+                    label, size.width, size.height, max,
+                )
+            }
+            TextureError::TooManyLayers(label, max) => {
+                write!(f, "Texture {} needs more than the device's limit of {} layers", label, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TextureError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TextureError::NotFound(_) => None,
+            TextureError::Io(_, error) => Some(error),
+            TextureError::Decode(_, error) => Some(error),
+            TextureError::Grid(_) => None,
+            TextureError::TooLarge(..) => None,
+            TextureError::TooManyLayers(..) => None,
+        }
+    }
+}
+
+
+/// Read and decode the image file at `path`.
+pub fn load_image(path: &Path) -> Result<image::RgbaImage, TextureError> {
+    let bytes = std::fs::read(path).map_err(|error| {
+        match error.kind() {
+            io::ErrorKind::NotFound => TextureError::NotFound(path.into()),
+            _ => TextureError::Io(path.into(), error),
+        }
+    })?;
+
+    decode_image(&bytes, &path.display().to_string())
+}
+
+/// Fail unless an image of `(width, height)` fits in a texture no more than
+/// `max` pixels per side.
+fn check_size((width, height): (u32, u32), max: u32, label: &str) -> Result<(), TextureError> {
+    if width > max || height > max {
+        return Err(TextureError::TooLarge(label.into(), Size::new(width, height), max));
+    }
+    Ok(())
+}
+
+/// Fail unless a texture array of `layers` layers is within the device's
+/// `max`.
+fn check_layers(layers: u32, max: u32, label: &str) -> Result<(), TextureError> {
+    if layers > max {
+        return Err(TextureError::TooManyLayers(label.into(), max));
+    }
+    Ok(())
+}
+
+/// Decode an encoded PNG or JPEG image held in memory.
+pub fn decode_image(bytes: &[u8], label: &str) -> Result<image::RgbaImage, TextureError> {
+    image::load_from_memory(bytes)
+        .map(|img| img.to_rgba8())
+        .map_err(|error| TextureError::Decode(label.into(), error))
+}
+
+
+/// A 2D texture array of one or more layers, all the same size, viewed as
+/// `texture_2d_array` by shaders.
 pub struct Texture {
     pub texture: wgpu::Texture,
     pub view: wgpu::TextureView,
     pub sampler: wgpu::Sampler,
+    size: wgpu::Extent3d,
+    mip_level_count: u32,
+    filter: TextureFilter,
+    // The coarsest mip level `LinearMipmapped` samples
+    mip_limit: u32,
+    mipmaps_valid: bool,
+    // Level 0 of every layer, kept so the texture can be rebuilt after the
+    // device is lost
+    layers: Vec<image::RgbaImage>,
+    label: Option<String>,
 }
 
 impl Texture {
+    pub fn from_path(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        path: &Path,
+        filter: TextureFilter,
+    ) -> Result<Self, TextureError> {
+        let rgba = load_image(path)?;
+        let label = path.display().to_string();
+        Self::from_rgba(device, queue, rgba, Some(&label), filter)
+    }
+
     pub fn from_bytes(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         bytes: &[u8],
         label: &str,
-    ) -> Result<Self> {
-        let img = image::load_from_memory(bytes)?;
-        Self::from_image(device, queue, &img, Some(label))
+        filter: TextureFilter,
+    ) -> Result<Self, TextureError> {
+        let rgba = decode_image(bytes, label)?;
+        Self::from_rgba(device, queue, rgba, Some(label), filter)
     }
 
     pub fn from_image(
@@ -24,64 +169,456 @@ impl Texture {
         queue: &wgpu::Queue,
         img: &image::DynamicImage,
         label: Option<&str>,
-    ) -> Result<Self> {
-        let rgba = img.to_rgba8();
-        let dimensions = img.dimensions();
+        filter: TextureFilter,
+    ) -> Result<Self, TextureError> {
+        Self::from_rgba(device, queue, img.to_rgba8(), label, filter)
+    }
+
+    pub fn from_rgba(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        rgba: image::RgbaImage,
+        label: Option<&str>,
+        filter: TextureFilter,
+    ) -> Result<Self, TextureError> {
+        Self::from_layers(device, queue, vec![rgba], label, filter)
+    }
+
+    /// A texture with one layer per image. Every image has to be the size
+    /// of the first.
+    pub fn from_layers(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layers: Vec<image::RgbaImage>,
+        label: Option<&str>,
+        filter: TextureFilter,
+    ) -> Result<Self, TextureError> {
+        let dimensions = layers.first().map_or((1, 1), |layer| layer.dimensions());
+        debug_assert!(layers.iter().all(|layer| layer.dimensions() == dimensions));
+
+        let limits = device.limits();
+        let name = label.unwrap_or("texture");
+        check_size(dimensions, limits.max_texture_dimension_2d, name)?;
+        check_layers(layers.len() as u32, limits.max_texture_array_layers, name)?;
 
         let size = wgpu::Extent3d {
             width: dimensions.0,
             height: dimensions.1,
-            depth_or_array_layers: 1,
+            depth_or_array_layers: layers.len().max(1) as u32,
         };
 
+        let (texture, mip_level_count) = Self::create_texture(device, size, label);
+        for (index, layer) in layers.iter().enumerate() {
+            write_layer(queue, &texture, index as u32, 0, 0, layer);
+        }
+
+        let view = Self::create_view(&texture);
+        let sampler = Self::create_sampler(device, filter, mip_level_count - 1);
+
+        let mut texture = Self {
+            texture,
+            view,
+            sampler,
+            size,
+            mip_level_count,
+            filter,
+            mip_limit: mip_level_count - 1,
+            mipmaps_valid: false,
+            layers,
+            label: label.map(String::from),
+        };
+
+        texture.apply_filter(device, queue, filter);
+        Ok(texture)
+    }
+
+    fn create_texture(
+        device: &wgpu::Device,
+        size: wgpu::Extent3d,
+        label: Option<&str>,
+    ) -> (wgpu::Texture, u32) {
+        // The full mip chain is always allocated so the filter can be
+        // switched at runtime without recreating the texture
+        let mip_level_count = size.max_mips(wgpu::TextureDimension::D2);
+
         let texture = device.create_texture(
             &wgpu::TextureDescriptor {
-                label: Some("Diffuse Texture"),
+                label,
                 size,
-                mip_level_count: 1,
+                mip_level_count,
                 sample_count: 1,
                 dimension: wgpu::TextureDimension::D2,
-                format: wgpu::TextureFormat::Rgba8UnormSrgb,
+                format: FORMAT,
                 usage: (
                     wgpu::TextureUsages::TEXTURE_BINDING |
-                    wgpu::TextureUsages::COPY_DST
+                    wgpu::TextureUsages::COPY_SRC |
+                    wgpu::TextureUsages::COPY_DST |
+                    wgpu::TextureUsages::RENDER_ATTACHMENT
                 ),
                 view_formats: &[],
             },
         );
 
-        queue.write_texture(
+        (texture, mip_level_count)
+    }
+
+    // Every layer is viewed as part of the array, even when there is one
+    fn create_view(texture: &wgpu::Texture) -> wgpu::TextureView {
+        texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        })
+    }
+
+    /// Enlarge every layer to `width` by `height`, keeping level 0 in the
+    /// top-left corner. The old contents are copied on the GPU; the new
+    /// area is transparent.
+    pub fn grow(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, width: u32, height: u32) {
+        let size = wgpu::Extent3d {
+            width,
+            height,
+            ..self.size
+        };
+        self.resize(device, queue, size);
+
+        for layer in &mut self.layers {
+            let mut pixels = image::RgbaImage::new(width, height);
+            image::imageops::replace(&mut pixels, layer, 0, 0);
+            *layer = pixels;
+        }
+        self.invalidate_mipmaps(device, queue);
+    }
+
+    /// Append a transparent layer, copying the others over on the GPU.
+    /// Fails, leaving the texture as it was, if the device allows no more.
+    pub fn add_layer(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<(), TextureError> {
+        let layers = self.size.depth_or_array_layers + 1;
+        let max = device.limits().max_texture_array_layers;
+        check_layers(layers, max, self.label.as_deref().unwrap_or("texture"))?;
+
+        let size = wgpu::Extent3d {
+            depth_or_array_layers: layers,
+            ..self.size
+        };
+        self.resize(device, queue, size);
+
+        self.layers.push(image::RgbaImage::new(size.width, size.height));
+        self.invalidate_mipmaps(device, queue);
+        Ok(())
+    }
+
+    // Move to a new texture of `size`, copying level 0 of the old one into
+    // its corner
+    fn resize(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, size: wgpu::Extent3d) {
+        let (texture, mip_level_count) = Self::create_texture(
+            device,
+            size,
+            self.label.as_deref(),
+        );
+
+        let mut encoder = device.create_command_encoder(
+            &wgpu::CommandEncoderDescriptor {
+                label: Some("Texture Grow Encoder"),
+            },
+        );
+        encoder.copy_texture_to_texture(
             wgpu::ImageCopyTexture {
-                texture: &texture,
+                texture: &self.texture,
                 mip_level: 0,
                 origin: wgpu::Origin3d::ZERO,
                 aspect: wgpu::TextureAspect::All,
             },
-            &rgba,
-            wgpu::ImageDataLayout {
-                offset: 0,
-                bytes_per_row: std::num::NonZeroU32::new(4 * dimensions.0),
-                rows_per_image: std::num::NonZeroU32::new(dimensions.1),
+            wgpu::ImageCopyTexture {
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
             },
-            size,
+            self.size,
         );
+        queue.submit(std::iter::once(encoder.finish()));
 
-        let view = texture.create_view(
-            &wgpu::TextureViewDescriptor::default(),
-        );
+        self.view = Self::create_view(&texture);
+        self.texture = texture;
+        self.size = size;
+        self.mip_level_count = mip_level_count;
+    }
+
+    /// Upload the retained pixels to a new texture on `device`, keeping the
+    /// filter. Used to restore the texture after the device is lost. Fails,
+    /// leaving the texture as it was, if the new device allows only smaller
+    /// textures.
+    pub fn recreate(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) -> Result<(), TextureError> {
+        let limits = device.limits();
+        let name = self.label.as_deref().unwrap_or("texture");
+        check_size((self.size.width, self.size.height), limits.max_texture_dimension_2d, name)?;
+        check_layers(self.size.depth_or_array_layers, limits.max_texture_array_layers, name)?;
+
+        let layers = std::mem::take(&mut self.layers);
+        let label = self.label.take();
+        let mip_limit = self.mip_limit;
+        *self = Self::from_layers(device, queue, layers, label.as_deref(), self.filter)?;
+        self.set_mip_limit(device, mip_limit);
+        Ok(())
+    }
+
+    /// Level 0 of every layer, in layer order.
+    pub fn layers(&self) -> &[image::RgbaImage] {
+        &self.layers
+    }
+
+    pub fn size(&self) -> Size<u32> {
+        Size::new(self.size.width, self.size.height)
+    }
+
+    /// Copy `image` into level 0 of `layer` at `(x, y)`, on the GPU and in
+    /// the retained pixels. Call [`Texture::invalidate_mipmaps`] once the
+    /// writes are done.
+    pub fn write(&mut self, queue: &wgpu::Queue, layer: usize, x: u32, y: u32, image: &image::RgbaImage) {
+        write_layer(queue, &self.texture, layer as u32, x, y, image);
+        image::imageops::replace(&mut self.layers[layer], image, x as i64, y as i64);
+        self.mipmaps_valid = false;
+    }
+
+    pub fn filter(&self) -> TextureFilter {
+        self.filter
+    }
+
+    /// Switch to `filter`, generating mipmaps first if it needs them. Bind
+    /// groups referring to the old sampler have to be recreated afterwards.
+    pub fn apply_filter(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        filter: TextureFilter,
+    ) {
+        if filter == TextureFilter::LinearMipmapped && !self.mipmaps_valid {
+            self.generate_mipmaps(device, queue);
+        }
+
+        self.filter = filter;
+        self.sampler = Self::create_sampler(device, filter, self.mip_limit);
+    }
+
+    /// Keep `LinearMipmapped` from sampling levels coarser than `level`,
+    /// where the texels of neighbouring images would blend in. Bind groups
+    /// referring to the old sampler have to be recreated afterwards.
+    pub fn set_mip_limit(&mut self, device: &wgpu::Device, level: u32) {
+        self.mip_limit = level;
+        self.sampler = Self::create_sampler(device, self.filter, level);
+    }
+
+    /// Mark the mip chain stale after level 0 changed. It is regenerated now
+    /// if the current filter samples it.
+    pub fn invalidate_mipmaps(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        self.mipmaps_valid = false;
+        if self.filter == TextureFilter::LinearMipmapped {
+            self.generate_mipmaps(device, queue);
+        }
+    }
+
+    fn create_sampler(
+        device: &wgpu::Device,
+        filter: TextureFilter,
+        mip_limit: u32,
+    ) -> wgpu::Sampler {
+        let (filter_mode, lod_max_clamp) = match filter {
+            TextureFilter::Nearest => (wgpu::FilterMode::Nearest, 0.0),
+            TextureFilter::Linear => (wgpu::FilterMode::Linear, 0.0),
+            TextureFilter::LinearMipmapped => (wgpu::FilterMode::Linear, mip_limit as f32),
+        };
 
-        let sampler = device.create_sampler(
+        device.create_sampler(
             &wgpu::SamplerDescriptor {
                 address_mode_u: wgpu::AddressMode::ClampToEdge,
                 address_mode_v: wgpu::AddressMode::ClampToEdge,
                 address_mode_w: wgpu::AddressMode::ClampToEdge,
-                mag_filter: wgpu::FilterMode::Linear,
-                min_filter: wgpu::FilterMode::Nearest,
-                mipmap_filter: wgpu::FilterMode::Nearest,
+                mag_filter: filter_mode,
+                min_filter: filter_mode,
+                mipmap_filter: filter_mode,
+                lod_max_clamp,
                 ..Default::default()
             },
+        )
+    }
+
+    /// Fill every mip level by downsampling the one above it.
+    fn generate_mipmaps(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Blit Shader"),
+            source: wgpu::ShaderSource::Wgsl(
+                Cow::Borrowed(include_str!("shaders/blit.wgsl"))
+            ),
+        });
+
+        let pipeline = device.create_render_pipeline(
+            &wgpu::RenderPipelineDescriptor {
+                label: Some("Mipmap Pipeline"),
+                layout: None,
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_main",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fs_main",
+                    targets: &[Some(FORMAT.into())],
+                }),
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            },
+        );
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Mipmap Sampler"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let mut encoder = device.create_command_encoder(
+            &wgpu::CommandEncoderDescriptor {
+                label: Some("Mipmap Encoder"),
+            },
+        );
+
+        for layer in 0..self.size.depth_or_array_layers {
+            let views: Vec<wgpu::TextureView> = (0..self.mip_level_count)
+                .map(|level| {
+                    self.texture.create_view(&wgpu::TextureViewDescriptor {
+                        label: Some("Mip View"),
+                        dimension: Some(wgpu::TextureViewDimension::D2),
+                        base_mip_level: level,
+                        mip_level_count: std::num::NonZeroU32::new(1),
+                        base_array_layer: layer,
+                        array_layer_count: std::num::NonZeroU32::new(1),
+                        ..Default::default()
+                    })
+                })
+                .collect();
+            self.encode_mipmaps(device, &mut encoder, &pipeline, &sampler, &views);
+        }
+
+        queue.submit(std::iter::once(encoder.finish()));
+        self.mipmaps_valid = true;
+    }
+
+    // Downsample each of one layer's `views`, one per level, from the last
+    fn encode_mipmaps(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        pipeline: &wgpu::RenderPipeline,
+        sampler: &wgpu::Sampler,
+        views: &[wgpu::TextureView],
+    ) {
+        let bind_group_layout = pipeline.get_bind_group_layout(0);
+
+        for level in 1..views.len() {
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: Some("Mipmap Bind Group"),
+                layout: &bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(
+                            &views[level - 1],
+                        ),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(sampler),
+                    },
+                ],
+            });
+
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Mipmap Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &views[level],
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: None,
+            });
+
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+    }
+}
+
+
+/// Upload `image` to level 0 of `layer` at `(x, y)`.
+fn write_layer(
+    queue: &wgpu::Queue,
+    texture: &wgpu::Texture,
+    layer: u32,
+    x: u32,
+    y: u32,
+    image: &image::RgbaImage,
+) {
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 {
+        return;
+    }
+
+    queue.write_texture(
+        wgpu::ImageCopyTexture {
+            texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d { x, y, z: layer },
+            aspect: wgpu::TextureAspect::All,
+        },
+        image,
+        wgpu::ImageDataLayout {
+            offset: 0,
+            bytes_per_row: std::num::NonZeroU32::new(4 * width),
+            rows_per_image: std::num::NonZeroU32::new(height),
+        },
+        wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+    );
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::size::Size;
+    use crate::texture::{check_layers, check_size, TextureError};
+
+    #[test]
+    fn test_oversized_images_are_rejected() {
+        assert!(check_size((2048, 2048), 2048, "sheet").is_ok());
+
+        let error = check_size((4096, 16), 2048, "sheet").unwrap_err();
+        assert!(matches!(error, TextureError::TooLarge(_, size, 2048) if size == Size::new(4096, 16)));
+        assert_eq!(
+            error.to_string(),
+            "Image sheet is 4096x16, larger than the device's limit of 2048 pixels per side",
         );
+        assert!(check_size((16, 4097), 4096, "sheet").is_err());
+    }
 
-        Ok(Self { texture, view, sampler })
+    #[test]
+    fn test_too_many_layers_are_rejected() {
+        assert!(check_layers(256, 256, "Atlas Pages").is_ok());
+
+        let error = check_layers(257, 256, "Atlas Pages").unwrap_err();
+        assert!(matches!(error, TextureError::TooManyLayers(_, 256)));
+        assert_eq!(
+            error.to_string(),
+            "Texture Atlas Pages needs more than the device's limit of 256 layers",
+        );
     }
 }
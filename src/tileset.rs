@@ -1,10 +1,103 @@
-use crate::atlas::TileInfo;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+use std::ops::RangeInclusive;
+
+use image::RgbaImage;
+
+use crate::atlas::{TileAlignment, TileInfo};
+use crate::codepage::Codepage;
+use crate::color::Color;
+use crate::font::Font;
+use crate::point::Point;
 use crate::size::Size;
+use crate::texture::{self, TextureError};
+
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Frame {
+    pub code: char,
+    pub duration: Duration,
+}
+
+
+/// A looping sequence of tiles shown in place of a single code.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Animation {
+    frames: Vec<Frame>,
+}
+
+impl Animation {
+    pub fn new(frames: Vec<Frame>) -> Self {
+        Self { frames }
+    }
+
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.frames.iter().map(|f| f.duration).sum()
+    }
+}
+
+
+/// How the tiles for a range of codes sit in their cells.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TileLayout {
+    pub codes: RangeInclusive<char>,
+    /// `Unknown` stretches tiles over the cell; anything else draws them at
+    /// their own size, placed by the alignment.
+    pub alignment: TileAlignment,
+    /// Pixels the tiles are shifted by after alignment.
+    pub offset: Point<i32>,
+}
+
+impl TileLayout {
+    pub fn contains(&self, code: char) -> bool {
+        self.codes.contains(&code)
+    }
+}
+
+
+/// How a tileset image is cut into tiles.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TileGrid {
+    pub tile_size: Size<i32>,
+    /// Pixels between neighbouring tiles.
+    pub spacing: Size<i32>,
+    /// Pixels left and above the first tile.
+    pub margin: Size<i32>,
+    /// Pixels of this color become transparent, for images without alpha.
+    /// Alpha is ignored when comparing.
+    pub key_color: Option<Color>,
+    /// Classic ASCII sheets are laid out as [`Codepage::cp437`].
+    pub codepage: Codepage,
+}
+
+impl Default for TileGrid {
+    fn default() -> Self {
+        Self {
+            tile_size: Size::new(16, 16),
+            spacing: Size::new(0, 0),
+            margin: Size::new(0, 0),
+            key_color: None,
+            codepage: Codepage::Sequential('\0'),
+        }
+    }
+}
 
 
 pub struct Tileset {
     pub offset: char,
     spacing: Size<i32>,
+    tile_size: Size<i32>,
+    tiles: BTreeMap<char, RgbaImage>,
+    // Draws the codes `tiles` has none for, as they are first shown
+    font: Option<Font>,
+    animations: BTreeMap<char, Animation>,
+    layouts: Vec<TileLayout>,
 }
 
 impl Tileset {
@@ -12,32 +105,280 @@ impl Tileset {
         Self {
             offset,
             spacing: Size { width: 1, height: 1 },
+            tile_size: Size::new(0, 0),
+            tiles: BTreeMap::new(),
+            font: None,
+            animations: BTreeMap::new(),
+            layouts: Vec::new(),
+        }
+    }
+
+    /// A tileset whose tiles are drawn by `font` on demand, sized to its
+    /// cell.
+    pub fn from_font(font: Font) -> Self {
+        let mut tileset = Self::new('\0');
+        tileset.spacing = Size::new(0, 0);
+        tileset.tile_size = font.cell_size();
+        tileset.font = Some(font);
+        tileset
+    }
+
+    pub fn font(&self) -> Option<&Font> {
+        self.font.as_ref()
+    }
+
+    /// Cut `image` into tiles by `grid`. Every whole tile that fits is
+    /// taken, unless the codepage runs out first.
+    pub fn from_image(image: &RgbaImage, grid: &TileGrid) -> Result<Self, TextureError> {
+        let TileGrid { tile_size, spacing, margin, .. } = *grid;
+        if tile_size.width <= 0 || tile_size.height <= 0 {
+            return Err(TextureError::Grid(format!(
+                "tile size must be positive, got {}x{}",
+                tile_size.width, tile_size.height,
+            )));
+        }
+        if spacing.width < 0 || spacing.height < 0 || margin.width < 0 || margin.height < 0 {
+            return Err(TextureError::Grid("spacing and margin cannot be negative".into()));
+        }
+
+        // The last tile of a row or column needs no spacing after it
+        let fit = |length: u32, margin: i32, tile: i32, spacing: i32| {
+            ((length as i32 - margin + spacing) / (tile + spacing)).max(0) as u32
+        };
+        let columns = fit(image.width(), margin.width, tile_size.width, spacing.width);
+        let rows = fit(image.height(), margin.height, tile_size.height, spacing.height);
+        if columns == 0 || rows == 0 {
+            return Err(TextureError::Grid(format!(
+                "a {}x{} image holds no {}x{} tiles",
+                image.width(), image.height(), tile_size.width, tile_size.height,
+            )));
+        }
+
+        let mut tileset = Self::new(grid.codepage.first());
+        tileset.spacing = spacing;
+        tileset.tile_size = tile_size;
+
+        for index in 0..columns * rows {
+            let Some(code) = grid.codepage.code(index as usize) else {
+                break;
+            };
+            let (column, row) = (index % columns, index / columns);
+            let mut tile = image::imageops::crop_imm(
+                image,
+                (margin.width + column as i32 * (tile_size.width + spacing.width)) as u32,
+                (margin.height + row as i32 * (tile_size.height + spacing.height)) as u32,
+                tile_size.width as u32,
+                tile_size.height as u32,
+            ).to_image();
+
+            if let Some(key) = grid.key_color {
+                for pixel in tile.pixels_mut() {
+                    if pixel.0[..3] == [key.r, key.g, key.b] {
+                        pixel.0 = [0, 0, 0, 0];
+                    }
+                }
+            }
+            tileset.tiles.insert(code, tile);
+        }
+
+        Ok(tileset)
+    }
+
+    /// Read the image file at `path` and cut it into tiles by `grid`.
+    pub fn load(path: impl AsRef<Path>, grid: &TileGrid) -> Result<Self, TextureError> {
+        Self::from_image(&texture::load_image(path.as_ref())?, grid)
+    }
+
+    /// Every tile and the code it stands for.
+    pub fn tiles(&self) -> impl Iterator<Item = (char, &RgbaImage)> {
+        self.tiles.iter().map(|(code, tile)| (*code, tile))
+    }
+
+    /// Place the tiles for `codes` in their cells by `alignment`, then shift
+    /// them by `offset` pixels. Later calls win where ranges overlap.
+    pub fn set_alignment(
+        &mut self,
+        codes: RangeInclusive<char>,
+        alignment: TileAlignment,
+        offset: Point<i32>,
+    ) {
+        self.layouts.push(TileLayout { codes, alignment, offset });
+    }
+
+    pub fn layouts(&self) -> &[TileLayout] {
+        &self.layouts
+    }
+
+    /// The layout `code` is drawn with, if any range covers it.
+    pub fn layout(&self, code: char) -> Option<&TileLayout> {
+        self.layouts.iter().rev().find(|layout| layout.contains(code))
+    }
+
+    /// Show `frames` in a loop wherever `code` is drawn.
+    pub fn add_animation(&mut self, code: char, frames: Vec<Frame>) {
+        if frames.is_empty() {
+            self.animations.remove(&code);
+        } else {
+            self.animations.insert(code, Animation::new(frames));
         }
     }
 
-    pub fn get_offset(self) -> char {
-        todo!()
+    pub fn remove_animation(&mut self, code: char) {
+        self.animations.remove(&code);
+    }
+
+    pub fn animations(&self) -> impl Iterator<Item = (char, &Animation)> {
+        self.animations.iter().map(|(code, animation)| (*code, animation))
+    }
+
+    pub fn is_animated(&self, code: char) -> bool {
+        self.animations.contains_key(&code)
+    }
+
+    pub fn get_offset(&self) -> char {
+        self.offset
+    }
+
+    /// Whether the tileset has a tile for `code`, or its font a glyph.
+    pub fn provides(&self, code: char) -> bool {
+        self.tiles.contains_key(&code)
+            || self.font.as_ref().is_some_and(|font| font.provides(code))
     }
 
-    pub fn provides(self) -> bool {
-        todo!()
+    /// The tile for `code`: the one cut from the image, or else the font
+    /// glyph drawn now.
+    pub fn rasterize(&self, code: char) -> Option<RgbaImage> {
+        match self.tiles.get(&code) {
+            Some(tile) => Some(tile.clone()),
+            None => self.font.as_ref()?.rasterize(code),
+        }
     }
 
-    pub fn get(self, code: char) -> &'static TileInfo<'static> {
-        todo!()
+    pub fn get(&self, code: char) -> Option<&RgbaImage> {
+        self.tiles.get(&code)
     }
 
-    pub fn get_bounding_box_size(self) -> Size<i32> {
-        todo!()
+    /// The size of the tiles cut from the image.
+    pub fn get_bounding_box_size(&self) -> Size<i32> {
+        self.tile_size
     }
 
-    pub fn get_spacing(self) -> Size<i32> {
-        todo!()
+    pub fn get_spacing(&self) -> Size<i32> {
+        self.spacing
     }
 
+    #[allow(clippy::wrong_self_convention)]
     pub fn is_font_offset(self, offset: char) -> bool {
-        todo!()
+        self.offset == offset
+    }
+
+    /// Take in the tiles, font, animations and layouts of `tileset`. Its
+    /// tiles and font replace any earlier ones. Tiles win over the font
+    /// whichever came first, as in [`Tileset::rasterize`].
+    pub fn add_tileset(&mut self, tileset: Tileset) {
+        self.tiles.extend(tileset.tiles);
+        if tileset.font.is_some() {
+            self.font = tileset.font;
+        }
+        self.animations.extend(tileset.animations);
+        self.layouts.extend(tileset.layouts);
     }
+}
 
-    fn add_tileset(self, tileset: &Tileset) {}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use image::{Rgba, RgbaImage};
+
+    use crate::atlas::TileAlignment;
+    use crate::color::Color;
+    use crate::point::Point;
+    use crate::size::Size;
+    use crate::codepage::Codepage;
+    use crate::tileset::{Animation, Frame, TileGrid, Tileset};
+
+    fn frame(code: char, millis: u64) -> Frame {
+        Frame { code, duration: Duration::from_millis(millis) }
+    }
+
+    #[test]
+    fn test_animation_duration() {
+        let animation = Animation::new(vec![
+            frame('a', 100),
+            frame('b', 300),
+        ]);
+        assert_eq!(animation.duration(), Duration::from_millis(400));
+    }
+
+    #[test]
+    fn test_add_and_remove_animation() {
+        let mut tileset = Tileset::new('\0');
+        tileset.add_animation('~', vec![frame('~', 100), frame('-', 100)]);
+        assert!(tileset.is_animated('~'));
+
+        tileset.add_animation('~', vec![]);
+        assert!(!tileset.is_animated('~'));
+    }
+
+    #[test]
+    fn test_grid_with_margin_and_spacing() {
+        // Two rows of three 4x4 tiles, a 1 pixel margin and 2 pixel gaps
+        let mut image = RgbaImage::from_pixel(17, 11, Rgba([255, 0, 255, 255]));
+        image.put_pixel(7, 7, Rgba([1, 2, 3, 255]));
+        let grid = TileGrid {
+            tile_size: Size::new(4, 4),
+            spacing: Size::new(2, 2),
+            margin: Size::new(1, 1),
+            key_color: Some(Color::rgb(255, 0, 255)),
+            codepage: Codepage::Sequential('a'),
+        };
+        let tileset = Tileset::from_image(&image, &grid).unwrap();
+
+        assert_eq!(tileset.tiles().count(), 6);
+        assert_eq!(tileset.get_offset(), 'a');
+        assert_eq!(tileset.get_spacing(), Size::new(2, 2));
+
+        let tile = tileset.get('e').unwrap();
+        assert_eq!(*tile.get_pixel(0, 0), Rgba([1, 2, 3, 255]));
+        assert_eq!(*tile.get_pixel(1, 1), Rgba([0, 0, 0, 0]));
+    }
+
+    #[test]
+    fn test_codepage_table_skips_unmapped_tiles() {
+        let grid = TileGrid {
+            tile_size: Size::new(2, 2),
+            codepage: Codepage::Table(vec!['☺', '♥']),
+            ..TileGrid::default()
+        };
+        let tileset = Tileset::from_image(&RgbaImage::new(8, 2), &grid).unwrap();
+
+        let codes: Vec<char> = tileset.tiles().map(|(code, _)| code).collect();
+        assert_eq!(codes, vec!['☺', '♥']);
+        assert!(!tileset.provides('\0'));
+    }
+
+    #[test]
+    fn test_bad_grid_is_rejected() {
+        let image = RgbaImage::new(8, 8);
+        let grid = TileGrid { tile_size: Size::new(0, 8), ..TileGrid::default() };
+        assert!(Tileset::from_image(&image, &grid).is_err());
+
+        let grid = TileGrid { tile_size: Size::new(16, 16), ..TileGrid::default() };
+        assert!(Tileset::from_image(&image, &grid).is_err());
+    }
+
+    #[test]
+    fn test_later_layouts_win() {
+        let mut tileset = Tileset::new('\0');
+        tileset.set_alignment('a'..='z', TileAlignment::Center, Point::new(0, 0));
+        tileset.set_alignment('m'..='p', TileAlignment::BottomLeft, Point::new(1, -2));
+
+        assert_eq!(tileset.layout('a').unwrap().alignment, TileAlignment::Center);
+        let layout = tileset.layout('n').unwrap();
+        assert_eq!(layout.alignment, TileAlignment::BottomLeft);
+        assert_eq!(layout.offset, Point::new(1, -2));
+        assert!(tileset.layout('A').is_none());
+    }
 }
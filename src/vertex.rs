@@ -1,7 +1,3 @@
-use bytemuck;
-use wgpu;
-
-
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Vertex {
@@ -32,3 +28,130 @@ impl Vertex {
         }
     }
 }
+
+
+/// Per-quad data for the instanced pipeline, shared by cells and sprites.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Instance {
+    /// Top-left corner in pixels.
+    pub position: [f32; 2],
+    /// Quad size in pixels.
+    pub size: [f32; 2],
+    /// Pivot for `scale` and `rotation`, relative to `size`.
+    pub anchor: [f32; 2],
+    pub scale: f32,
+    /// Clockwise rotation about `anchor`, in radians.
+    pub rotation: f32,
+    /// `FLIP_X` and `FLIP_Y` bits.
+    pub flags: u32,
+    pub tex_coords: [f32; 4],
+    pub fore: [f32; 4],
+    pub back: [f32; 4],
+    /// 1-based index into the animation table, or 0 for a static tile.
+    pub animation: u32,
+    /// The atlas page `tex_coords` are on.
+    pub page: u32,
+}
+
+impl Instance {
+    pub const FLIP_X: u32 = 1;
+    pub const FLIP_Y: u32 = 2;
+
+    const ATTRIBS: [wgpu::VertexAttribute; 11] = wgpu::vertex_attr_array![
+        2 => Float32x2,
+        3 => Float32x2,
+        4 => Float32x2,
+        5 => Float32,
+        6 => Float32,
+        7 => Uint32,
+        8 => Float32x4,
+        9 => Float32x4,
+        10 => Float32x4,
+        11 => Uint32,
+        12 => Uint32,
+    ];
+
+    pub fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<Instance>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &Self::ATTRIBS,
+        }
+    }
+}
+
+
+/// Per-particle data for the particle pipeline in `shaders/draw.wgsl`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ParticleInstance {
+    /// Center in pixels.
+    pub position: [f32; 2],
+    /// Pixels per second; only used to turn the tile.
+    pub velocity: [f32; 2],
+    /// Quad size in pixels.
+    pub size: [f32; 2],
+    pub tex_coords: [f32; 4],
+    pub color: [f32; 4],
+    pub flags: u32,
+    /// The atlas page `tex_coords` are on.
+    pub page: u32,
+}
+
+impl ParticleInstance {
+    pub const ALIGN_TO_VELOCITY: u32 = 1;
+    /// Fill the quad with the color alone, sampling nothing.
+    pub const SOLID: u32 = 2;
+
+    const ATTRIBS: [wgpu::VertexAttribute; 7] = wgpu::vertex_attr_array![
+        2 => Float32x2,
+        3 => Float32x2,
+        4 => Float32x2,
+        5 => Float32x4,
+        6 => Float32x4,
+        7 => Uint32,
+        8 => Uint32,
+    ];
+
+    pub fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<ParticleInstance>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &Self::ATTRIBS,
+        }
+    }
+}
+
+
+/// Uniforms shared by every shader, bound at group 1.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct Globals {
+    pub viewport: [f32; 2],
+    pub cell_size: [f32; 2],
+    pub time: f32,
+    pub cursor_blink: f32,
+    pub cursor_epoch: f32,
+    pub _padding: f32,
+}
+
+
+/// An entry in the animation table: a run of `count` frames starting at
+/// `first` in the frame table, looping every `duration` seconds.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct AnimationEntry {
+    pub first: u32,
+    pub count: u32,
+    pub duration: f32,
+}
+
+
+/// A frame of an animation; `end` is the time, in seconds from the start of
+/// the loop, at which the next frame takes over.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct AnimationFrame {
+    pub tex_coords: [f32; 4],
+    pub end: f32,
+    pub page: u32,
+}